@@ -94,7 +94,7 @@ impl<B, E> Client<B, E> where
 		let state = self.state_at(hash)?;
 		let mut changes = state_machine::OverlayedChanges::default();
 
-		let _ = state_machine::execute(
+		let _ = state_machine::execute_boxed(
 			&state,
 			&mut changes,
 			&self.executor,
@@ -37,6 +37,12 @@ error_chain! {
 			display("Invalid Code: {:?}", c),
 		}
 
+		/// Code is not a usable runtime module.
+		InvalidModule(reason: String) {
+			description("invalid module"),
+			display("Invalid module: {}", reason),
+		}
+
 		/// Externalities have failed.
 		Externalities(e: Box<state_machine::Error>) {
 			description("externalities failure"),
@@ -60,5 +66,53 @@ error_chain! {
 			description("runtime failure"),
 			display("Runtime error"),
 		}
+
+		/// Code uses floating point, which isn't deterministic.
+		FloatingPointForbidden {
+			description("floating point forbidden"),
+			display("Code uses floating point instructions"),
+		}
+
+		/// Runtime tried to use more memory than allowed.
+		MemoryLimitExceeded(pages: u32) {
+			description("memory limit exceeded"),
+			display("Memory limit of {} pages exceeded", pages),
+		}
+
+		/// Runtime ran out of gas.
+		GasExhausted {
+			description("gas exhausted"),
+			display("Gas exhausted"),
+		}
+
+		/// Runtime called a host function which doesn't exist.
+		UnknownHostFunction(name: String) {
+			description("unknown host function"),
+			display("Unknown host function: '{}'", name),
+		}
+
+		/// Runtime imports a function which isn't an allowed host function.
+		ForbiddenImport(name: String) {
+			description("forbidden import"),
+			display("Forbidden import: '{}'", name),
+		}
+
+		/// Runtime returned an undecodable version.
+		InvalidVersion {
+			description("invalid runtime version"),
+			display("Runtime returned an invalid version"),
+		}
+
+		/// Runtime was built against a host function ABI the executor doesn't provide.
+		AbiVersionMismatch(version: u32) {
+			description("unsupported host function ABI"),
+			display("Host function ABI version {} not supported", version),
+		}
+	}
+}
+
+impl From<state_machine::InvalidVersion> for Error {
+	fn from(_: state_machine::InvalidVersion) -> Self {
+		ErrorKind::InvalidVersion.into()
 	}
 }
@@ -38,6 +38,7 @@ extern crate byteorder;
 extern crate error_chain;
 
 #[cfg(test)]
+#[macro_use]
 extern crate assert_matches;
 
 #[macro_use]
@@ -46,6 +47,8 @@ mod wasm_executor;
 
 pub mod error;
 
+pub use wasm_executor::{Limits, RuntimeInfo, ABI_VERSION, ALLOWED_IMPORTS, DEFAULT_MAX_MEMORY_PAGES};
+
 /// Creates new RustExecutor for contracts.
 pub fn executor() -> wasm_executor::WasmExecutor {
 	wasm_executor::WasmExecutor::default()
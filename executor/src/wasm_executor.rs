@@ -17,14 +17,18 @@
 //! Rust implementation of Polkadot contracts.
 
 use std::sync::Arc;
+use std::borrow::Cow;
 use std::collections::HashMap;
 use parity_wasm::{deserialize_buffer, ModuleInstanceInterface, ProgramInstance};
+use parity_wasm::elements::{BlockType, External, GlobalEntry, GlobalSection, GlobalType, InitExpr, Internal, MemoryType, Module, Opcode,
+	ResizableLimits, Section, Type, ValueType};
 use parity_wasm::interpreter::{ItemIndex};
 use parity_wasm::RuntimeValue::{I32, I64};
+use primitives::codec::Encode;
 use primitives::contract::CallData;
-use state_machine::{Externalities, CodeExecutor};
+use state_machine::{Externalities, CodeExecutor, HashAlgo};
 use error::{Error, ErrorKind, Result};
-use wasm_utils::{MemoryInstance, UserDefinedElements,
+use wasm_utils::{self, DummyUserError, MemoryInstance, UserDefinedElements, UserFunctionDescriptor, RuntimeValue,
 	AddModuleWithoutFullDependentInstance};
 
 struct Heap {
@@ -50,6 +54,10 @@ struct FunctionExecutor<'e, E: Externalities + 'e> {
 	heap: Heap,
 	memory: Arc<MemoryInstance>,
 	ext: &'e mut E,
+	signatures: Cow<'static, [UserFunctionDescriptor]>,
+	unknown_call: Option<String>,
+	gas_left: Option<u64>,
+	gas_exhausted: bool,
 }
 
 impl<'e, E: Externalities> FunctionExecutor<'e, E> {
@@ -58,8 +66,45 @@ impl<'e, E: Externalities> FunctionExecutor<'e, E> {
 			heap: Heap::new(),
 			memory: Arc::clone(m),
 			ext: e,
+			signatures: Cow::from(Self::SIGNATURES),
+			unknown_call: None,
+			gas_left: None,
+			gas_exhausted: false,
 		}
 	}
+
+	// charge for a host call when metering, trapping once the gas has run out.
+	fn charge_gas(&mut self) -> ::std::result::Result<(), wasm_utils::Error> {
+		if let Some(ref mut left) = self.gas_left {
+			if *left == 0 {
+				self.gas_exhausted = true;
+				return Err(wasm_utils::Error::Trap("Gas exhausted".into()));
+			}
+			*left -= 1;
+		}
+		Ok(())
+	}
+
+	// trap unless `len` bytes from `offset` on lie within the memory.
+	fn check_fits(&self, offset: u32, len: u32) -> ::std::result::Result<(), wasm_utils::Error> {
+		let size = self.memory.size() as u64 * WASM_PAGE_SIZE;
+		if offset as u64 + len as u64 > size {
+			return Err(wasm_utils::Error::Trap(format!("{} bytes at {} don't fit in memory", len, offset)));
+		}
+		Ok(())
+	}
+
+	// write `data` at `offset`, trapping rather than writing nothing if it doesn't fit.
+	fn write_or_trap(&self, offset: u32, data: &[u8]) -> ::std::result::Result<(), wasm_utils::Error> {
+		self.memory.set(offset, data)
+			.map_err(|_| wasm_utils::Error::Trap(format!("{} bytes at {} don't fit in memory", data.len(), offset)))
+	}
+
+	// called for stubbed imports the host doesn't implement.
+	fn unknown_function(&mut self, name: &str) -> ::std::result::Result<Option<RuntimeValue>, wasm_utils::Error> {
+		self.unknown_call = Some(name.to_owned());
+		Err(wasm_utils::Error::Trap(format!("Unknown host function: {}", name)))
+	}
 }
 
 trait WritePrimitive<T: Sized> {
@@ -126,35 +171,511 @@ impl_function_executor!(this: FunctionExecutor<'e, E>,
 
 		this.memory.write_primitive(written_out, written);
 		offset as u32
+	},
+	ext_storage_len(key_data: *const u8, key_len: i32) -> u32 => {
+		let len = this.memory.get(key_data, key_len as usize).ok()
+			.and_then(|key| this.ext.storage_len(&key).ok())
+			.and_then(|len| len);
+		len.map_or(u32::max_value(), |len| len as u32)
+	},
+	ext_get_storage_into(key_data: *const u8, key_len: i32, value_data: *mut u8, value_len: i32) -> u32 => {
+		let value = this.memory.get(key_data, key_len as usize).ok()
+			.and_then(|key| this.ext.storage(&key).ok().map(|v| v.to_vec()));
+		match value {
+			Some(ref value) if !value.is_empty() => {
+				let written = ::std::cmp::min(value_len as usize, value.len());
+				this.write_or_trap(value_data, &value[..written])?;
+				value.len() as u32
+			}
+			_ => u32::max_value(),
+		}
+	},
+	ext_hash(algo: u32, data: *const u8, len: u32, out: *mut u8) -> u32 => {
+		let hash = match (HashAlgo::from_selector(algo), this.memory.get(data, len as usize)) {
+			(Some(algo), Ok(data)) => this.ext.hash(algo, &data),
+			_ => Vec::new(),
+		};
+		this.write_or_trap(out, &hash)?;
+		hash.len() as u32
+	},
+	ext_genesis_hash(out: *mut u8) -> u32 => {
+		match this.ext.genesis_hash() {
+			Some(hash) => {
+				this.write_or_trap(out, &hash)?;
+				1
+			}
+			None => 0,
+		}
+	},
+	ext_code_hash(out: *mut u8) => {
+		if let Ok(hash) = this.ext.code_hash() {
+			this.write_or_trap(out, &hash)?;
+		}
+	},
+	ext_storage_quota_left() -> u64 => {
+		this.ext.storage_quota_left()
+	},
+	ext_timestamp() -> u64 => {
+		this.ext.timestamp()
+	},
+	ext_random(out: *mut u8, len: u32) => {
+		// `len` is the runtime's to choose, so check it before generating that many bytes.
+		this.check_fits(out, len)?;
+		let bytes = this.ext.random(len as usize);
+		this.write_or_trap(out, &bytes)?;
+	},
+	ext_emit_event(topic_data: *const u8, topic_len: i32, data: *const u8, data_len: i32) => {
+		if let (Ok(topic), Ok(data)) = (this.memory.get(topic_data, topic_len as usize), this.memory.get(data, data_len as usize)) {
+			this.ext.emit_event(topic, data);
+		}
+	},
+	ext_fee_params(out: *mut u8) => {
+		let params = this.ext.fee_params().unwrap_or_default();
+		this.write_or_trap(out, &params.encode())?;
 	}
 	=> <'e, E: Externalities + 'e>
 );
 
+// stands in for real externalities where only the host signatures are needed.
+struct NoExternalities;
+
+impl Externalities for NoExternalities {
+	type Error = Error;
+
+	fn storage(&self, _key: &[u8]) -> Result<&[u8]> {
+		Ok(&[])
+	}
+
+	fn set_storage(&mut self, _key: Vec<u8>, _value: Vec<u8>) {}
+
+	fn storage_root(&self) -> Result<[u8; 32]> {
+		Ok(::state_machine::backend::storage_root(Vec::new()))
+	}
+
+	fn clear_prefix(&mut self, _prefix: &[u8]) -> Result<()> {
+		Ok(())
+	}
+
+	fn next_storage_key(&self, _key: &[u8]) -> Result<Option<Vec<u8>>> {
+		Ok(None)
+	}
+
+	fn child_storage(&self, _storage_key: &[u8], _key: &[u8]) -> Result<&[u8]> {
+		Ok(&[])
+	}
+
+	fn set_child_storage(&mut self, _storage_key: Vec<u8>, _key: Vec<u8>, _value: Vec<u8>) {}
+
+	fn child_storage_root(&self, _storage_key: &[u8]) -> Result<[u8; 32]> {
+		Ok(::state_machine::backend::storage_root(Vec::new()))
+	}
+
+	fn random(&mut self, len: usize) -> Vec<u8> {
+		vec![0; len]
+	}
+}
+
+// bytes in a page of wasm memory.
+const WASM_PAGE_SIZE: u64 = 65536;
+
+/// Most pages of memory a runtime may use, unless configured otherwise.
+pub const DEFAULT_MAX_MEMORY_PAGES: u32 = 1024;
+
+/// Version of the host function ABI provided by this executor.
+///
+/// Runtimes not exporting an `abi_version` global are taken to be built against version 1.
+pub const ABI_VERSION: u32 = 1;
+
+/// Host functions a runtime may import, those whose effects depend only on the
+/// state and the call.
+///
+/// Runtimes importing anything else are refused with `ForbiddenImport`, except
+/// by an executor made with `WasmExecutor::with_stubbed_imports`.
+pub const ALLOWED_IMPORTS: &[&str] = &[
+	"ext_print", "ext_print_num",
+	"ext_memcpy", "ext_memmove", "ext_memset", "ext_malloc", "ext_free",
+	"ext_set_storage", "ext_get_allocated_storage", "ext_storage_len", "ext_get_storage_into",
+	"ext_hash", "ext_genesis_hash", "ext_code_hash", "ext_storage_quota_left",
+	"ext_timestamp", "ext_random", "ext_emit_event", "ext_fee_params",
+];
+
+// the first function the module imports which is not an allowed host function.
+fn forbidden_import(module: &Module) -> Option<String> {
+	module.import_section().map(|s| s.entries()).unwrap_or(&[]).iter()
+		.filter(|entry| match *entry.external() {
+			External::Function(_) => true,
+			_ => false,
+		})
+		.find(|entry| entry.module() != "env" || !ALLOWED_IMPORTS.contains(&entry.field()))
+		.map(|entry| entry.field().to_owned())
+}
+
+// clamp the maximum memory of the module to `max_pages`, failing if it starts out larger.
+fn limit_memory(module: &mut Module, max_pages: u32) -> Result<()> {
+	for section in module.sections_mut() {
+		if let Section::Memory(ref mut memory) = *section {
+			for entry in memory.entries_mut() {
+				let (initial, maximum) = (entry.limits().initial(), entry.limits().maximum());
+				if initial > max_pages {
+					bail!(ErrorKind::MemoryLimitExceeded(max_pages));
+				}
+				let maximum = maximum.map_or(max_pages, |max| ::std::cmp::min(max, max_pages));
+				*entry = MemoryType::new(initial, Some(maximum));
+			}
+		}
+	}
+	Ok(())
+}
+
+// make every `grow_memory` of the module also set a new mutable i32 global to 1
+// when it fails, returning the global's index among the module's own globals.
+// a second new global holds the result of the grow while it is checked.
+fn record_failed_grows(module: &mut Module) -> u32 {
+	let imported = module.import_section().map(|s| s.entries()).unwrap_or(&[]).iter()
+		.filter(|entry| match *entry.external() {
+			External::Global(_) => true,
+			_ => false,
+		})
+		.count() as u32;
+	let own = module.global_section().map_or(0, |s| s.entries().len()) as u32;
+	let flag = imported + own;
+	let scratch = flag + 1;
+
+	let entry = GlobalEntry::new(GlobalType::new(ValueType::I32, true), InitExpr::new(vec![Opcode::I32Const(0), Opcode::End]));
+	let entries = vec![entry.clone(), entry];
+	let has_globals = module.global_section().is_some();
+	let sections = module.sections_mut();
+	if has_globals {
+		for section in sections.iter_mut() {
+			if let Section::Global(ref mut globals) = *section {
+				globals.entries_mut().extend(entries.clone());
+			}
+		}
+	} else {
+		// globals come before exports, the start function, elements, code and data.
+		let position = sections.iter()
+			.position(|section| match *section {
+				Section::Export(_) | Section::Start(_) | Section::Element(_) | Section::Code(_) | Section::Data(_) => true,
+				_ => false,
+			})
+			.unwrap_or(sections.len());
+		sections.insert(position, Section::Global(GlobalSection::with_entries(entries)));
+	}
+
+	for section in sections.iter_mut() {
+		if let Section::Code(ref mut code) = *section {
+			for body in code.bodies_mut() {
+				let mut instrumented = Vec::new();
+				for op in body.code().elements() {
+					let grows = match *op {
+						Opcode::GrowMemory(_) => true,
+						_ => false,
+					};
+					instrumented.push(op.clone());
+					if grows {
+						// keeps the result on the stack: flag |= (result == -1).
+						instrumented.extend(vec![
+							Opcode::SetGlobal(scratch),
+							Opcode::GetGlobal(scratch),
+							Opcode::GetGlobal(scratch),
+							Opcode::I32Const(-1),
+							Opcode::I32Eq,
+							Opcode::GetGlobal(flag),
+							Opcode::I32Or,
+							Opcode::SetGlobal(flag),
+						]);
+					}
+				}
+				*body.code_mut().elements_mut() = instrumented;
+			}
+		}
+	}
+
+	own
+}
+
+// whether a `grow_memory` of the instance failed, going by the global set up by `record_failed_grows`.
+fn any_grow_failed<M: ModuleInstanceInterface<DummyUserError> + ?Sized>(module: &M, flag: u32) -> bool {
+	match module.global(ItemIndex::Internal(flag), None, None).map(|global| global.get()) {
+		Ok(I32(failed)) => failed != 0,
+		_ => false,
+	}
+}
+
+fn is_float(ty: ValueType) -> bool {
+	ty == ValueType::F32 || ty == ValueType::F64
+}
+
+// whether the instruction takes, produces or converts a floating point value.
+fn is_float_op(op: &Opcode) -> bool {
+	use parity_wasm::elements::Opcode::*;
+	match *op {
+		Block(BlockType::Value(ty)) | Loop(BlockType::Value(ty)) | If(BlockType::Value(ty)) => is_float(ty),
+
+		F32Load(..) | F64Load(..) | F32Store(..) | F64Store(..) | F32Const(_) | F64Const(_) => true,
+
+		F32Eq | F32Ne | F32Lt | F32Gt | F32Le | F32Ge
+		| F64Eq | F64Ne | F64Lt | F64Gt | F64Le | F64Ge => true,
+
+		F32Abs | F32Neg | F32Ceil | F32Floor | F32Trunc | F32Nearest | F32Sqrt
+		| F32Add | F32Sub | F32Mul | F32Div | F32Min | F32Max | F32Copysign => true,
+
+		F64Abs | F64Neg | F64Ceil | F64Floor | F64Trunc | F64Nearest | F64Sqrt
+		| F64Add | F64Sub | F64Mul | F64Div | F64Min | F64Max | F64Copysign => true,
+
+		I32TruncSF32 | I32TruncUF32 | I32TruncSF64 | I32TruncUF64
+		| I64TruncSF32 | I64TruncUF32 | I64TruncSF64 | I64TruncUF64
+		| F32ConvertSI32 | F32ConvertUI32 | F32ConvertSI64 | F32ConvertUI64 | F32DemoteF64
+		| F64ConvertSI32 | F64ConvertUI32 | F64ConvertSI64 | F64ConvertUI64 | F64PromoteF32
+		| I32ReinterpretF32 | I64ReinterpretF64 | F32ReinterpretI32 | F64ReinterpretI64 => true,
+
+		_ => false,
+	}
+}
+
+// whether the module uses floating point anywhere, which is not deterministic across platforms.
+fn uses_floats(module: &Module) -> bool {
+
+	let in_types = module.type_section().map(|s| s.types()).unwrap_or(&[]).iter()
+		.any(|ty| match *ty {
+			Type::Function(ref f) => f.params().iter().cloned().any(is_float) || f.return_type().map_or(false, is_float),
+		});
+	let in_globals = module.global_section().map(|s| s.entries()).unwrap_or(&[]).iter()
+		.any(|g| is_float(g.global_type().content_type()) || g.init_expr().code().iter().any(is_float_op));
+	let in_code = module.code_section().map(|s| s.bodies()).unwrap_or(&[]).iter()
+		.any(|body| body.locals().iter().any(|l| is_float(l.value_type()))
+			|| body.code().elements().iter().any(is_float_op));
+
+	in_types || in_globals || in_code
+}
+
+// the value of the exported `abi_version` global, which must be an i32 constant.
+fn abi_version(module: &Module) -> Result<u32> {
+	let index = module.export_section().map(|s| s.entries()).unwrap_or(&[]).iter()
+		.find(|entry| entry.field() == "abi_version")
+		.map(|entry| match *entry.internal() {
+			Internal::Global(index) => Ok(index),
+			_ => Err(ErrorKind::InvalidModule("abi_version is not a global".into())),
+		});
+	let index = match index {
+		Some(index) => index?,
+		None => return Ok(1),
+	};
+
+	let imported = module.import_section().map(|s| s.entries()).unwrap_or(&[]).iter()
+		.filter(|entry| match *entry.external() {
+			External::Global(_) => true,
+			_ => false,
+		})
+		.count() as u32;
+	let global = index.checked_sub(imported)
+		.and_then(|index| module.global_section().and_then(|s| s.entries().get(index as usize)))
+		.ok_or_else(|| ErrorKind::InvalidModule("abi_version is not a defined global".into()))?;
+
+	match global.init_expr().code().first() {
+		Some(&Opcode::I32Const(version)) => Ok(version as u32),
+		_ => bail!(ErrorKind::InvalidModule("abi_version is not an i32 constant".into())),
+	}
+}
+
+/// Initial and maximum size of a memory or table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+	/// Size on instantiation.
+	pub initial: u32,
+	/// Size it may grow to, if bounded.
+	pub maximum: Option<u32>,
+}
+
+impl<'a> From<&'a ResizableLimits> for Limits {
+	fn from(limits: &'a ResizableLimits) -> Self {
+		Limits {
+			initial: limits.initial(),
+			maximum: limits.maximum(),
+		}
+	}
+}
+
+/// What `WasmExecutor::validate_code` learned about a runtime blob.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeInfo {
+	/// Names of the exported functions, in export order.
+	pub methods: Vec<String>,
+	/// Memory limits, in pages.
+	pub memory: Limits,
+	/// Table limits, if the module has a table.
+	pub table: Option<Limits>,
+	/// Host function ABI version the runtime was built against.
+	pub abi_version: u32,
+}
+
 /// Wasm rust executor for contracts.
 ///
 /// Executes the provided code in a sandboxed wasm runtime.
-#[derive(Debug, Default)]
-pub struct WasmExecutor;
+#[derive(Debug)]
+pub struct WasmExecutor {
+	max_memory_pages: u32,
+	min_abi_version: u32,
+	max_abi_version: u32,
+	stub_unknown_imports: bool,
+}
 
-impl CodeExecutor for WasmExecutor {
-	type Error = Error;
+impl Default for WasmExecutor {
+	fn default() -> Self {
+		WasmExecutor {
+			max_memory_pages: DEFAULT_MAX_MEMORY_PAGES,
+			min_abi_version: ABI_VERSION,
+			max_abi_version: ABI_VERSION,
+			stub_unknown_imports: false,
+		}
+	}
+}
 
-	fn call<E: Externalities>(
+impl WasmExecutor {
+	/// Create an executor limiting runtimes to the given number of memory pages.
+	///
+	/// Growing memory past the limit fails inside the runtime; a call which fails
+	/// after one of its attempts to grow memory failed is reported as
+	/// `MemoryLimitExceeded`.
+	pub fn with_max_memory_pages(max_memory_pages: u32) -> Self {
+		WasmExecutor {
+			max_memory_pages,
+			..Default::default()
+		}
+	}
+
+	/// Create an executor accepting runtimes built against the given range of
+	/// host function ABI versions, inclusive.
+	pub fn with_abi_versions(min_abi_version: u32, max_abi_version: u32) -> Self {
+		WasmExecutor {
+			min_abi_version,
+			max_abi_version,
+			..Default::default()
+		}
+	}
+
+	/// Create an executor for development, which accepts runtimes importing
+	/// functions other than `ALLOWED_IMPORTS`.
+	///
+	/// Such imports are stubbed out, and a call fails with `UnknownHostFunction`
+	/// only if the runtime actually calls one.
+	pub fn with_stubbed_imports() -> Self {
+		WasmExecutor {
+			stub_unknown_imports: true,
+			..Default::default()
+		}
+	}
+
+	// the first import refused by this executor, if any.
+	fn refused_import(&self, module: &Module) -> Option<String> {
+		if self.stub_unknown_imports {
+			None
+		} else {
+			forbidden_import(module)
+		}
+	}
+
+	/// Check that the given code is a runtime which can be instantiated, without
+	/// calling any of its methods.
+	pub fn validate_code(&self, code: &[u8]) -> Result<RuntimeInfo> {
+		let module = deserialize_buffer(code.to_vec())
+			.map_err(|e| ErrorKind::InvalidModule(format!("{:?}", e)))?;
+
+		if uses_floats(&module) {
+			bail!(ErrorKind::FloatingPointForbidden);
+		}
+
+		if let Some(name) = self.refused_import(&module) {
+			bail!(ErrorKind::ForbiddenImport(name));
+		}
+
+		let abi_version = abi_version(&module)?;
+		if abi_version < self.min_abi_version || abi_version > self.max_abi_version {
+			bail!(ErrorKind::AbiVersionMismatch(abi_version));
+		}
+
+		let methods = module.export_section().map(|s| s.entries()).unwrap_or(&[]).iter()
+			.filter(|entry| match *entry.internal() {
+				Internal::Function(_) => true,
+				_ => false,
+			})
+			.map(|entry| entry.field().to_owned())
+			.collect();
+
+		let memory: Limits = module.memory_section()
+			.and_then(|s| s.entries().first())
+			.map(|m| m.limits().into())
+			.ok_or_else(|| ErrorKind::InvalidModule("no memory".into()))?;
+
+		if memory.initial > self.max_memory_pages || memory.maximum.map_or(false, |max| max < memory.initial) {
+			bail!(ErrorKind::InvalidModule(format!("unreasonable memory limits: {:?}", memory)));
+		}
+
+		let table = module.table_section()
+			.and_then(|s| s.entries().first())
+			.map(|t| t.limits().into());
+
+		let provided = FunctionExecutor::<NoExternalities>::SIGNATURES;
+		let mut signatures = provided.to_vec();
+		signatures.extend(wasm_utils::missing_signatures(&module, provided));
+
+		let program = ProgramInstance::new().expect("this really shouldn't be able to fail; qed");
+		program.add_module_by_sigs("test", module, map!["env" => Cow::from(signatures)])
+			.map_err(|e| ErrorKind::InvalidModule(format!("{:?}", e)))?;
+
+		Ok(RuntimeInfo {
+			methods,
+			memory,
+			table,
+			abi_version,
+		})
+	}
+}
+
+impl WasmExecutor {
+	// call `method`, charging one unit of `gas` per host call if given.
+	fn call_metered<E: Externalities>(
 		&self,
 		ext: &mut E,
 		code: &[u8],
 		method: &str,
 		data: &CallData,
+		gas: Option<&mut u64>,
 	) -> Result<Vec<u8>> {
-		// TODO: handle all expects as errors to be returned.
-
 		let program = ProgramInstance::new().expect("this really shouldn't be able to fail; qed");
 
-		let module = deserialize_buffer(code.to_vec()).expect("all modules compiled with rustc are valid wasm code; qed");
-		let module = program.add_module_by_sigs("test", module, map!["env" => FunctionExecutor::<E>::SIGNATURES]).expect("runtime signatures always provided; qed");
+		let mut module = deserialize_buffer(code.to_vec())
+			.map_err(|e| ErrorKind::InvalidModule(format!("{:?}", e)))?;
+		limit_memory(&mut module, self.max_memory_pages)?;
+
+		if let Some(name) = self.refused_import(&module) {
+			bail!(ErrorKind::ForbiddenImport(name));
+		}
+		let grow_flag = record_failed_grows(&mut module);
+
+		let exported = module.export_section().map(|s| s.entries()).unwrap_or(&[]).iter()
+			.any(|entry| entry.field() == method && match *entry.internal() {
+				Internal::Function(_) => true,
+				_ => false,
+			});
+		if !exported {
+			bail!(ErrorKind::MethodNotFound(method.to_owned()));
+		}
+
+		// imports we don't implement are stubbed out and fail only when called;
+		// outside development mode they have been refused already.
+		let mut signatures = FunctionExecutor::<E>::SIGNATURES.to_vec();
+		signatures.extend(wasm_utils::missing_signatures(&module, FunctionExecutor::<E>::SIGNATURES));
+		let signatures = Cow::from(signatures);
+
+		let module = program.add_module_by_sigs("test", module, map!["env" => signatures.clone()])
+			.map_err(|e| ErrorKind::InvalidModule(format!("{:?}", e)))?;
 
-		let memory = module.memory(ItemIndex::Internal(0)).expect("all modules compiled with rustc include memory segments; qed");
+		let memory = module.memory(ItemIndex::Internal(0))
+			.map_err(|_| ErrorKind::InvalidModule("no memory".into()))?;
 		let mut fec = FunctionExecutor::new(&memory, ext);
+		fec.signatures = signatures;
+		fec.gas_left = gas.as_ref().map(|gas| **gas);
 
 		let size = data.0.len() as u32;
 		let offset = fec.heap.allocate(size);
@@ -165,8 +686,21 @@ impl CodeExecutor for WasmExecutor {
 				.map(|p| p
 					.add_argument(I32(offset as i32))
 					.add_argument(I32(size as i32)))
-			.and_then(|p| module.execute_export(method, p))
-			.map_err(|_| -> Error { ErrorKind::Runtime.into() })?;
+			.and_then(|p| module.execute_export(method, p));
+
+		if let Some(gas) = gas {
+			*gas = fec.gas_left.unwrap_or(0);
+		}
+
+		let returned = match returned {
+			Ok(returned) => returned,
+			Err(_) => return Err(match fec.unknown_call.take() {
+				Some(name) => ErrorKind::UnknownHostFunction(name).into(),
+				None if fec.gas_exhausted => ErrorKind::GasExhausted.into(),
+				None if any_grow_failed(&*module, grow_flag) => ErrorKind::MemoryLimitExceeded(self.max_memory_pages).into(),
+				None => ErrorKind::Runtime.into(),
+			}),
+		};
 
 		if let Some(I64(r)) = returned {
 			memory.get(r as u32, (r >> 32) as u32 as usize)
@@ -177,6 +711,35 @@ impl CodeExecutor for WasmExecutor {
 	}
 }
 
+impl CodeExecutor for WasmExecutor {
+	type Error = Error;
+
+	fn call<E: Externalities>(
+		&self,
+		ext: &mut E,
+		code: &[u8],
+		method: &str,
+		data: &CallData,
+	) -> Result<Vec<u8>> {
+		self.call_metered(ext, code, method, data, None)
+	}
+
+	/// Each host call costs one unit of gas; running out fails with `GasExhausted`.
+	///
+	/// Instructions executed between host calls are not metered, so a loop
+	/// which never calls the host runs unbounded.
+	fn call_with_gas<E: Externalities>(
+		&self,
+		ext: &mut E,
+		code: &[u8],
+		method: &str,
+		data: &CallData,
+		gas: &mut u64,
+	) -> Result<Vec<u8>> {
+		self.call_metered(ext, code, method, data, Some(gas))
+	}
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -185,6 +748,8 @@ mod tests {
 	#[derive(Debug, Default)]
 	struct TestExternalities {
 		storage: HashMap<Vec<u8>, Vec<u8>>,
+		events: Vec<(Vec<u8>, Vec<u8>)>,
+		drbg: Option<::state_machine::Drbg>,
 	}
 	impl Externalities for TestExternalities {
 		type Error = Error;
@@ -196,6 +761,39 @@ mod tests {
 		fn set_storage(&mut self, key: Vec<u8>, value: Vec<u8>) {
 			self.storage.insert(key, value);
 		}
+
+		fn storage_root(&self) -> Result<[u8; 32]> {
+			Ok(::state_machine::backend::storage_root(self.storage.clone()))
+		}
+
+		fn clear_prefix(&mut self, prefix: &[u8]) -> Result<()> {
+			self.storage.retain(|key, _| !key.starts_with(prefix));
+			Ok(())
+		}
+
+		fn next_storage_key(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+			Ok(self.storage.keys().filter(|k| &k[..] > key).min().cloned())
+		}
+
+		fn child_storage(&self, _storage_key: &[u8], _key: &[u8]) -> Result<&[u8]> {
+			Ok(&[])
+		}
+
+		fn set_child_storage(&mut self, _storage_key: Vec<u8>, _key: Vec<u8>, _value: Vec<u8>) {}
+
+		fn child_storage_root(&self, _storage_key: &[u8]) -> Result<[u8; 32]> {
+			Ok(::state_machine::backend::storage_root(Vec::new()))
+		}
+
+		fn random(&mut self, len: usize) -> Vec<u8> {
+			let storage = &self.storage;
+			self.drbg.get_or_insert_with(|| ::state_machine::Drbg::new(::state_machine::backend::storage_root(storage.clone())))
+				.next_bytes(len)
+		}
+
+		fn emit_event(&mut self, topic: Vec<u8>, data: Vec<u8>) {
+			self.events.push((topic, data));
+		}
 	}
 
 	#[test]
@@ -207,7 +805,7 @@ mod tests {
 
 		let test_module = include_bytes!("../../runtime/target/wasm32-unknown-unknown/release/runtime_test.compact.wasm");
 		let module = deserialize_buffer(test_module.to_vec()).expect("Failed to load module");
-		let module = program.add_module_by_sigs("test", module, map!["env" => FunctionExecutor::<TestExternalities>::SIGNATURES]).expect("Failed to initialize module");
+		let module = program.add_module_by_sigs("test", module, map!["env" => Cow::from(FunctionExecutor::<TestExternalities>::SIGNATURES)]).expect("Failed to initialize module");
 
 		let output = {
 			let memory = module.memory(ItemIndex::Internal(0)).unwrap();
@@ -245,4 +843,472 @@ mod tests {
 		];
 		assert_eq!(expected, ext.storage);
 	}
+
+	#[test]
+	fn call_runs_compiled_runtime() {
+		let mut ext = TestExternalities::default();
+		ext.set_storage(b"\0code".to_vec(), b"The code".to_vec());
+
+		let test_code = include_bytes!("../../runtime/target/wasm32-unknown-unknown/release/runtime_test.compact.wasm");
+		let output = WasmExecutor::default().call(&mut ext, &test_code[..], "test_data_in", &CallData(b"Hello world".to_vec())).unwrap();
+
+		assert_eq!(output, b"all ok!".to_vec());
+		assert_eq!(ext.storage.get(&b"input"[..]), Some(&b"Hello world".to_vec()));
+	}
+
+	#[test]
+	fn call_reports_missing_method_and_malformed_code() {
+		let mut ext = TestExternalities::default();
+		let test_code = include_bytes!("../../runtime/target/wasm32-unknown-unknown/release/runtime_test.compact.wasm");
+
+		let err = WasmExecutor::default().call(&mut ext, &test_code[..], "test", &CallData(vec![])).unwrap_err();
+		assert_matches!(*err.kind(), ErrorKind::MethodNotFound(ref name) if name == "test");
+
+		let err = WasmExecutor::default().call(&mut ext, b"not wasm", "test", &CallData(vec![])).unwrap_err();
+		assert_matches!(*err.kind(), ErrorKind::InvalidModule(_));
+	}
+
+	// a module whose `test` export calls the unknown host function `ext_bogus`.
+	fn bogus_import_module() -> Vec<u8> {
+		vec![
+			// header
+			0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+			// types: () -> (), (i32, i32) -> i64
+			0x01, 0x0a, 0x02, 0x60, 0x00, 0x00, 0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7e,
+			// imports: env.ext_bogus
+			0x02, 0x11, 0x01, 0x03, 0x65, 0x6e, 0x76, 0x09, 0x65, 0x78, 0x74, 0x5f, 0x62, 0x6f, 0x67, 0x75, 0x73, 0x00, 0x00,
+			// functions
+			0x03, 0x02, 0x01, 0x01,
+			// memory
+			0x05, 0x03, 0x01, 0x00, 0x01,
+			// exports: test
+			0x07, 0x08, 0x01, 0x04, 0x74, 0x65, 0x73, 0x74, 0x00, 0x01,
+			// code: call ext_bogus; i64.const 0
+			0x0a, 0x08, 0x01, 0x06, 0x00, 0x10, 0x00, 0x42, 0x00, 0x0b,
+		]
+	}
+
+	#[test]
+	fn unknown_host_function_is_reported_by_name() {
+		let code = bogus_import_module();
+		assert!(WasmExecutor::with_stubbed_imports().validate_code(&code).is_ok());
+
+		let mut ext = TestExternalities::default();
+		let err = WasmExecutor::with_stubbed_imports().call(&mut ext, &code, "test", &CallData(vec![])).unwrap_err();
+		assert_matches!(*err.kind(), ErrorKind::UnknownHostFunction(ref name) if name == "ext_bogus");
+	}
+
+	#[test]
+	fn random_bytes_past_memory_trap() {
+		let code = [
+			// header
+			0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+			// types: (i32, i32) -> (), (i32, i32) -> i64
+			0x01, 0x0c, 0x02, 0x60, 0x02, 0x7f, 0x7f, 0x00, 0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7e,
+			// imports: env.ext_random
+			0x02, 0x12, 0x01, 0x03, 0x65, 0x6e, 0x76, 0x0a, 0x65, 0x78, 0x74, 0x5f, 0x72, 0x61, 0x6e, 0x64, 0x6f, 0x6d, 0x00, 0x00,
+			// functions
+			0x03, 0x02, 0x01, 0x01,
+			// memory
+			0x05, 0x03, 0x01, 0x00, 0x01,
+			// exports: test
+			0x07, 0x08, 0x01, 0x04, 0x74, 0x65, 0x73, 0x74, 0x00, 0x01,
+			// code: ask for 2^32 - 1 random bytes at 0; i64.const 0
+			0x0a, 0x0c, 0x01, 0x0a, 0x00, 0x41, 0x00, 0x41, 0x7f, 0x10, 0x00, 0x42, 0x00, 0x0b,
+		];
+
+		let mut ext = TestExternalities::default();
+		let err = WasmExecutor::default().call(&mut ext, &code, "test", &CallData(vec![])).unwrap_err();
+		assert_matches!(*err.kind(), ErrorKind::Runtime);
+	}
+
+	#[test]
+	fn forbidden_import_is_rejected_at_load() {
+		let code = bogus_import_module();
+		let err = WasmExecutor::default().validate_code(&code).unwrap_err();
+		assert_matches!(*err.kind(), ErrorKind::ForbiddenImport(ref name) if name == "ext_bogus");
+
+		let mut ext = TestExternalities::default();
+		let err = WasmExecutor::default().call(&mut ext, &code, "test", &CallData(vec![])).unwrap_err();
+		assert_matches!(*err.kind(), ErrorKind::ForbiddenImport(ref name) if name == "ext_bogus");
+
+		// imports only allowed host functions.
+		let test_module = include_bytes!("../../runtime/target/wasm32-unknown-unknown/release/runtime_test.compact.wasm");
+		assert!(WasmExecutor::default().validate_code(test_module).is_ok());
+	}
+
+	#[test]
+	fn validate_code_reports_runtime_info() {
+		let test_module = include_bytes!("../../runtime/target/wasm32-unknown-unknown/release/runtime_test.compact.wasm");
+		let info = WasmExecutor::default().validate_code(test_module).unwrap();
+
+		assert!(info.methods.iter().any(|m| m == "test_data_in"));
+		assert!(info.memory.initial <= DEFAULT_MAX_MEMORY_PAGES);
+	}
+
+	#[test]
+	fn float_instructions_are_recognised() {
+		use parity_wasm::elements::{BlockType, Opcode, ValueType};
+
+		assert!(super::is_float_op(&Opcode::F64PromoteF32));
+		assert!(super::is_float_op(&Opcode::I32ReinterpretF32));
+		assert!(super::is_float_op(&Opcode::Block(BlockType::Value(ValueType::F64))));
+		assert!(!super::is_float_op(&Opcode::Block(BlockType::NoResult)));
+		assert!(!super::is_float_op(&Opcode::I64Add));
+	}
+
+	#[test]
+	fn validate_code_rejects_floating_point() {
+		let code = [
+			// header
+			0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+			// types: () -> ()
+			0x01, 0x04, 0x01, 0x60, 0x00, 0x00,
+			// functions
+			0x03, 0x02, 0x01, 0x00,
+			// memory
+			0x05, 0x03, 0x01, 0x00, 0x01,
+			// exports: test
+			0x07, 0x08, 0x01, 0x04, 0x74, 0x65, 0x73, 0x74, 0x00, 0x00,
+			// code: f32.const 0; drop
+			0x0a, 0x0a, 0x01, 0x08, 0x00, 0x43, 0x00, 0x00, 0x00, 0x00, 0x1a, 0x0b,
+		];
+
+		let err = WasmExecutor::default().validate_code(&code).unwrap_err();
+		assert_matches!(*err.kind(), ErrorKind::FloatingPointForbidden);
+
+		let test_module = include_bytes!("../../runtime/target/wasm32-unknown-unknown/release/runtime_test.compact.wasm");
+		assert!(WasmExecutor::default().validate_code(test_module).is_ok());
+	}
+
+	#[test]
+	fn validate_code_checks_abi_version() {
+		let code = |version: u8| vec![
+			// header
+			0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+			// types: () -> ()
+			0x01, 0x04, 0x01, 0x60, 0x00, 0x00,
+			// functions
+			0x03, 0x02, 0x01, 0x00,
+			// memory
+			0x05, 0x03, 0x01, 0x00, 0x01,
+			// globals: abi_version = version
+			0x06, 0x06, 0x01, 0x7f, 0x00, 0x41, version, 0x0b,
+			// exports: test, abi_version
+			0x07, 0x16, 0x02, 0x04, 0x74, 0x65, 0x73, 0x74, 0x00, 0x00, 0x0b, 0x61, 0x62, 0x69, 0x5f, 0x76, 0x65, 0x72, 0x73, 0x69, 0x6f, 0x6e, 0x03, 0x00,
+			// code: nothing
+			0x0a, 0x04, 0x01, 0x02, 0x00, 0x0b,
+		];
+
+		assert_eq!(WasmExecutor::default().validate_code(&code(1)).unwrap().abi_version, 1);
+
+		let err = WasmExecutor::default().validate_code(&code(2)).unwrap_err();
+		assert_matches!(*err.kind(), ErrorKind::AbiVersionMismatch(2));
+		assert!(WasmExecutor::with_abi_versions(1, 2).validate_code(&code(2)).is_ok());
+
+		let test_module = include_bytes!("../../runtime/target/wasm32-unknown-unknown/release/runtime_test.compact.wasm");
+		assert_eq!(WasmExecutor::default().validate_code(test_module).unwrap().abi_version, 1);
+	}
+
+	#[test]
+	fn validate_code_rejects_malformed_code() {
+		let err = WasmExecutor::default().validate_code(b"not wasm").unwrap_err();
+		assert_matches!(*err.kind(), ErrorKind::InvalidModule(_));
+	}
+
+	#[test]
+	fn emitted_events_reach_externalities() {
+		let code = [
+			// header
+			0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+			// types: (i32, i32, i32, i32) -> (), (i32, i32) -> i64
+			0x01, 0x0e, 0x02, 0x60, 0x04, 0x7f, 0x7f, 0x7f, 0x7f, 0x00, 0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7e,
+			// imports: env.ext_emit_event
+			0x02, 0x16, 0x01, 0x03, 0x65, 0x6e, 0x76, 0x0e, 0x65, 0x78, 0x74, 0x5f, 0x65, 0x6d, 0x69, 0x74, 0x5f, 0x65, 0x76, 0x65, 0x6e, 0x74, 0x00, 0x00,
+			// functions
+			0x03, 0x02, 0x01, 0x01,
+			// memory
+			0x05, 0x03, 0x01, 0x00, 0x01,
+			// exports: test
+			0x07, 0x08, 0x01, 0x04, 0x74, 0x65, 0x73, 0x74, 0x00, 0x01,
+			// code: emit ("a", "xy"), ("b", "zw"); i64.const 0
+			0x0a, 0x1a, 0x01, 0x18, 0x00, 0x41, 0x00, 0x41, 0x01, 0x41, 0x01, 0x41, 0x02, 0x10, 0x00, 0x41, 0x03, 0x41, 0x01, 0x41, 0x04, 0x41, 0x02, 0x10, 0x00, 0x42, 0x00, 0x0b,
+			// data: "axybzw" at 0
+			0x0b, 0x0c, 0x01, 0x00, 0x41, 0x00, 0x0b, 0x06, 0x61, 0x78, 0x79, 0x62, 0x7a, 0x77,
+		];
+
+		let mut ext = TestExternalities::default();
+		WasmExecutor::default().call(&mut ext, &code, "test", &CallData(vec![])).unwrap();
+		assert_eq!(ext.events, vec![
+			(b"a".to_vec(), b"xy".to_vec()),
+			(b"b".to_vec(), b"zw".to_vec()),
+		]);
+	}
+
+	#[test]
+	fn storage_round_trips_through_overlay() {
+		use state_machine::{execute, OverlayedChanges, Update};
+		use state_machine::backend::{Backend, InMemory};
+
+		let code = vec![
+			// header
+			0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+			// types: (i32, i32, i32, i32) -> (), (i32, i32, i32, i32) -> i32, (i32, i32) -> i64
+			0x01, 0x16, 0x03, 0x60, 0x04, 0x7f, 0x7f, 0x7f, 0x7f, 0x00, 0x60, 0x04, 0x7f, 0x7f, 0x7f, 0x7f, 0x01, 0x7f, 0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7e,
+			// imports: env.ext_set_storage, env.ext_get_storage_into
+			0x02, 0x32, 0x02, 0x03, 0x65, 0x6e, 0x76, 0x0f, 0x65, 0x78, 0x74, 0x5f, 0x73, 0x65, 0x74, 0x5f, 0x73, 0x74, 0x6f, 0x72, 0x61, 0x67, 0x65, 0x00, 0x00, 0x03, 0x65, 0x6e, 0x76, 0x14, 0x65, 0x78, 0x74, 0x5f, 0x67, 0x65, 0x74, 0x5f, 0x73, 0x74, 0x6f, 0x72, 0x61, 0x67, 0x65, 0x5f, 0x69, 0x6e, 0x74, 0x6f, 0x00, 0x01,
+			// functions
+			0x03, 0x03, 0x02, 0x02, 0x02,
+			// memory
+			0x05, 0x03, 0x01, 0x00, 0x01,
+			// exports: test, missing
+			0x07, 0x12, 0x02, 0x04, 0x74, 0x65, 0x73, 0x74, 0x00, 0x02, 0x07, 0x6d, 0x69, 0x73, 0x73, 0x69, 0x6e, 0x67, 0x00, 0x03,
+			// code: set "k" to "v!" and return it as read back into 16; store the length read for "x" at 32 and return it
+			0x0a, 0x38, 0x02, 0x1d, 0x00, 0x41, 0x00, 0x41, 0x01, 0x41, 0x01, 0x41, 0x02, 0x10, 0x00, 0x41, 0x00, 0x41, 0x01, 0x41, 0x10, 0x41, 0x02, 0x10, 0x01, 0xad, 0x42, 0x20, 0x86, 0x42, 0x10, 0x84, 0x0b, 0x18, 0x00, 0x41, 0x20, 0x41, 0x03, 0x41, 0x01, 0x41, 0x30, 0x41, 0x00, 0x10, 0x01, 0x36, 0x02, 0x00, 0x42, 0xa0, 0x80, 0x80, 0x80, 0xc0, 0x00, 0x0b,
+			// data: "kv!x" at 0
+			0x0b, 0x0a, 0x01, 0x00, 0x41, 0x00, 0x0b, 0x04, 0x6b, 0x76, 0x21, 0x78,
+		];
+
+		let mut backend = InMemory::default();
+		backend.commit(vec![Update::Storage(b"\0code".to_vec(), code)]);
+		let mut overlay = OverlayedChanges::default();
+
+		let read_back = execute(&backend, &mut overlay, &WasmExecutor::default(), "test", &CallData(vec![])).unwrap();
+		assert_eq!(read_back, b"v!".to_vec());
+		assert_eq!(overlay.storage(b"k"), Some(&b"v!"[..]));
+
+		let missing = execute(&backend, &mut overlay, &WasmExecutor::default(), "missing", &CallData(vec![])).unwrap();
+		assert_eq!(missing, vec![0xff; 4]);
+	}
+
+	#[test]
+	fn storage_read_past_memory_traps() {
+		let code = [
+			// header
+			0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+			// types: (i32, i32, i32, i32) -> i32, (i32, i32) -> i64
+			0x01, 0x0f, 0x02, 0x60, 0x04, 0x7f, 0x7f, 0x7f, 0x7f, 0x01, 0x7f, 0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7e,
+			// imports: env.ext_get_storage_into
+			0x02, 0x1c, 0x01, 0x03, 0x65, 0x6e, 0x76, 0x14, 0x65, 0x78, 0x74, 0x5f, 0x67, 0x65, 0x74, 0x5f, 0x73, 0x74, 0x6f, 0x72, 0x61, 0x67, 0x65, 0x5f, 0x69, 0x6e, 0x74, 0x6f, 0x00, 0x00,
+			// functions
+			0x03, 0x02, 0x01, 0x01,
+			// memory: 1 page
+			0x05, 0x03, 0x01, 0x00, 0x01,
+			// exports: test
+			0x07, 0x08, 0x01, 0x04, 0x74, 0x65, 0x73, 0x74, 0x00, 0x01,
+			// code: read "k" into a 16 byte buffer at 65535, the last byte of memory; i64.const 0
+			0x0a, 0x13, 0x01, 0x11, 0x00, 0x41, 0x00, 0x41, 0x01, 0x41, 0xff, 0xff, 0x03, 0x41, 0x10, 0x10, 0x00, 0x1a, 0x42, 0x00, 0x0b,
+			// data: "k" at 0
+			0x0b, 0x07, 0x01, 0x00, 0x41, 0x00, 0x0b, 0x01, 0x6b,
+		];
+
+		let mut ext = TestExternalities::default();
+		ext.set_storage(b"k".to_vec(), b"value".to_vec());
+		let err = WasmExecutor::default().call(&mut ext, &code, "test", &CallData(vec![])).unwrap_err();
+		assert_matches!(*err.kind(), ErrorKind::Runtime);
+	}
+
+	#[test]
+	fn endless_loop_runs_out_of_gas() {
+		use state_machine::{execute_with_config, ExecutionConfig, OverlayedChanges, Update};
+		use state_machine::backend::{Backend, InMemory};
+
+		let code = vec![
+			// header
+			0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+			// types: () -> i64, (i32, i32, i32, i32) -> (), (i32, i32) -> i64
+			0x01, 0x12, 0x03, 0x60, 0x00, 0x01, 0x7e, 0x60, 0x04, 0x7f, 0x7f, 0x7f, 0x7f, 0x00, 0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7e,
+			// imports: env.ext_storage_quota_left, env.ext_set_storage
+			0x02, 0x34, 0x02, 0x03, 0x65, 0x6e, 0x76, 0x16, 0x65, 0x78, 0x74, 0x5f, 0x73, 0x74, 0x6f, 0x72, 0x61, 0x67, 0x65, 0x5f, 0x71, 0x75, 0x6f, 0x74, 0x61, 0x5f, 0x6c, 0x65, 0x66, 0x74, 0x00, 0x00, 0x03, 0x65, 0x6e, 0x76, 0x0f, 0x65, 0x78, 0x74, 0x5f, 0x73, 0x65, 0x74, 0x5f, 0x73, 0x74, 0x6f, 0x72, 0x61, 0x67, 0x65, 0x00, 0x01,
+			// functions
+			0x03, 0x02, 0x01, 0x02,
+			// memory
+			0x05, 0x03, 0x01, 0x00, 0x01,
+			// exports: test
+			0x07, 0x08, 0x01, 0x04, 0x74, 0x65, 0x73, 0x74, 0x00, 0x02,
+			// code: set "k" to "v", then call ext_storage_quota_left forever
+			0x0a, 0x18, 0x01, 0x16, 0x00, 0x41, 0x00, 0x41, 0x01, 0x41, 0x01, 0x41, 0x01, 0x10, 0x01, 0x03, 0x40, 0x10, 0x00, 0x1a, 0x0c, 0x00, 0x0b, 0x42, 0x00, 0x0b,
+			// data: "kv" at 0
+			0x0b, 0x08, 0x01, 0x00, 0x41, 0x00, 0x0b, 0x02, 0x6b, 0x76,
+		];
+
+		let mut ext = TestExternalities::default();
+		let mut gas = 1000;
+		let err = WasmExecutor::default().call_with_gas(&mut ext, &code, "test", &CallData(vec![]), &mut gas).unwrap_err();
+		assert_matches!(*err.kind(), ErrorKind::GasExhausted);
+		assert_eq!(gas, 0);
+
+		let mut backend = InMemory::default();
+		backend.commit(vec![Update::Storage(b"\0code".to_vec(), code)]);
+		let mut overlay = OverlayedChanges::default();
+		let config = ExecutionConfig { gas_limit: Some(1000), ..Default::default() };
+
+		assert!(execute_with_config(&backend, &mut overlay, &WasmExecutor::default(), "test", &CallData(vec![]), config).is_err());
+		assert!(overlay.storage(b"k").is_none());
+	}
+
+	#[test]
+	fn loops_without_host_calls_use_no_gas() {
+		let code = vec![
+			// header
+			0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+			// types: (i32, i32) -> i64
+			0x01, 0x07, 0x01, 0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7e,
+			// functions
+			0x03, 0x02, 0x01, 0x00,
+			// memory
+			0x05, 0x03, 0x01, 0x00, 0x01,
+			// exports: test
+			0x07, 0x08, 0x01, 0x04, 0x74, 0x65, 0x73, 0x74, 0x00, 0x00,
+			// code: count a local up to 100000, then return nothing
+			0x0a, 0x19, 0x01, 0x17, 0x01, 0x01, 0x7f, 0x03, 0x40, 0x20, 0x02, 0x41, 0x01, 0x6a, 0x22, 0x02, 0x41, 0xa0, 0x8d, 0x06, 0x49, 0x0d, 0x00, 0x0b, 0x42, 0x00, 0x0b,
+		];
+
+		// gas is charged per host call only, so this runs to completion for free.
+		let mut ext = TestExternalities::default();
+		let mut gas = 10;
+		let output = WasmExecutor::default().call_with_gas(&mut ext, &code, "test", &CallData(vec![]), &mut gas).unwrap();
+		assert_eq!(output, Vec::<u8>::new());
+		assert_eq!(gas, 10);
+	}
+
+	#[test]
+	fn storage_quota_decreases_by_bytes_written() {
+		use state_machine::{execute_with_config, ExecutionConfig, OverlayedChanges, Update};
+		use state_machine::backend::{Backend, InMemory};
+
+		let code = vec![
+			// header
+			0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+			// types: () -> i64, (i32, i32, i32, i32) -> (), (i32, i32) -> i64
+			0x01, 0x12, 0x03, 0x60, 0x00, 0x01, 0x7e, 0x60, 0x04, 0x7f, 0x7f, 0x7f, 0x7f, 0x00, 0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7e,
+			// imports: env.ext_storage_quota_left, env.ext_set_storage
+			0x02, 0x34, 0x02, 0x03, 0x65, 0x6e, 0x76, 0x16, 0x65, 0x78, 0x74, 0x5f, 0x73, 0x74, 0x6f, 0x72, 0x61, 0x67, 0x65, 0x5f, 0x71, 0x75, 0x6f, 0x74, 0x61, 0x5f, 0x6c, 0x65, 0x66, 0x74, 0x00, 0x00, 0x03, 0x65, 0x6e, 0x76, 0x0f, 0x65, 0x78, 0x74, 0x5f, 0x73, 0x65, 0x74, 0x5f, 0x73, 0x74, 0x6f, 0x72, 0x61, 0x67, 0x65, 0x00, 0x01,
+			// functions
+			0x03, 0x02, 0x01, 0x02,
+			// memory
+			0x05, 0x03, 0x01, 0x00, 0x01,
+			// exports: test
+			0x07, 0x08, 0x01, 0x04, 0x74, 0x65, 0x73, 0x74, 0x00, 0x02,
+			// code: before = quota; set "k" to "vvvv"; store before - quota at 8; return 8 bytes at 8
+			0x0a, 0x25, 0x01, 0x23, 0x01, 0x01, 0x7e, 0x10, 0x00, 0x21, 0x02, 0x41, 0x01, 0x41, 0x01, 0x41, 0x02, 0x41, 0x04, 0x10, 0x01, 0x41, 0x08, 0x20, 0x02, 0x10, 0x00, 0x7d, 0x37, 0x03, 0x00, 0x42, 0x88, 0x80, 0x80, 0x80, 0x80, 0x01, 0x0b,
+			// data: "kvvvv" at 1
+			0x0b, 0x0b, 0x01, 0x00, 0x41, 0x01, 0x0b, 0x05, 0x6b, 0x76, 0x76, 0x76, 0x76,
+		];
+
+		let mut backend = InMemory::default();
+		backend.commit(vec![Update::Storage(b"\0code".to_vec(), code)]);
+		let mut overlay = OverlayedChanges::default();
+		let config = ExecutionConfig { storage_quota: Some(100), ..Default::default() };
+
+		let used = execute_with_config(&backend, &mut overlay, &WasmExecutor::default(), "test", &CallData(vec![]), config).unwrap();
+		assert_eq!(used, vec![4, 0, 0, 0, 0, 0, 0, 0]);
+	}
+
+	#[test]
+	fn other_traps_at_the_memory_limit_are_runtime_errors() {
+		let code = [
+			// header
+			0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+			// types: (i32, i32) -> i64
+			0x01, 0x07, 0x01, 0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7e,
+			// functions
+			0x03, 0x02, 0x01, 0x00,
+			// memory: 1 page
+			0x05, 0x03, 0x01, 0x00, 0x01,
+			// exports: test
+			0x07, 0x08, 0x01, 0x04, 0x74, 0x65, 0x73, 0x74, 0x00, 0x00,
+			// code: unreachable
+			0x0a, 0x05, 0x01, 0x03, 0x00, 0x00, 0x0b,
+		];
+
+		let mut ext = TestExternalities::default();
+		let err = WasmExecutor::with_max_memory_pages(1).call(&mut ext, &code, "test", &CallData(vec![])).unwrap_err();
+		assert_matches!(*err.kind(), ErrorKind::Runtime);
+	}
+
+	#[test]
+	fn traps_after_a_successful_grow_are_runtime_errors() {
+		let code = [
+			// header
+			0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+			// types: (i32, i32) -> i64
+			0x01, 0x07, 0x01, 0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7e,
+			// functions
+			0x03, 0x02, 0x01, 0x00,
+			// memory: 1 page, unbounded
+			0x05, 0x03, 0x01, 0x00, 0x01,
+			// exports: test
+			0x07, 0x08, 0x01, 0x04, 0x74, 0x65, 0x73, 0x74, 0x00, 0x00,
+			// code: grow by 1; unreachable
+			0x0a, 0x0a, 0x01, 0x08, 0x00, 0x41, 0x01, 0x40, 0x00, 0x1a, 0x00, 0x0b,
+		];
+
+		let mut ext = TestExternalities::default();
+		let err = WasmExecutor::with_max_memory_pages(2).call(&mut ext, &code, "test", &CallData(vec![])).unwrap_err();
+		assert_matches!(*err.kind(), ErrorKind::Runtime);
+	}
+
+	#[test]
+	fn failed_grow_is_remembered_past_later_grows() {
+		let code = [
+			// header
+			0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+			// types: (i32, i32) -> i64
+			0x01, 0x07, 0x01, 0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7e,
+			// functions
+			0x03, 0x02, 0x01, 0x00,
+			// memory: 1 page, unbounded
+			0x05, 0x03, 0x01, 0x00, 0x01,
+			// exports: test
+			0x07, 0x08, 0x01, 0x04, 0x74, 0x65, 0x73, 0x74, 0x00, 0x00,
+			// code: grow by 2; grow by 1; unreachable
+			0x0a, 0x0f, 0x01, 0x0d, 0x00, 0x41, 0x02, 0x40, 0x00, 0x1a, 0x41, 0x01, 0x40, 0x00, 0x1a, 0x00, 0x0b,
+		];
+
+		let mut ext = TestExternalities::default();
+		let err = WasmExecutor::with_max_memory_pages(2).call(&mut ext, &code, "test", &CallData(vec![])).unwrap_err();
+		assert_matches!(*err.kind(), ErrorKind::MemoryLimitExceeded(2));
+	}
+
+	#[test]
+	fn growing_memory_past_limit_is_reported() {
+		use state_machine::{execute, OverlayedChanges, Update};
+		use state_machine::backend::{Backend, InMemory};
+
+		let code = vec![
+			// header
+			0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+			// types: (i32, i32, i32, i32) -> (), (i32, i32) -> i64
+			0x01, 0x0e, 0x02, 0x60, 0x04, 0x7f, 0x7f, 0x7f, 0x7f, 0x00, 0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7e,
+			// imports: env.ext_set_storage
+			0x02, 0x17, 0x01, 0x03, 0x65, 0x6e, 0x76, 0x0f, 0x65, 0x78, 0x74, 0x5f, 0x73, 0x65, 0x74, 0x5f, 0x73, 0x74, 0x6f, 0x72, 0x61, 0x67, 0x65, 0x00, 0x00,
+			// functions
+			0x03, 0x02, 0x01, 0x01,
+			// memory: 1 page, unbounded
+			0x05, 0x03, 0x01, 0x00, 0x01,
+			// exports: test
+			0x07, 0x08, 0x01, 0x04, 0x74, 0x65, 0x73, 0x74, 0x00, 0x01,
+			// code: set "k" to "v"; grow by 1; grow by 1, trapping on failure; i64.const 0
+			0x0a, 0x20, 0x01, 0x1e, 0x00, 0x41, 0x01, 0x41, 0x01, 0x41, 0x02, 0x41, 0x01, 0x10, 0x00, 0x41, 0x01, 0x40, 0x00, 0x1a, 0x41, 0x01, 0x40, 0x00, 0x41, 0x7f, 0x46, 0x04, 0x40, 0x00, 0x0b, 0x42, 0x00, 0x0b,
+			// data: "kv" at 1
+			0x0b, 0x08, 0x01, 0x00, 0x41, 0x01, 0x0b, 0x02, 0x6b, 0x76,
+		];
+
+		let mut ext = TestExternalities::default();
+		let err = WasmExecutor::with_max_memory_pages(2).call(&mut ext, &code, "test", &CallData(vec![])).unwrap_err();
+		assert_matches!(*err.kind(), ErrorKind::MemoryLimitExceeded(2));
+
+		let mut backend = InMemory::default();
+		backend.commit(vec![Update::Storage(b"\0code".to_vec(), code)]);
+		let mut overlay = OverlayedChanges::default();
+
+		assert!(execute(&backend, &mut overlay, &WasmExecutor::with_max_memory_pages(2), "test", &CallData(vec![])).is_err());
+		overlay.commit_prospective();
+		let mut committed = 0;
+		overlay.for_each_committed(|_, _| committed += 1);
+		assert_eq!(committed, 0);
+
+		assert!(WasmExecutor::with_max_memory_pages(3).call(&mut ext, &code, "test", &CallData(vec![])).is_ok());
+	}
 }
@@ -17,10 +17,11 @@
 //! Rust implementation of Polkadot contracts.
 
 use std::sync::{Arc};
+use std::borrow::Cow;
 use std::collections::HashMap;
 pub use std::result;
 pub use parity_wasm::builder;
-pub use parity_wasm::elements::{ValueType, Module};
+pub use parity_wasm::elements::{ValueType, Module, External, Type};
 pub use parity_wasm::interpreter::{RuntimeValue, UserFunctionDescriptor, UserFunctionExecutor,
 	UserDefinedElements, env_native_module, DummyUserError, ExecutionParams, UserError};
 use parity_wasm::interpreter;
@@ -91,11 +92,12 @@ macro_rules! dispatch {
 		fn execute(&mut self, name: &str, context: $crate::wasm_utils::CallerContext)
 			-> $crate::wasm_utils::result::Result<Option<$crate::wasm_utils::RuntimeValue>, $crate::wasm_utils::Error> {
 			let $objectname = self;
+			$objectname.charge_gas()?;
 			match name {
 				$(
 					stringify!($name) => marshall!(context, $objectname, ( $( $names : $params ),* ) $( -> $returns )* => $body),
 				)*
-				_ => panic!()
+				_ => $objectname.unknown_function(name),
 			}
 		}
 	);
@@ -112,6 +114,33 @@ macro_rules! signatures {
 	);
 }
 
+/// Signatures for the functions a module imports from `env` which are not in `provided`.
+///
+/// These let such a module be instantiated even though the host doesn't implement
+/// all of its imports.
+pub fn missing_signatures(module: &Module, provided: &[UserFunctionDescriptor]) -> Vec<UserFunctionDescriptor> {
+	let types = module.type_section().map(|s| s.types()).unwrap_or(&[]);
+	let is_provided = |name: &str| provided.iter().any(|d| match *d {
+		UserFunctionDescriptor::Static(n, _, _) => n == name,
+		UserFunctionDescriptor::Heap(ref n, _, _) => n == name,
+	});
+
+	module.import_section().map(|s| s.entries()).unwrap_or(&[]).iter()
+		.filter(|entry| entry.module() == "env" && !is_provided(entry.field()))
+		.filter_map(|entry| match *entry.external() {
+			External::Function(index) => match types.get(index as usize) {
+				Some(&Type::Function(ref ty)) => Some(UserFunctionDescriptor::Heap(
+					entry.field().to_owned(),
+					ty.params().to_vec(),
+					ty.return_type(),
+				)),
+				None => None,
+			},
+			_ => None,
+		})
+		.collect()
+}
+
 pub trait IntoUserDefinedElements {
 	fn into_user_defined_elements(&mut self) -> UserDefinedElements<DummyUserError>;
 }
@@ -127,10 +156,11 @@ macro_rules! impl_function_executor {
 		}
 		impl $( $pre ) + $crate::wasm_utils::IntoUserDefinedElements for $structname {
 			fn into_user_defined_elements(&mut self) -> UserDefinedElements<$crate::wasm_utils::DummyUserError> {
+				let functions = self.signatures.clone();
 				$crate::wasm_utils::UserDefinedElements {
 					executor: Some(self),
 					globals: HashMap::new(),	// TODO: provide
-					functions: ::std::borrow::Cow::from(Self::SIGNATURES),
+					functions,
 				}
 			}
 		}
@@ -152,7 +182,7 @@ pub trait AddModuleWithoutFullDependentInstance {
 		&self,
 		name: &str,
 		module: Module,
-		functions: HashMap<&str, &'static [UserFunctionDescriptor]>,
+		functions: HashMap<&str, Cow<'static, [UserFunctionDescriptor]>>,
 	) -> result::Result<Arc<interpreter::ModuleInstance<DummyUserError>>, interpreter::Error<DummyUserError>>;
 
 	fn params_with_external<'a, 'b: 'a>(&'b self, externals_name: &str, externals: &'a mut IntoUserDefinedElements) -> result::Result<ExecutionParams<'a, DummyUserError>, Error>;
@@ -163,7 +193,7 @@ impl AddModuleWithoutFullDependentInstance for interpreter::ProgramInstance<Dumm
 		&self,
 		name: &str,
 		module: Module,
-		functions: HashMap<&str, &'static [UserFunctionDescriptor]>
+		functions: HashMap<&str, Cow<'static, [UserFunctionDescriptor]>>
 	) -> result::Result<Arc<interpreter::ModuleInstance<DummyUserError>>, interpreter::Error<DummyUserError>> {
 		let mut dufe = vec![DummyUserFunctionExecutor; functions.len()];
 		let dufe_refs = dufe.iter_mut().collect::<Vec<_>>();
@@ -175,7 +205,7 @@ impl AddModuleWithoutFullDependentInstance for interpreter::ProgramInstance<Dumm
 						self.module(dep_mod_name).ok_or(DummyUserError)?, UserDefinedElements {
 							executor: Some(dufe),
 							globals: HashMap::new(),
-							functions: ::std::borrow::Cow::from(functions),
+							functions,
 						}
 					)?
 				);
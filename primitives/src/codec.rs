@@ -0,0 +1,156 @@
+// Copyright 2017 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Minimal binary codec for data exchanged between the host and the runtime.
+//!
+//! Hand-written rather than derived so that it has no dependencies beyond
+//! `core` and `Vec`. Integers are fixed-width little-endian; byte vectors
+//! carry a `u32` length prefix.
+//!
+//! This module builds without `std`, so the runtime shares it with the host.
+
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+/// A type which can be encoded to bytes.
+pub trait Encode {
+	/// Append the encoding of `self` to `dest`.
+	fn encode_to(&self, dest: &mut Vec<u8>);
+
+	/// Encode `self` into a new vector.
+	fn encode(&self) -> Vec<u8> {
+		let mut r = Vec::new();
+		self.encode_to(&mut r);
+		r
+	}
+}
+
+/// A type which can be decoded from bytes.
+pub trait Decode: Sized {
+	/// Decode a value from the front of `input`, advancing it past the bytes read.
+	///
+	/// Returns `None` if the input is too short or malformed.
+	fn decode(input: &mut &[u8]) -> Option<Self>;
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+	if input.len() < len {
+		return None;
+	}
+
+	let (head, rest) = input.split_at(len);
+	*input = rest;
+	Some(head)
+}
+
+macro_rules! impl_int {
+	($t: ty, $len: expr) => {
+		impl Encode for $t {
+			fn encode_to(&self, dest: &mut Vec<u8>) {
+				let mut v = *self;
+				for _ in 0..$len {
+					dest.push(v as u8);
+					v >>= 8;
+				}
+			}
+		}
+
+		impl Decode for $t {
+			fn decode(input: &mut &[u8]) -> Option<Self> {
+				take(input, $len).map(|bytes| bytes.iter()
+					.rev()
+					.fold(0, |acc, &b| (acc << 8) | b as $t))
+			}
+		}
+	}
+}
+
+impl_int!(u32, 4);
+impl_int!(u64, 8);
+
+impl Encode for Vec<u8> {
+	fn encode_to(&self, dest: &mut Vec<u8>) {
+		(self.len() as u32).encode_to(dest);
+		dest.extend_from_slice(self);
+	}
+}
+
+impl Decode for Vec<u8> {
+	fn decode(input: &mut &[u8]) -> Option<Self> {
+		let len = u32::decode(input)? as usize;
+		take(input, len).map(|bytes| {
+			let mut r = Vec::with_capacity(len);
+			r.extend_from_slice(bytes);
+			r
+		})
+	}
+}
+
+impl Encode for [u8; 32] {
+	fn encode_to(&self, dest: &mut Vec<u8>) {
+		dest.extend_from_slice(self);
+	}
+}
+
+impl Decode for [u8; 32] {
+	fn decode(input: &mut &[u8]) -> Option<Self> {
+		take(input, 32).map(|bytes| {
+			let mut r = [0u8; 32];
+			r.copy_from_slice(bytes);
+			r
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn round_trip<T: Encode + Decode + PartialEq + ::std::fmt::Debug>(value: T) {
+		let encoded = value.encode();
+		let mut input = &encoded[..];
+		assert_eq!(T::decode(&mut input), Some(value));
+		assert!(input.is_empty());
+	}
+
+	#[test]
+	fn integers_round_trip() {
+		round_trip(0u32);
+		round_trip(0x01020304u32);
+		round_trip(u32::max_value());
+		round_trip(0u64);
+		round_trip(0x0102030405060708u64);
+		round_trip(u64::max_value());
+
+		assert_eq!(0x01020304u32.encode(), vec![4, 3, 2, 1]);
+	}
+
+	#[test]
+	fn bytes_round_trip() {
+		round_trip(Vec::<u8>::new());
+		round_trip(b"hello world".to_vec());
+		round_trip([42u8; 32]);
+
+		assert_eq!(vec![1u8, 2].encode(), vec![2, 0, 0, 0, 1, 2]);
+	}
+
+	#[test]
+	fn short_input_is_rejected() {
+		assert_eq!(u32::decode(&mut &[1u8, 2, 3][..]), None);
+		assert_eq!(<[u8; 32]>::decode(&mut &[0u8; 31][..]), None);
+		assert_eq!(Vec::<u8>::decode(&mut &[3u8, 0, 0, 0, 1, 2][..]), None);
+	}
+}
@@ -0,0 +1,63 @@
+// Copyright 2017 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Fee parameters, as the host hands them to the runtime.
+
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+use codec::{Decode, Encode};
+
+/// Chain fee parameters, stored under the reserved `fees` key.
+///
+/// When the key is absent or malformed the default applies: both fees are zero.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FeeParams {
+	/// Fee charged for every transaction.
+	pub base_fee: u64,
+	/// Fee charged for every byte of transaction data.
+	pub byte_fee: u64,
+}
+
+impl Encode for FeeParams {
+	fn encode_to(&self, dest: &mut Vec<u8>) {
+		self.base_fee.encode_to(dest);
+		self.byte_fee.encode_to(dest);
+	}
+}
+
+impl Decode for FeeParams {
+	fn decode(input: &mut &[u8]) -> Option<Self> {
+		Some(FeeParams {
+			base_fee: u64::decode(input)?,
+			byte_fee: u64::decode(input)?,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn fee_params_round_trip() {
+		let params = FeeParams { base_fee: 10, byte_fee: 3 };
+		let encoded = params.encode();
+		assert_eq!(encoded.len(), 16);
+		assert_eq!(FeeParams::decode(&mut &encoded[..]), Some(params));
+		assert_eq!(FeeParams::decode(&mut &encoded[..15]), None);
+	}
+}
@@ -15,22 +15,36 @@
 // along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
 
 //! Shareable Polkadot types.
+//!
+//! Without the default `std` feature only the codec and the types the runtime
+//! shares with the host are built, so that the runtime can use them too.
 
 #![warn(missing_docs)]
+#![cfg_attr(not(feature = "std"), no_std)]
+#![cfg_attr(not(feature = "std"), feature(alloc))]
 
+#[cfg(feature = "std")]
 extern crate rustc_hex;
+#[cfg(feature = "std")]
 extern crate serde;
+#[cfg(feature = "std")]
 extern crate tiny_keccak;
 
+#[cfg(feature = "std")]
 #[macro_use]
 extern crate crunchy;
+#[cfg(feature = "std")]
 #[macro_use]
 extern crate fixed_hash;
+#[cfg(feature = "std")]
 #[macro_use]
 extern crate serde_derive;
+#[cfg(feature = "std")]
 #[macro_use]
 extern crate uint as uint_crate;
 
+#[cfg(not(feature = "std"))]
+extern crate alloc;
 #[cfg(feature="std")]
 extern crate core;
 #[cfg(test)]
@@ -39,23 +53,37 @@ extern crate polkadot_serializer;
 #[macro_use]
 extern crate pretty_assertions;
 
+#[cfg(feature = "std")]
 mod bytes;
+#[cfg(feature = "std")]
 pub mod block;
+pub mod codec;
+#[cfg(feature = "std")]
 pub mod contract;
+pub mod fees;
+#[cfg(feature = "std")]
 pub mod hash;
+#[cfg(feature = "std")]
 pub mod parachain;
+#[cfg(feature = "std")]
 pub mod uint;
+#[cfg(feature = "std")]
 pub mod validator;
 
 /// Alias to 160-bit hash when used in the context of an account address.
+#[cfg(feature = "std")]
 pub type Address = hash::H160;
 /// Alias to 520-bit hash when used in the context of a signature.
+#[cfg(feature = "std")]
 pub type Signature = hash::H520;
 
+#[cfg(feature = "std")]
 pub use self::hash::{H160, H256};
+#[cfg(feature = "std")]
 pub use self::uint::{U256, U512};
 
 /// A hash function.
+#[cfg(feature = "std")]
 pub fn hash(data: &[u8]) -> hash::H256 {
 	tiny_keccak::keccak256(data).into()
 }
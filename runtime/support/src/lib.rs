@@ -9,6 +9,12 @@ use alloc::vec::Vec;
 
 extern crate pwasm_libc;
 extern crate pwasm_alloc;
+extern crate polkadot_primitives as primitives;
+
+pub use primitives::codec;
+pub use primitives::fees::FeeParams;
+
+use codec::Decode;
 
 #[lang = "panic_fmt"]
 #[no_mangle]
@@ -21,6 +27,16 @@ extern "C" {
 	fn ext_print_num(value: u64);
 	fn ext_set_storage(key_data: *const u8, key_len: i32, value_data: *const u8, value_len: i32);
 	fn ext_get_allocated_storage(key_data: *const u8, key_len: i32, written_out: *mut i32) -> *mut u8;
+	fn ext_storage_len(key_data: *const u8, key_len: i32) -> u32;
+	fn ext_get_storage_into(key_data: *const u8, key_len: i32, value_data: *mut u8, value_len: i32) -> u32;
+	fn ext_hash(algo: u32, data: *const u8, len: u32, out: *mut u8) -> u32;
+	fn ext_fee_params(out: *mut u8);
+	fn ext_code_hash(out: *mut u8);
+	fn ext_genesis_hash(out: *mut u8) -> u32;
+	fn ext_storage_quota_left() -> u64;
+	fn ext_timestamp() -> u64;
+	fn ext_random(out: *mut u8, len: u32);
+	fn ext_emit_event(topic_data: *const u8, topic_len: i32, data: *const u8, data_len: i32);
 }
 
 pub fn storage(key: &[u8]) -> Vec<u8> {
@@ -31,6 +47,21 @@ pub fn storage(key: &[u8]) -> Vec<u8> {
 	}
 }
 
+pub fn storage_len(key: &[u8]) -> Option<usize> {
+	match unsafe { ext_storage_len(&key[0], key.len() as i32) } {
+		::core::u32::MAX => None,
+		len => Some(len as usize),
+	}
+}
+
+/// Read as much of the value of `key` as fits into `value_out`; `None` if absent.
+pub fn read_storage(key: &[u8], value_out: &mut [u8]) -> Option<usize> {
+	match unsafe { ext_get_storage_into(&key[0], key.len() as i32, value_out.as_mut_ptr(), value_out.len() as i32) } {
+		::core::u32::MAX => None,
+		len => Some(len as usize),
+	}
+}
+
 pub fn set_storage(key: &[u8], value: &[u8]) {
 	unsafe {
 		ext_set_storage(
@@ -48,6 +79,22 @@ pub fn set_code(new: &[u8]) {
 	set_storage(b"\0code", new)
 }
 
+pub fn genesis_hash() -> Option<[u8; 32]> {
+	let mut r = [0u8; 32];
+	match unsafe { ext_genesis_hash(r.as_mut_ptr()) } {
+		0 => None,
+		_ => Some(r),
+	}
+}
+
+pub fn code_hash() -> [u8; 32] {
+	let mut r = [0u8; 32];
+	unsafe {
+		ext_code_hash(r.as_mut_ptr());
+	}
+	r
+}
+
 fn value_vec(mut value: usize, initial: Vec<u8>) -> Vec<u8> {
 	let mut acc = initial;
 	while value > 0 {
@@ -83,6 +130,57 @@ pub fn set_validators(validators: &[&[u8]]) {
 	validators.iter().enumerate().for_each(|(v, i)| set_validator(v, i));
 }
 
+fn hash_into(algo: u32, data: &[u8], out: &mut [u8]) {
+	unsafe {
+		ext_hash(algo, data.as_ptr(), data.len() as u32, out.as_mut_ptr());
+	}
+}
+
+pub fn blake2_128(data: &[u8]) -> [u8; 16] {
+	let mut r = [0u8; 16];
+	hash_into(0, data, &mut r);
+	r
+}
+
+pub fn blake2_256(data: &[u8]) -> [u8; 32] {
+	let mut r = [0u8; 32];
+	hash_into(1, data, &mut r);
+	r
+}
+
+pub fn keccak256(data: &[u8]) -> [u8; 32] {
+	let mut r = [0u8; 32];
+	hash_into(2, data, &mut r);
+	r
+}
+
+pub fn storage_quota_left() -> u64 {
+	unsafe { ext_storage_quota_left() }
+}
+
+pub fn timestamp() -> u64 {
+	unsafe { ext_timestamp() }
+}
+
+/// Fill `out` with deterministic random bytes, distinct on every call.
+pub fn random(out: &mut [u8]) {
+	unsafe { ext_random(out.as_mut_ptr(), out.len() as u32); }
+}
+
+pub fn emit_event(topic: &[u8], data: &[u8]) {
+	unsafe {
+		ext_emit_event(topic.as_ptr(), topic.len() as i32, data.as_ptr(), data.len() as i32);
+	}
+}
+
+pub fn fee_params() -> FeeParams {
+	let mut r = [0u8; 16];
+	unsafe {
+		ext_fee_params(r.as_mut_ptr());
+	}
+	FeeParams::decode(&mut &r[..]).expect("16 bytes always hold two u64s; qed")
+}
+
 pub trait Printable {
 	fn print(self);
 }
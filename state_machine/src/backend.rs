@@ -17,10 +17,17 @@
 //! State machine backends. These manage the code and storage of contracts.
 
 use std::{error, fmt};
+use std::cell::{Cell, OnceCell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
 use primitives::hash::H256;
-use triehash::sec_trie_root;
+use ethcore_bigint::hash::H256 as TrieRoot;
+use hashdb::HashDB;
+use memorydb::MemoryDB;
+use patricia_trie::{Recorder, Trie, TrieDB, TrieDBMut, TrieError, TrieMut};
+use triehash::trie_root;
 
-use super::{Update, MemoryState};
+use super::Update;
 
 /// Output of a commit.
 pub struct Committed {
@@ -37,11 +44,77 @@ pub trait Backend {
 	/// Get keyed storage associated with specific address.
 	fn storage(&self, key: &[u8]) -> Result<&[u8], Self::Error>;
 
+	/// Get the values stored under each of `keys`, in order.
+	///
+	/// Remote backends should override this to fetch all keys in one round-trip.
+	fn storage_batch(&self, keys: &[Vec<u8>]) -> Result<Vec<&[u8]>, Self::Error> {
+		keys.iter().map(|key| self.storage(key)).collect()
+	}
+
+	/// Get the length of the value stored under `key`, or `None` if absent.
+	///
+	/// Backends which keep value lengths as metadata can avoid loading the value.
+	fn storage_len(&self, key: &[u8]) -> Result<Option<usize>, Self::Error> {
+		self.storage(key).map(|v| if v.is_empty() { None } else { Some(v.len()) })
+	}
+
+	/// Get all key/value pairs held by the backend.
+	fn pairs(&self) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Self::Error>;
+
+	/// Get all keys starting with the given prefix, in byte order.
+	fn keys_with_prefix(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, Self::Error> {
+		let mut keys: Vec<_> = self.pairs()?.into_iter()
+			.map(|(key, _)| key)
+			.filter(|key| key.starts_with(prefix))
+			.collect();
+		keys.sort();
+		Ok(keys)
+	}
+
+	/// Get the smallest key holding a value which is strictly greater than `key`.
+	///
+	/// Backends which can seek through their keys should override this to
+	/// avoid listing them all.
+	fn next_key(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+		Ok(self.keys_with_prefix(&[])?.into_iter().find(|k| &k[..] > key))
+	}
+
+	/// Root of the trie holding all key/value pairs of the backend.
+	fn storage_root(&self) -> Result<[u8; 32], Self::Error> {
+		self.pairs().map(storage_root)
+	}
+
+	/// Get the value stored under `key` in the child storage under `storage_key`.
+	///
+	/// Backends without child storage hold no child entries. Backends wrapping
+	/// another must forward this and `child_pairs`, or the child storage of the
+	/// wrapped backend is hidden.
+	fn child_storage(&self, _storage_key: &[u8], _key: &[u8]) -> Result<&[u8], Self::Error> {
+		Ok(&[])
+	}
+
+	/// Get all key/value pairs of the child storage under `storage_key`.
+	fn child_pairs(&self, _storage_key: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Self::Error> {
+		Ok(Vec::new())
+	}
+
+	/// Identity stamp of this backend, if any.
+	fn stamp(&self) -> Option<u64> { None }
+
 	/// Commit updates to the backend and get new state.
 	fn commit<I>(&mut self, changes: I) -> Committed
 		where I: IntoIterator<Item=Update>;
 }
 
+/// A backend which can persist changes directly.
+pub trait StateDb: Backend {
+	/// Write the given changes to the backend; a value of `None` deletes the key.
+	fn commit(&mut self, changes: &[(Vec<u8>, Option<Vec<u8>>)]) -> Result<(), Self::Error>;
+
+	/// Write the given changes to the child storage under `storage_key`, as in `commit`.
+	fn commit_child(&mut self, storage_key: &[u8], changes: &[(Vec<u8>, Option<Vec<u8>>)]) -> Result<(), Self::Error>;
+}
+
 /// Error impossible.
 // TODO: use `!` type when stabilized.
 #[derive(Debug)]
@@ -61,27 +134,80 @@ impl error::Error for Void {
 /// tests.
 #[derive(Default)]
 pub struct InMemory {
-	inner: MemoryState, // keeps all the state in memory.
+	inner: HashMap<Vec<u8>, Vec<u8>>, // keeps all the state in memory.
+	children: HashMap<Vec<u8>, HashMap<Vec<u8>, Vec<u8>>>,
+	stamp: Option<u64>,
+}
+
+impl InMemory {
+	/// Create a new, empty backend carrying the given identity stamp.
+	pub fn with_stamp(stamp: u64) -> Self {
+		InMemory {
+			inner: HashMap::new(),
+			children: HashMap::new(),
+			stamp: Some(stamp),
+		}
+	}
+
+	/// Apply the given updates directly, removing keys set to an empty value.
+	pub fn update<I>(&mut self, changes: I) where I: IntoIterator<Item=Update> {
+		for update in changes {
+			match update {
+				Update::Storage(key, val) => {
+					if val.is_empty() {
+						self.inner.remove(&key);
+					} else {
+						self.inner.insert(key, val);
+					}
+				}
+			}
+		}
+	}
+}
+
+impl From<HashMap<Vec<u8>, Vec<u8>>> for InMemory {
+	fn from(inner: HashMap<Vec<u8>, Vec<u8>>) -> Self {
+		InMemory {
+			inner,
+			children: HashMap::new(),
+			stamp: None,
+		}
+	}
 }
 
 impl Backend for InMemory {
 	type Error = Void;
 
 	fn storage(&self, key: &[u8]) -> Result<&[u8], Void> {
-		Ok(self.inner.storage(key).unwrap_or(&[]))
+		Ok(self.inner.get(key).map_or(&[][..], |v| &v[..]))
+	}
+
+	fn pairs(&self) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Void> {
+		Ok(self.inner.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+	}
+
+	fn child_storage(&self, storage_key: &[u8], key: &[u8]) -> Result<&[u8], Void> {
+		Ok(self.children.get(storage_key).and_then(|child| child.get(key)).map_or(&[][..], |v| &v[..]))
+	}
+
+	fn child_pairs(&self, storage_key: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Void> {
+		Ok(self.children.get(storage_key)
+			.map_or_else(Vec::new, |child| child.iter().map(|(k, v)| (k.clone(), v.clone())).collect()))
+	}
+
+	fn stamp(&self) -> Option<u64> {
+		self.stamp
 	}
 
 	fn commit<I>(&mut self, changes: I) -> Committed
 		where I: IntoIterator<Item=Update>
 	{
-		self.inner.update(changes);
+		self.update(changes);
 
-		// fully recalculate trie roots.
-		let storage_tree_root = H256(sec_trie_root(
-			self.inner.storage.iter()
-				.map(|(k, v)| (k.to_vec(), v.clone()))
-				.collect()
-			).0);
+		// fully recalculate trie roots, the same way as `storage_root`.
+		let storage_tree_root = H256(storage_root(
+			self.inner.iter().map(|(k, v)| (k.to_vec(), v.clone()))
+		));
 
 		Committed {
 			storage_tree_root,
@@ -89,4 +215,1241 @@ impl Backend for InMemory {
 	}
 }
 
-// TODO: DB-based backend
+impl StateDb for InMemory {
+	fn commit(&mut self, changes: &[(Vec<u8>, Option<Vec<u8>>)]) -> Result<(), Void> {
+		self.update(changes.iter().map(|&(ref key, ref value)| {
+			Update::Storage(key.clone(), value.clone().unwrap_or_default())
+		}));
+		Ok(())
+	}
+
+	fn commit_child(&mut self, storage_key: &[u8], changes: &[(Vec<u8>, Option<Vec<u8>>)]) -> Result<(), Void> {
+		{
+			let child = self.children.entry(storage_key.to_vec()).or_insert_with(HashMap::new);
+			for &(ref key, ref value) in changes {
+				match *value {
+					Some(ref value) if !value.is_empty() => { child.insert(key.clone(), value.clone()); }
+					_ => { child.remove(key); }
+				}
+			}
+		}
+		if self.children.get(storage_key).map_or(false, HashMap::is_empty) {
+			self.children.remove(storage_key);
+		}
+		Ok(())
+	}
+}
+
+// number of arena segments of a `ReadCache`; segment `i` holds `2^i` values.
+const READ_CACHE_SEGMENTS: usize = 32;
+
+// append-only cache of values read, filled through a shared reference.
+//
+// Values live in arena segments which never move once allocated, so reads can
+// hand out references into them as the cache grows; `index` maps each key to
+// its slot. Owners drop the whole cache when their state changes.
+#[derive(Default)]
+struct ReadCache {
+	index: RefCell<HashMap<Vec<u8>, usize>>,
+	segments: [OnceCell<Box<[OnceCell<Vec<u8>>]>>; READ_CACHE_SEGMENTS],
+}
+
+impl ReadCache {
+	// the arena slot of the `n`th value remembered.
+	fn slot(&self, n: usize) -> &OnceCell<Vec<u8>> {
+		let segment = 8 * ::std::mem::size_of::<usize>() - 1 - (n + 1).leading_zeros() as usize;
+		let offset = n + 1 - (1 << segment);
+		let slots = self.segments[segment].get_or_init(|| {
+			(0..1usize << segment).map(|_| OnceCell::new()).collect::<Vec<_>>().into_boxed_slice()
+		});
+		&slots[offset]
+	}
+
+	fn get(&self, key: &[u8]) -> Option<&[u8]> {
+		let n = *self.index.borrow().get(key)?;
+		self.slot(n).get().map(|value| &value[..])
+	}
+
+	fn remember(&self, key: Vec<u8>, value: Vec<u8>) -> &[u8] {
+		if let Some(known) = self.get(&key) {
+			return known;
+		}
+		let n = self.index.borrow().len();
+		let slot = self.slot(n);
+		let _ = slot.set(value);
+		self.index.borrow_mut().insert(key, n);
+		slot.get().expect("set just above; qed")
+	}
+}
+
+/// How deep reads have traversed a trie, in nodes fetched from the database.
+///
+/// A maximum far above the average suggests keys are unbalancing the trie.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReadDepths {
+	/// Number of reads which traversed the trie.
+	pub reads: u64,
+	/// Deepest traversal of any read.
+	pub max: u32,
+	/// Sum of the depths of all reads.
+	pub total: u64,
+}
+
+impl ReadDepths {
+	/// Average depth of a read, zero if there were none.
+	pub fn average(&self) -> f64 {
+		if self.reads == 0 { 0.0 } else { self.total as f64 / self.reads as f64 }
+	}
+
+	fn record(&mut self, depth: u32) {
+		self.reads += 1;
+		self.max = ::std::cmp::max(self.max, depth);
+		self.total += depth as u64;
+	}
+}
+
+/// A backend reading from a patricia trie held in a `HashDB`.
+///
+/// Values are looked up through the trie on first read and kept until the
+/// next commit. Missing trie nodes are reported as errors.
+pub struct TrieBackend<D> {
+	db: D,
+	root: TrieRoot,
+	reads: ReadCache,
+	depths: Cell<ReadDepths>,
+}
+
+impl<D: HashDB> TrieBackend<D> {
+	/// Create a backend over the trie with the given root in `db`.
+	pub fn new(db: D, root: [u8; 32]) -> Self {
+		TrieBackend {
+			db,
+			root: TrieRoot::from(root),
+			reads: ReadCache::default(),
+			depths: Cell::new(ReadDepths::default()),
+		}
+	}
+
+	/// Root of the trie the backend reads from.
+	pub fn root(&self) -> [u8; 32] {
+		self.root.0
+	}
+
+	/// Depths of the reads made so far; reads served from the cache aren't counted.
+	pub fn read_depths(&self) -> ReadDepths {
+		self.depths.get()
+	}
+
+	// look `key` up through `trie`, a view of this backend's trie.
+	fn read_through(&self, trie: &TrieDB, key: &[u8]) -> Result<&[u8], Box<TrieError>> {
+		let mut recorder = Recorder::new();
+		let value = trie.get_with(key, &mut recorder)?;
+
+		let mut depths = self.depths.get();
+		depths.record(recorder.drain().len() as u32);
+		self.depths.set(depths);
+
+		match value {
+			Some(value) => Ok(self.reads.remember(key.to_vec(), value.to_vec())),
+			None => Ok(&[]),
+		}
+	}
+}
+
+impl<D: HashDB> Backend for TrieBackend<D> {
+	type Error = Box<TrieError>;
+
+	fn storage(&self, key: &[u8]) -> Result<&[u8], Self::Error> {
+		if let Some(value) = self.reads.get(key) {
+			return Ok(value);
+		}
+
+		let trie = TrieDB::new(&self.db, &self.root)?;
+		self.read_through(&trie, key)
+	}
+
+	/// Keys not yet cached are all looked up through one view of the trie.
+	fn storage_batch(&self, keys: &[Vec<u8>]) -> Result<Vec<&[u8]>, Self::Error> {
+		let mut trie = None;
+		let mut values = Vec::with_capacity(keys.len());
+		for key in keys {
+			if let Some(value) = self.reads.get(key) {
+				values.push(value);
+				continue;
+			}
+			if trie.is_none() {
+				trie = Some(TrieDB::new(&self.db, &self.root)?);
+			}
+			values.push(self.read_through(trie.as_ref().expect("set just above; qed"), key)?);
+		}
+		Ok(values)
+	}
+
+	fn pairs(&self) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Self::Error> {
+		let trie = TrieDB::new(&self.db, &self.root)?;
+		let pairs: Result<Vec<_>, _> = trie.iter()?
+			.map(|item| item.map(|(key, value)| (key, value.to_vec())))
+			.collect();
+		pairs
+	}
+
+	fn next_key(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+		let trie = TrieDB::new(&self.db, &self.root)?;
+		let mut iter = trie.iter()?;
+		iter.seek(key)?;
+
+		// the seek stops at `key` itself if it is present.
+		for item in iter {
+			let (next, _) = item?;
+			if &next[..] > key {
+				return Ok(Some(next));
+			}
+		}
+		Ok(None)
+	}
+
+	fn storage_root(&self) -> Result<[u8; 32], Self::Error> {
+		Ok(self.root.0)
+	}
+
+	/// # Panics
+	///
+	/// Panics if the database is missing trie nodes the changes touch.
+	fn commit<I>(&mut self, changes: I) -> Committed
+		where I: IntoIterator<Item=Update>
+	{
+		{
+			let mut trie = TrieDBMut::from_existing(&mut self.db, &mut self.root)
+				.expect("committing on top of an incomplete trie database");
+			for update in changes {
+				let result = match update {
+					Update::Storage(key, val) => if val.is_empty() {
+						trie.remove(&key)
+					} else {
+						trie.insert(&key, &val)
+					},
+				};
+				result.expect("committing on top of an incomplete trie database");
+			}
+		}
+		self.reads = ReadCache::default();
+
+		Committed {
+			storage_tree_root: H256(self.root.0),
+		}
+	}
+}
+
+/// A backend built from a function giving the value of a key and one listing
+/// all keys, in byte order.
+///
+/// Key enumeration uses the key list directly; everything else materializes
+/// the full state on first use.
+pub struct LazyEnumerable<F, G> {
+	value: F,
+	keys: G,
+	state: OnceCell<InMemory>,
+}
+
+impl<F, G> LazyEnumerable<F, G>
+	where F: Fn(&[u8]) -> Option<Vec<u8>>, G: Fn() -> Vec<Vec<u8>>
+{
+	/// Create a backend from the given value and key list functions.
+	pub fn new(value: F, keys: G) -> Self {
+		LazyEnumerable {
+			value,
+			keys,
+			state: OnceCell::new(),
+		}
+	}
+
+	fn materialized(&self) -> &InMemory {
+		self.state.get_or_init(|| {
+			let mut state = InMemory::default();
+			for key in (self.keys)() {
+				if let Some(value) = (self.value)(&key) {
+					state.inner.insert(key, value);
+				}
+			}
+			state
+		})
+	}
+}
+
+impl<F, G> Backend for LazyEnumerable<F, G>
+	where F: Fn(&[u8]) -> Option<Vec<u8>>, G: Fn() -> Vec<Vec<u8>>
+{
+	type Error = Void;
+
+	fn storage(&self, key: &[u8]) -> Result<&[u8], Void> {
+		self.materialized().storage(key)
+	}
+
+	fn pairs(&self) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Void> {
+		self.materialized().pairs()
+	}
+
+	fn keys_with_prefix(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, Void> {
+		match self.state.get() {
+			Some(state) => state.keys_with_prefix(prefix),
+			None => Ok((self.keys)().into_iter().filter(|key| key.starts_with(prefix)).collect()),
+		}
+	}
+
+	fn child_storage(&self, storage_key: &[u8], key: &[u8]) -> Result<&[u8], Void> {
+		self.materialized().child_storage(storage_key, key)
+	}
+
+	fn child_pairs(&self, storage_key: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Void> {
+		self.materialized().child_pairs(storage_key)
+	}
+
+	fn commit<I>(&mut self, changes: I) -> Committed
+		where I: IntoIterator<Item=Update>
+	{
+		self.materialized();
+		self.state.get_mut().expect("materialized above; qed").commit(changes)
+	}
+}
+
+/// Wraps a fallible backend, retrying failed reads.
+///
+/// Each read is attempted up to `attempts` times, as long as `should_retry`
+/// accepts the error from the previous attempt.
+pub struct Retrying<B, F> {
+	inner: B,
+	attempts: usize,
+	should_retry: F,
+}
+
+impl<B: Backend, F: Fn(&B::Error) -> bool> Retrying<B, F> {
+	/// Wrap a backend with the given retry policy.
+	pub fn new(inner: B, attempts: usize, should_retry: F) -> Self {
+		Retrying {
+			inner,
+			attempts,
+			should_retry,
+		}
+	}
+
+	/// Unwrap the inner backend.
+	pub fn into_inner(self) -> B {
+		self.inner
+	}
+
+	fn retry<T, G>(&self, op: G) -> Result<T, B::Error>
+		where G: Fn() -> Result<T, B::Error>
+	{
+		let mut attempt = 1;
+		loop {
+			match op() {
+				Err(ref e) if attempt < self.attempts && (self.should_retry)(e) => attempt += 1,
+				result => return result,
+			}
+		}
+	}
+}
+
+impl<B: Backend, F: Fn(&B::Error) -> bool> Backend for Retrying<B, F> {
+	type Error = B::Error;
+
+	fn storage(&self, key: &[u8]) -> Result<&[u8], Self::Error> {
+		self.retry(|| self.inner.storage(key))
+	}
+
+	fn storage_len(&self, key: &[u8]) -> Result<Option<usize>, Self::Error> {
+		self.retry(|| self.inner.storage_len(key))
+	}
+
+	fn pairs(&self) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Self::Error> {
+		self.retry(|| self.inner.pairs())
+	}
+
+	fn child_storage(&self, storage_key: &[u8], key: &[u8]) -> Result<&[u8], Self::Error> {
+		self.retry(|| self.inner.child_storage(storage_key, key))
+	}
+
+	fn child_pairs(&self, storage_key: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Self::Error> {
+		self.retry(|| self.inner.child_pairs(storage_key))
+	}
+
+	fn stamp(&self) -> Option<u64> {
+		self.inner.stamp()
+	}
+
+	fn commit<I>(&mut self, changes: I) -> Committed
+		where I: IntoIterator<Item=Update>
+	{
+		self.inner.commit(changes)
+	}
+}
+
+/// Wraps a backend, recording the keys read from it in order.
+///
+/// Useful to check that replaying a call reads the same keys in the same order.
+pub struct RecordOrder<B> {
+	inner: B,
+	reads: RefCell<Vec<Vec<u8>>>,
+}
+
+impl<B: Backend> RecordOrder<B> {
+	/// Wrap a backend, starting with an empty recording.
+	pub fn new(inner: B) -> Self {
+		RecordOrder {
+			inner,
+			reads: RefCell::new(Vec::new()),
+		}
+	}
+
+	/// The keys read so far, in order.
+	pub fn into_recording(self) -> Vec<Vec<u8>> {
+		self.reads.into_inner()
+	}
+}
+
+impl<B: Backend> Backend for RecordOrder<B> {
+	type Error = B::Error;
+
+	fn storage(&self, key: &[u8]) -> Result<&[u8], Self::Error> {
+		self.reads.borrow_mut().push(key.to_vec());
+		self.inner.storage(key)
+	}
+
+	fn storage_len(&self, key: &[u8]) -> Result<Option<usize>, Self::Error> {
+		self.reads.borrow_mut().push(key.to_vec());
+		self.inner.storage_len(key)
+	}
+
+	fn pairs(&self) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Self::Error> {
+		self.inner.pairs()
+	}
+
+	fn child_storage(&self, storage_key: &[u8], key: &[u8]) -> Result<&[u8], Self::Error> {
+		self.inner.child_storage(storage_key, key)
+	}
+
+	fn child_pairs(&self, storage_key: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Self::Error> {
+		self.inner.child_pairs(storage_key)
+	}
+
+	fn stamp(&self) -> Option<u64> {
+		self.inner.stamp()
+	}
+
+	fn commit<I>(&mut self, changes: I) -> Committed
+		where I: IntoIterator<Item=Update>
+	{
+		self.inner.commit(changes)
+	}
+}
+
+/// A backend layering changes of its own over a shared, immutable base.
+///
+/// Forks share the base and start from the changes of the view they were
+/// forked from, after which each one changes independently.
+pub struct CopyOnWrite<B> {
+	base: Arc<B>,
+	changes: HashMap<Vec<u8>, Option<Vec<u8>>>, // `None` records a deletion.
+}
+
+impl<B: Backend> CopyOnWrite<B> {
+	/// Create a view over the given base, with no changes of its own.
+	pub fn new(base: Arc<B>) -> Self {
+		CopyOnWrite {
+			base,
+			changes: HashMap::new(),
+		}
+	}
+
+	/// Create an independent view starting from the current state of this one.
+	pub fn fork(&self) -> Self {
+		CopyOnWrite {
+			base: self.base.clone(),
+			changes: self.changes.clone(),
+		}
+	}
+}
+
+impl<B: Backend> Backend for CopyOnWrite<B> {
+	type Error = B::Error;
+
+	fn storage(&self, key: &[u8]) -> Result<&[u8], Self::Error> {
+		match self.changes.get(key) {
+			Some(&Some(ref value)) => Ok(value),
+			Some(&None) => Ok(&[]),
+			None => self.base.storage(key),
+		}
+	}
+
+	fn pairs(&self) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Self::Error> {
+		let mut pairs: HashMap<_, _> = self.base.pairs()?.into_iter().collect();
+		for (key, value) in &self.changes {
+			match *value {
+				Some(ref value) => pairs.insert(key.clone(), value.clone()),
+				None => pairs.remove(key),
+			};
+		}
+		Ok(pairs.into_iter().collect())
+	}
+
+	fn child_storage(&self, storage_key: &[u8], key: &[u8]) -> Result<&[u8], Self::Error> {
+		self.base.child_storage(storage_key, key)
+	}
+
+	fn child_pairs(&self, storage_key: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Self::Error> {
+		self.base.child_pairs(storage_key)
+	}
+
+	/// # Panics
+	///
+	/// Panics if the base fails to list its pairs for the new root.
+	fn commit<I>(&mut self, changes: I) -> Committed
+		where I: IntoIterator<Item=Update>
+	{
+		for update in changes {
+			match update {
+				Update::Storage(key, val) => {
+					let val = if val.is_empty() { None } else { Some(val) };
+					self.changes.insert(key, val);
+				}
+			}
+		}
+
+		let root = self.storage_root().unwrap_or_else(|e| panic!("base of copy-on-write backend failed: {}", e));
+		Committed {
+			storage_tree_root: H256(root),
+		}
+	}
+}
+
+/// Wraps a backend, caching reads of reserved keys for as long as its root stays the same.
+///
+/// Reserved keys such as `\0validator_count` rarely change but are read every
+/// block. Moving the cache onto the next block's backend with `rebase` keeps
+/// the cached values if the root is unchanged and drops them otherwise.
+pub struct ReservedCache<B> {
+	inner: B,
+	// root the cached values were read at; unknown after a commit.
+	root: Option<[u8; 32]>,
+	reserved_prefix: u8,
+	values: ReadCache,
+}
+
+impl<B: Backend> ReservedCache<B> {
+	/// Wrap a backend, caching keys starting with `reserved_prefix`.
+	pub fn new(inner: B, reserved_prefix: u8) -> Result<Self, B::Error> {
+		Ok(ReservedCache {
+			root: Some(inner.storage_root()?),
+			inner,
+			reserved_prefix,
+			values: ReadCache::default(),
+		})
+	}
+
+	/// Move the cache onto another backend, keeping the cached values if it has the same root.
+	pub fn rebase(self, inner: B) -> Result<Self, B::Error> {
+		let root = inner.storage_root()?;
+		let values = if self.root == Some(root) { self.values } else { ReadCache::default() };
+		Ok(ReservedCache {
+			inner,
+			root: Some(root),
+			reserved_prefix: self.reserved_prefix,
+			values,
+		})
+	}
+
+	/// Unwrap the backend, dropping the cache.
+	pub fn into_inner(self) -> B {
+		self.inner
+	}
+}
+
+impl<B: Backend> Backend for ReservedCache<B> {
+	type Error = B::Error;
+
+	fn storage(&self, key: &[u8]) -> Result<&[u8], Self::Error> {
+		if key.first() != Some(&self.reserved_prefix) {
+			return self.inner.storage(key);
+		}
+		if let Some(value) = self.values.get(key) {
+			return Ok(value);
+		}
+		let value = self.inner.storage(key)?.to_vec();
+		Ok(self.values.remember(key.to_vec(), value))
+	}
+
+	fn pairs(&self) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Self::Error> {
+		self.inner.pairs()
+	}
+
+	fn storage_root(&self) -> Result<[u8; 32], Self::Error> {
+		self.inner.storage_root()
+	}
+
+	fn child_storage(&self, storage_key: &[u8], key: &[u8]) -> Result<&[u8], Self::Error> {
+		self.inner.child_storage(storage_key, key)
+	}
+
+	fn child_pairs(&self, storage_key: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Self::Error> {
+		self.inner.child_pairs(storage_key)
+	}
+
+	fn stamp(&self) -> Option<u64> {
+		self.inner.stamp()
+	}
+
+	fn commit<I>(&mut self, changes: I) -> Committed
+		where I: IntoIterator<Item=Update>
+	{
+		self.root = None;
+		self.values = ReadCache::default();
+		self.inner.commit(changes)
+	}
+}
+
+/// A remote backend whose values carry the number of the block they were read at.
+pub trait BlockTagged: Backend {
+	/// Get the value stored under `key` along with the block it was read at.
+	fn storage_tagged(&self, key: &[u8]) -> Result<(&[u8], u64), Self::Error>;
+}
+
+/// Failure to read fresh enough state from a remote backend.
+#[derive(Debug)]
+pub enum StalenessError<E> {
+	/// The remote backend failed.
+	Backend(E),
+	/// The value of `key` was read at block `read_at`, too long before the head.
+	StaleRead {
+		/// Key whose value was stale.
+		key: Vec<u8>,
+		/// Block the value was read at.
+		read_at: u64,
+	},
+}
+
+impl<E: fmt::Display> fmt::Display for StalenessError<E> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			StalenessError::Backend(ref e) => write!(f, "Remote backend error: {}", e),
+			StalenessError::StaleRead { ref key, read_at } => write!(f, "Stale read of {:?} at block {}", key, read_at),
+		}
+	}
+}
+
+/// Wraps a remote backend, rejecting values read more than `max_staleness`
+/// blocks before `head`.
+///
+/// Only point reads carry block numbers, so only they are checked; `pairs`
+/// and child storage are passed through as they are.
+pub struct BoundedStaleness<B> {
+	inner: B,
+	head: u64,
+	max_staleness: u64,
+}
+
+impl<B: BlockTagged> BoundedStaleness<B> {
+	/// Wrap a remote backend, given the current head and staleness bound in blocks.
+	pub fn new(inner: B, head: u64, max_staleness: u64) -> Self {
+		BoundedStaleness {
+			inner,
+			head,
+			max_staleness,
+		}
+	}
+
+	/// Move the head reads are checked against.
+	pub fn set_head(&mut self, head: u64) {
+		self.head = head;
+	}
+}
+
+impl<B: BlockTagged> Backend for BoundedStaleness<B> {
+	type Error = StalenessError<B::Error>;
+
+	fn storage(&self, key: &[u8]) -> Result<&[u8], Self::Error> {
+		let (value, read_at) = self.inner.storage_tagged(key).map_err(StalenessError::Backend)?;
+		if self.head.saturating_sub(read_at) > self.max_staleness {
+			return Err(StalenessError::StaleRead { key: key.to_vec(), read_at });
+		}
+		Ok(value)
+	}
+
+	fn pairs(&self) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Self::Error> {
+		self.inner.pairs().map_err(StalenessError::Backend)
+	}
+
+	fn child_storage(&self, storage_key: &[u8], key: &[u8]) -> Result<&[u8], Self::Error> {
+		self.inner.child_storage(storage_key, key).map_err(StalenessError::Backend)
+	}
+
+	fn child_pairs(&self, storage_key: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Self::Error> {
+		self.inner.child_pairs(storage_key).map_err(StalenessError::Backend)
+	}
+
+	fn stamp(&self) -> Option<u64> {
+		self.inner.stamp()
+	}
+
+	fn commit<I>(&mut self, changes: I) -> Committed
+		where I: IntoIterator<Item=Update>
+	{
+		self.inner.commit(changes)
+	}
+}
+
+/// A key whose value differs between two backends, along with its value in
+/// each of them (`None` where absent).
+pub type Difference = (Vec<u8>, Option<Vec<u8>>, Option<Vec<u8>>);
+
+/// Failure to enumerate one of the backends being compared.
+#[derive(Debug)]
+pub enum DiffError<A, B> {
+	/// The first backend failed.
+	Left(A),
+	/// The second backend failed.
+	Right(B),
+}
+
+impl<A: fmt::Display, B: fmt::Display> fmt::Display for DiffError<A, B> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			DiffError::Left(ref e) => write!(f, "First backend error: {}", e),
+			DiffError::Right(ref e) => write!(f, "Second backend error: {}", e),
+		}
+	}
+}
+
+/// Root of the trie holding the given key/value pairs, skipping empty values.
+///
+/// An empty set of pairs gives the root of the empty trie.
+pub fn storage_root<I>(pairs: I) -> [u8; 32]
+	where I: IntoIterator<Item=(Vec<u8>, Vec<u8>)>
+{
+	let mut pairs: Vec<_> = pairs.into_iter().filter(|&(_, ref value)| !value.is_empty()).collect();
+	pairs.sort();
+	trie_root(pairs).0
+}
+
+/// Enumerate all keys whose values differ between two backends, in key order.
+pub fn diff<A: Backend, B: Backend>(a: &A, b: &B) -> Result<Vec<Difference>, DiffError<A::Error, B::Error>> {
+	let left = a.pairs().map_err(DiffError::Left)?;
+	let mut right: BTreeMap<_, _> = b.pairs().map_err(DiffError::Right)?.into_iter().collect();
+
+	let mut diffs = Vec::new();
+	for (key, value) in left {
+		match right.remove(&key) {
+			Some(ref other) if other == &value => {}
+			other => diffs.push((key, Some(value), other)),
+		}
+	}
+	diffs.extend(right.into_iter().map(|(key, value)| (key, None, Some(value))));
+	diffs.sort_by(|x, y| x.0.cmp(&y.0));
+
+	Ok(diffs)
+}
+
+/// The fewest updates turning the state of `from` into that of `to`, in key order.
+///
+/// Keys absent from `to` are deleted by setting them to an empty value.
+pub fn minimal_updates<A: Backend, B: Backend>(from: &A, to: &B) -> Result<Vec<Update>, DiffError<A::Error, B::Error>> {
+	Ok(diff(from, to)?.into_iter()
+		.map(|(key, _, value)| Update::Storage(key, value.unwrap_or_default()))
+		.collect())
+}
+
+/// Read the values of `keys` from `backend`, with the trie nodes proving them
+/// against its root.
+///
+/// The proof holds only the nodes on the paths to the keys, each once.
+pub fn prove_read<D: HashDB>(backend: &TrieBackend<D>, keys: &[Vec<u8>]) -> Result<(Vec<Option<Vec<u8>>>, Vec<Vec<u8>>), Box<TrieError>> {
+	let trie = TrieDB::new(&backend.db, &backend.root)?;
+	let mut recorder = Recorder::new();
+	let mut values = Vec::with_capacity(keys.len());
+	for key in keys {
+		values.push(trie.get_with(key, &mut recorder)?.map(|value| value.to_vec()));
+	}
+
+	let mut seen = HashSet::new();
+	let proof = recorder.drain().into_iter()
+		.filter(|record| seen.insert(record.hash))
+		.map(|record| record.data)
+		.collect();
+	Ok((values, proof))
+}
+
+/// Check the value of `key` under `root` against a proof from `prove_read`.
+///
+/// Fails if the proof lacks a node on the path to `key`.
+pub fn check_read_proof(root: [u8; 32], proof: &[Vec<u8>], key: &[u8]) -> Result<Option<Vec<u8>>, Box<TrieError>> {
+	let mut db = MemoryDB::new();
+	for node in proof {
+		db.insert(node);
+	}
+
+	let root = TrieRoot::from(root);
+	let trie = TrieDB::new(&db, &root)?;
+	Ok(trie.get(key)?.map(|value| value.to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::cell::Cell;
+	use std::fmt;
+	use ethcore_bigint::hash::H256 as TrieRoot;
+	use hashdb::HashDB;
+	use primitives::hash::H256;
+	use memorydb::MemoryDB;
+	use patricia_trie::{TrieDBMut, TrieMut};
+	use triehash::trie_root;
+	use std::collections::HashMap;
+	use std::sync::Arc;
+	use super::{diff, minimal_updates, storage_root, Backend, BlockTagged, BoundedStaleness, Committed, CopyOnWrite, InMemory, Retrying};
+	use super::{check_read_proof, prove_read, ReadCache, RecordOrder, ReservedCache, StalenessError, StateDb, TrieBackend, Update, Void};
+
+	#[derive(Debug, PartialEq)]
+	struct Transient;
+
+	impl fmt::Display for Transient {
+		fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+			write!(f, "transient failure")
+		}
+	}
+
+	// fails the first `failures` reads.
+	#[derive(Default)]
+	struct Faulty {
+		inner: InMemory,
+		failures: Cell<usize>,
+	}
+
+	impl Backend for Faulty {
+		type Error = Transient;
+
+		fn storage(&self, key: &[u8]) -> Result<&[u8], Transient> {
+			if self.failures.get() > 0 {
+				self.failures.set(self.failures.get() - 1);
+				return Err(Transient);
+			}
+			Ok(self.inner.storage(key).unwrap())
+		}
+
+		fn pairs(&self) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Transient> {
+			Ok(self.inner.pairs().unwrap())
+		}
+
+		fn commit<I>(&mut self, changes: I) -> Committed
+			where I: IntoIterator<Item=Update>
+		{
+			self.inner.commit(changes)
+		}
+	}
+
+	#[test]
+	fn read_cache_keeps_values_in_place_as_it_grows() {
+		let cache = ReadCache::default();
+		let first = cache.remember(b"first".to_vec(), vec![1]);
+		for i in 0..1000u32 {
+			cache.remember(vec![(i >> 8) as u8, i as u8], vec![i as u8, 2]);
+		}
+
+		assert_eq!(first, &[1]);
+		assert_eq!(cache.get(b"first"), Some(&[1][..]));
+		assert_eq!(cache.get(&[3, 231]), Some(&[231, 2][..]));
+		assert_eq!(cache.remember(b"first".to_vec(), vec![3]), &[1]);
+		assert_eq!(cache.get(b"missing"), None);
+	}
+
+	#[test]
+	fn trie_backend_seeks_next_key() {
+		let pairs = vec![
+			(b"alpha".to_vec(), vec![1]),
+			(b"beta".to_vec(), vec![2]),
+			(b"betamax".to_vec(), vec![3]),
+		];
+		let mut db = MemoryDB::new();
+		let mut root = TrieRoot::default();
+		{
+			let mut trie = TrieDBMut::new(&mut db, &mut root);
+			for &(ref key, ref value) in &pairs {
+				trie.insert(key, value).unwrap();
+			}
+		}
+		let backend = TrieBackend::new(db, root.0);
+		let in_memory = InMemory::from(pairs.into_iter().collect::<HashMap<_, _>>());
+
+		for key in &[&b""[..], &b"alpha"[..], &b"b"[..], &b"beta"[..], &b"betamax"[..], &b"z"[..]] {
+			assert_eq!(backend.next_key(key).unwrap(), in_memory.next_key(key).unwrap());
+		}
+		assert_eq!(backend.next_key(b"alpha").unwrap(), Some(b"beta".to_vec()));
+		assert_eq!(backend.next_key(b"beta").unwrap(), Some(b"betamax".to_vec()));
+		assert_eq!(backend.next_key(b"betamax").unwrap(), None);
+	}
+
+	#[test]
+	fn in_memory_commit_reports_storage_root() {
+		let mut backend = InMemory::default();
+		let committed = backend.commit(vec![
+			Update::Storage(b"alpha".to_vec(), vec![1]),
+			Update::Storage(b"beta".to_vec(), vec![2, 2]),
+		]);
+		assert_eq!(committed.storage_tree_root.0, backend.storage_root().unwrap());
+
+		let committed = backend.commit(vec![Update::Storage(b"alpha".to_vec(), vec![])]);
+		assert_eq!(committed.storage_tree_root.0, backend.storage_root().unwrap());
+		assert_eq!(committed.storage_tree_root.0, storage_root(vec![(b"beta".to_vec(), vec![2, 2])]));
+	}
+
+	#[test]
+	fn diff_reports_added_removed_and_changed() {
+		let mut a = InMemory::default();
+		a.commit(vec![
+			Update::Storage(b"changed".to_vec(), b"old".to_vec()),
+			Update::Storage(b"removed".to_vec(), b"gone".to_vec()),
+			Update::Storage(b"same".to_vec(), b"value".to_vec()),
+		]);
+
+		let mut b = InMemory::default();
+		b.commit(vec![
+			Update::Storage(b"added".to_vec(), b"new".to_vec()),
+			Update::Storage(b"changed".to_vec(), b"new".to_vec()),
+			Update::Storage(b"same".to_vec(), b"value".to_vec()),
+		]);
+
+		assert_eq!(diff(&a, &b).unwrap(), vec![
+			(b"added".to_vec(), None, Some(b"new".to_vec())),
+			(b"changed".to_vec(), Some(b"old".to_vec()), Some(b"new".to_vec())),
+			(b"removed".to_vec(), Some(b"gone".to_vec()), None),
+		]);
+		assert!(diff(&a, &a).unwrap().is_empty());
+	}
+
+	#[test]
+	fn minimal_updates_transform_one_state_into_another() {
+		let mut from = InMemory::default();
+		from.commit(vec![
+			Update::Storage(b"changed".to_vec(), b"old".to_vec()),
+			Update::Storage(b"removed".to_vec(), b"gone".to_vec()),
+			Update::Storage(b"same".to_vec(), b"value".to_vec()),
+		]);
+
+		let mut to = InMemory::default();
+		to.commit(vec![
+			Update::Storage(b"added".to_vec(), b"new".to_vec()),
+			Update::Storage(b"changed".to_vec(), b"new".to_vec()),
+			Update::Storage(b"same".to_vec(), b"value".to_vec()),
+		]);
+
+		let updates = minimal_updates(&from, &to).unwrap();
+		assert_eq!(updates.len(), 3);
+		assert!(minimal_updates(&to, &to).unwrap().is_empty());
+
+		from.commit(updates);
+		assert!(diff(&from, &to).unwrap().is_empty());
+	}
+
+	#[test]
+	fn state_db_commit_writes_and_deletes() {
+		let mut db = InMemory::default();
+		StateDb::commit(&mut db, &[
+			(b"kept".to_vec(), Some(b"value".to_vec())),
+			(b"dropped".to_vec(), Some(b"value".to_vec())),
+		]).unwrap();
+		StateDb::commit(&mut db, &[(b"dropped".to_vec(), None)]).unwrap();
+
+		assert_eq!(db.storage(b"kept").unwrap(), b"value");
+		assert!(db.storage(b"dropped").unwrap().is_empty());
+	}
+
+	#[test]
+	fn retrying_backend_recovers_from_transient_failures() {
+		let mut faulty = Faulty::default();
+		faulty.commit(vec![Update::Storage(b"key".to_vec(), b"value".to_vec())]);
+		faulty.failures.set(2);
+
+		let retrying = Retrying::new(faulty, 3, |_: &Transient| true);
+		assert_eq!(retrying.storage(b"key"), Ok(&b"value"[..]));
+
+		let faulty = retrying.into_inner();
+		faulty.failures.set(3);
+		let retrying = Retrying::new(faulty, 3, |_: &Transient| true);
+		assert_eq!(retrying.storage(b"key"), Err(Transient));
+
+		let faulty = retrying.into_inner();
+		faulty.failures.set(1);
+		let never = Retrying::new(faulty, 3, |_: &Transient| false);
+		assert_eq!(never.storage(b"key"), Err(Transient));
+	}
+
+	#[test]
+	fn trie_backend_reads_through_the_trie() {
+		let pairs = vec![
+			(b"alpha".to_vec(), vec![1]),
+			(b"beta".to_vec(), vec![2, 2]),
+		];
+
+		let mut db = MemoryDB::new();
+		let mut root = TrieRoot::default();
+		{
+			let mut trie = TrieDBMut::new(&mut db, &mut root);
+			for &(ref key, ref value) in &pairs {
+				trie.insert(key, value).unwrap();
+			}
+		}
+		assert_eq!(root, trie_root(pairs.clone()));
+		assert_eq!(root.0, storage_root(pairs.clone()));
+
+		let mut backend = TrieBackend::new(db, root.0);
+		assert_eq!(backend.storage_root().unwrap(), root.0);
+		assert_eq!(backend.storage(b"alpha").unwrap(), &[1]);
+		assert_eq!(backend.storage(b"beta").unwrap(), &[2, 2]);
+		assert_eq!(backend.storage(b"alpha").unwrap(), &[1]);
+		assert!(backend.storage(b"gamma").unwrap().is_empty());
+
+		let mut read = backend.pairs().unwrap();
+		read.sort();
+		assert_eq!(read, pairs);
+
+		backend.commit(vec![Update::Storage(b"alpha".to_vec(), vec![])]);
+		assert!(backend.storage(b"alpha").unwrap().is_empty());
+		assert_eq!(backend.root(), trie_root(vec![(b"beta".to_vec(), vec![2, 2])]).0);
+	}
+
+	#[test]
+	fn skewed_keys_make_deeper_reads() {
+		let max_depth = |keys: Vec<Vec<u8>>| {
+			let mut db = MemoryDB::new();
+			let mut root = TrieRoot::default();
+			{
+				let mut trie = TrieDBMut::new(&mut db, &mut root);
+				for key in &keys {
+					trie.insert(key, &[0x55; 32]).unwrap();
+				}
+			}
+
+			let backend = TrieBackend::new(db, root.0);
+			for key in &keys {
+				backend.storage(key).unwrap();
+			}
+			backend.storage(&keys[0]).unwrap();
+
+			let depths = backend.read_depths();
+			assert_eq!(depths.reads, keys.len() as u64);
+			assert!(depths.average() <= depths.max as f64);
+			depths.max
+		};
+
+		let balanced = (0..16u8).map(|i| vec![i << 4]).collect();
+		let skewed = (1..17).map(|len| vec![0; len]).collect();
+		assert!(max_depth(skewed) > max_depth(balanced));
+	}
+
+	#[test]
+	fn read_proof_round_trips() {
+		let mut db = MemoryDB::new();
+		let mut root = TrieRoot::default();
+		{
+			let mut trie = TrieDBMut::new(&mut db, &mut root);
+			for i in 0..64u8 {
+				trie.insert(&[i, i], &[i; 40]).unwrap();
+			}
+		}
+		let nodes = db.keys().len();
+		let backend = TrieBackend::new(db, root.0);
+
+		let keys = vec![vec![3, 3], vec![40, 40], vec![3, 4]];
+		let (values, proof) = prove_read(&backend, &keys).unwrap();
+		assert_eq!(values, vec![Some(vec![3; 40]), Some(vec![40; 40]), None]);
+		assert!(proof.len() < nodes);
+
+		// checked without the database.
+		drop(backend);
+		for (key, value) in keys.iter().zip(values) {
+			assert_eq!(check_read_proof(root.0, &proof, key).unwrap(), value);
+		}
+		assert!(check_read_proof(root.0, &proof, &[20, 20]).is_err());
+		assert!(check_read_proof([1; 32], &proof, &[3, 3]).is_err());
+	}
+
+	#[test]
+	fn validators_are_read_through_trie_in_one_batch() {
+		let mut pairs = vec![(b"\0validator_count".to_vec(), vec![100])];
+		for i in 0..100 {
+			let mut key = b"\0validator".to_vec();
+			::codec::encode_usize(i, &mut key);
+			pairs.push((key, vec![i as u8 + 1; 32]));
+		}
+
+		let mut db = MemoryDB::new();
+		let mut root = TrieRoot::default();
+		{
+			let mut trie = TrieDBMut::new(&mut db, &mut root);
+			for &(ref key, ref value) in &pairs {
+				trie.insert(key, value).unwrap();
+			}
+		}
+		let backend = TrieBackend::new(db, root.0);
+
+		let keys: Vec<_> = pairs.iter().map(|&(ref key, _)| key.clone()).collect();
+		let batched: Vec<_> = backend.storage_batch(&keys).unwrap().into_iter().map(|v| v.to_vec()).collect();
+		let expected: Vec<_> = pairs.iter().map(|&(_, ref value)| value.clone()).collect();
+		assert_eq!(batched, expected);
+		assert_eq!(backend.read_depths().reads, 101);
+
+		let mut overlay = ::OverlayedChanges::default();
+		let ext = ::ext::Ext::new(&mut overlay, &backend);
+		let validators = ::Externalities::validators(&ext).unwrap();
+		assert_eq!(validators.len(), 100);
+		assert_eq!(validators[99], &[100; 32][..]);
+		// served from the cache filled by the first batch.
+		assert_eq!(backend.read_depths().reads, 101);
+	}
+
+	#[test]
+	fn trie_backend_reports_missing_nodes() {
+		let backend = TrieBackend::new(MemoryDB::new(), [1; 32]);
+		assert!(backend.storage(b"alpha").is_err());
+	}
+
+	// remote state where each value was fetched at some block.
+	#[derive(Default)]
+	struct FakeRemote {
+		values: HashMap<Vec<u8>, (Vec<u8>, u64)>,
+	}
+
+	impl Backend for FakeRemote {
+		type Error = Void;
+
+		fn storage(&self, key: &[u8]) -> Result<&[u8], Void> {
+			self.storage_tagged(key).map(|(value, _)| value)
+		}
+
+		fn pairs(&self) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Void> {
+			Ok(self.values.iter().map(|(k, &(ref v, _))| (k.clone(), v.clone())).collect())
+		}
+
+		// changes land in the block after the latest one seen.
+		fn commit<I>(&mut self, changes: I) -> Committed
+			where I: IntoIterator<Item=Update>
+		{
+			let block = self.values.values().map(|&(_, at)| at + 1).max().unwrap_or(0);
+			for update in changes {
+				match update {
+					Update::Storage(key, val) => if val.is_empty() {
+						self.values.remove(&key);
+					} else {
+						self.values.insert(key, (val, block));
+					},
+				}
+			}
+			Committed {
+				storage_tree_root: H256(self.storage_root().unwrap()),
+			}
+		}
+	}
+
+	impl BlockTagged for FakeRemote {
+		fn storage_tagged(&self, key: &[u8]) -> Result<(&[u8], u64), Void> {
+			Ok(self.values.get(key).map_or((&[][..], 0), |&(ref v, at)| (&v[..], at)))
+		}
+	}
+
+	#[test]
+	fn reserved_cache_lives_as_long_as_the_root() {
+		let state = |count: u8| -> HashMap<Vec<u8>, Vec<u8>> {
+			vec![(b"\0validator_count".to_vec(), vec![count]), (b"a".to_vec(), vec![2])].into_iter().collect()
+		};
+
+		let cache = ReservedCache::new(RecordOrder::new(InMemory::from(state(1))), 0).unwrap();
+		assert_eq!(cache.storage(b"\0validator_count").unwrap(), &[1]);
+		assert_eq!(cache.storage(b"\0validator_count").unwrap(), &[1]);
+		assert_eq!(cache.storage(b"a").unwrap(), &[2]);
+		assert_eq!(cache.storage(b"a").unwrap(), &[2]);
+
+		let cache = cache.rebase(RecordOrder::new(InMemory::from(state(1)))).unwrap();
+		assert_eq!(cache.storage(b"\0validator_count").unwrap(), &[1]);
+		assert_eq!(cache.storage(b"a").unwrap(), &[2]);
+		assert_eq!(cache.into_inner().into_recording(), vec![b"a".to_vec()]);
+
+		let cache = ReservedCache::new(RecordOrder::new(InMemory::from(state(1))), 0).unwrap();
+		assert_eq!(cache.storage(b"\0validator_count").unwrap(), &[1]);
+		let cache = cache.rebase(RecordOrder::new(InMemory::from(state(2)))).unwrap();
+		assert_eq!(cache.storage(b"\0validator_count").unwrap(), &[2]);
+		assert_eq!(cache.into_inner().into_recording(), vec![b"\0validator_count".to_vec()]);
+	}
+
+	#[test]
+	fn wrappers_forward_child_storage() {
+		let mut backend = InMemory::default();
+		StateDb::commit_child(&mut backend, b"child", &[(b"k".to_vec(), Some(b"v".to_vec()))]).unwrap();
+		let pairs = vec![(b"k".to_vec(), b"v".to_vec())];
+
+		let recording = RecordOrder::new(backend);
+		assert_eq!(recording.child_storage(b"child", b"k").unwrap(), &b"v"[..]);
+		assert_eq!(recording.child_pairs(b"child").unwrap(), pairs);
+
+		let retrying = Retrying::new(recording.inner, 3, |_: &Void| true);
+		assert_eq!(retrying.child_storage(b"child", b"k").unwrap(), &b"v"[..]);
+		assert_eq!(retrying.child_pairs(b"child").unwrap(), pairs);
+
+		let cached = ReservedCache::new(retrying.into_inner(), 0).unwrap();
+		assert_eq!(cached.child_storage(b"child", b"k").unwrap(), &b"v"[..]);
+		assert_eq!(cached.child_pairs(b"child").unwrap(), pairs);
+
+		let view = CopyOnWrite::new(Arc::new(cached.into_inner()));
+		assert_eq!(view.child_storage(b"child", b"k").unwrap(), &b"v"[..]);
+		assert_eq!(view.child_pairs(b"child").unwrap(), pairs);
+		assert!(view.child_storage(b"other", b"k").unwrap().is_empty());
+	}
+
+	#[test]
+	fn copy_on_write_forks_are_isolated() {
+		let mut base = InMemory::default();
+		base.commit(vec![
+			Update::Storage(b"shared".to_vec(), vec![1]),
+			Update::Storage(b"doomed".to_vec(), vec![2]),
+		]);
+		let base = Arc::new(base);
+
+		let mut a = CopyOnWrite::new(base.clone());
+		a.commit(vec![Update::Storage(b"a".to_vec(), vec![3])]);
+		let mut b = a.fork();
+		b.commit(vec![
+			Update::Storage(b"b".to_vec(), vec![4]),
+			Update::Storage(b"doomed".to_vec(), vec![]),
+		]);
+		a.commit(vec![Update::Storage(b"shared".to_vec(), vec![5])]);
+
+		assert_eq!(a.storage(b"shared").unwrap(), &[5]);
+		assert_eq!(b.storage(b"shared").unwrap(), &[1]);
+		assert_eq!(a.storage(b"a").unwrap(), &[3]);
+		assert_eq!(b.storage(b"a").unwrap(), &[3]);
+		assert_eq!(a.storage(b"b").unwrap(), &[] as &[u8]);
+		assert_eq!(a.storage(b"doomed").unwrap(), &[2]);
+		assert_eq!(b.storage(b"doomed").unwrap(), &[] as &[u8]);
+		assert_eq!(base.storage(b"shared").unwrap(), &[1]);
+		assert_eq!(base.storage(b"a").unwrap(), &[] as &[u8]);
+
+		assert_eq!(b.storage_root().unwrap(), storage_root(vec![
+			(b"shared".to_vec(), vec![1]),
+			(b"a".to_vec(), vec![3]),
+			(b"b".to_vec(), vec![4]),
+		]));
+	}
+
+	#[test]
+	fn stale_remote_reads_are_rejected() {
+		let mut remote = FakeRemote::default();
+		remote.values.insert(b"fresh".to_vec(), (vec![1], 9));
+		remote.values.insert(b"stale".to_vec(), (vec![2], 5));
+
+		let mut backend = BoundedStaleness::new(remote, 10, 2);
+		assert_eq!(backend.storage(b"fresh").unwrap(), &[1]);
+		match backend.storage(b"stale") {
+			Err(StalenessError::StaleRead { ref key, read_at: 5 }) if key == b"stale" => {}
+			_ => panic!("stale read accepted"),
+		}
+
+		backend.set_head(7);
+		assert_eq!(backend.storage(b"stale").unwrap(), &[2]);
+	}
+}
@@ -0,0 +1,62 @@
+// Copyright 2017 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Variable-length little-endian integers, as used by the validator set in storage.
+//!
+//! A value is written as its significant bytes, least significant first, so
+//! zero is written as no bytes at all. This is how `\0validator_count` is
+//! stored and how the index of each validator is appended to `\0validator`.
+
+/// Append the little-endian bytes of `value` to `dest`, without trailing zeroes.
+pub fn encode_usize(mut value: usize, dest: &mut Vec<u8>) {
+	while value > 0 {
+		dest.push(value as u8);
+		value >>= 8;
+	}
+}
+
+/// Read a value written by `encode_usize`; empty input is zero.
+///
+/// Values too large for a `usize` saturate at `usize::max_value()`.
+pub fn decode_usize(bytes: &[u8]) -> usize {
+	bytes.iter().rev()
+		.fold(Some(0usize), |acc, &b| acc.and_then(|acc| acc.checked_mul(256)).map(|acc| acc + b as usize))
+		.unwrap_or(usize::max_value())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{decode_usize, encode_usize};
+
+	#[test]
+	fn usize_round_trips() {
+		for &value in &[0, 1, 255, 256, 300, 65535, 1 << 20, usize::max_value()] {
+			let mut encoded = Vec::new();
+			encode_usize(value, &mut encoded);
+			assert_eq!(decode_usize(&encoded), value);
+		}
+
+		let mut encoded = vec![7];
+		encode_usize(300, &mut encoded);
+		assert_eq!(encoded, vec![7, 44, 1]);
+		assert_eq!(decode_usize(&[]), 0);
+	}
+
+	#[test]
+	fn oversized_values_saturate() {
+		assert_eq!(decode_usize(&[1; 16]), usize::max_value());
+	}
+}
@@ -17,19 +17,40 @@
 //! Conrete externalities implementation.
 
 use std::{error, fmt};
+use std::borrow::Cow;
+use std::cell::{Cell, OnceCell, RefCell};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 
 use backend::Backend;
-use {Externalities, OverlayedChanges};
+use {BackendMismatch, BatchMismatch, Diagnostics, Drbg, Event, ExecutionConfig, Externalities, OverlayedChanges, Violation};
 
 /// Errors that can occur when interacting with the externalities.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub enum Error<B, E> {
 	/// Failure to load state data from the backend.
-	#[allow(unused)]
 	Backend(B),
 	/// Failure to execute a function.
-	#[allow(unused)]
 	Executor(E),
+	/// The overlayed changes were built against another backend.
+	BackendMismatch(BackendMismatch),
+	/// The backend answered a batched read with the wrong number of values.
+	BatchMismatch(BatchMismatch),
+	/// The call broke a rule of its execution config.
+	Violation(Violation),
+}
+
+impl<B: ::Error, E: ::Error> Error<B, E> {
+	/// Box the underlying error, as `execute_boxed` returns it.
+	pub fn into_boxed(self) -> Box<::Error> {
+		match self {
+			Error::Backend(e) => Box::new(e),
+			Error::Executor(e) => Box::new(e),
+			Error::BackendMismatch(e) => Box::new(e),
+			Error::BatchMismatch(e) => Box::new(e),
+			Error::Violation(e) => Box::new(e),
+		}
+	}
 }
 
 impl<B: fmt::Display, E: fmt::Display> fmt::Display for Error<B, E> {
@@ -37,6 +58,9 @@ impl<B: fmt::Display, E: fmt::Display> fmt::Display for Error<B, E> {
 		match *self {
 			Error::Backend(ref e) => write!(f, "Storage backend error: {}", e),
 			Error::Executor(ref e) => write!(f, "Sub-call execution error: {}", e),
+			Error::BackendMismatch(ref e) => write!(f, "{}", e),
+			Error::BatchMismatch(ref e) => write!(f, "{}", e),
+			Error::Violation(ref e) => write!(f, "{}", e),
 		}
 	}
 }
@@ -46,7 +70,49 @@ impl<B: error::Error, E: error::Error> error::Error for Error<B, E> {
 		match *self {
 			Error::Backend(..) => "backend error",
 			Error::Executor(..) => "executor error",
+			Error::BackendMismatch(..) => "backend mismatch",
+			Error::BatchMismatch(..) => "batch mismatch",
+			Error::Violation(..) => "execution config violation",
+		}
+	}
+}
+
+// invokes a callback every `every` storage operations.
+struct YieldPoint<'a> {
+	every: u32,
+	ops: Cell<u32>,
+	on_yield: RefCell<&'a mut FnMut()>,
+}
+
+/// Key the reserved item `name` is stored under in the overlay and backend, for calls under `config`.
+pub fn physical_reserved_key(config: &ExecutionConfig, name: &[u8]) -> Vec<u8> {
+	let mut key = config.key_namespace.clone().unwrap_or_default();
+	key.push(config.reserved_prefix);
+	key.extend_from_slice(name);
+	key
+}
+
+/// Key of the reserved item a call under `config` sees the root of the child storage under `storage_key` at.
+pub fn child_root_key(config: &ExecutionConfig, storage_key: &[u8]) -> Vec<u8> {
+	let mut key = vec![config.reserved_prefix];
+	key.extend_from_slice(b"child_storage:");
+	key.extend_from_slice(storage_key);
+	key
+}
+
+/// Key the root of the child storage stored under `physical_storage_key` is kept
+/// at in the backend, for changes made under `config`.
+///
+/// `None` if the storage key is outside the namespace of `config`.
+pub fn physical_child_root_key(config: &ExecutionConfig, physical_storage_key: &[u8]) -> Option<Vec<u8>> {
+	match config.key_namespace {
+		Some(ref namespace) if physical_storage_key.starts_with(namespace) => {
+			let mut key = namespace.clone();
+			key.extend(child_root_key(config, &physical_storage_key[namespace.len()..]));
+			Some(key)
 		}
+		Some(_) => None,
+		None => Some(child_root_key(config, physical_storage_key)),
 	}
 }
 
@@ -56,6 +122,367 @@ pub struct Ext<'a, B: 'a> {
 	pub overlay: &'a mut OverlayedChanges,
 	/// The storage backend to read from.
 	pub backend: &'a B,
+	config: ExecutionConfig,
+	// validator set as read during this call; cleared by any write to a validator key.
+	validators: OnceCell<Vec<Vec<u8>>>,
+	diagnostics: Option<RefCell<Diagnostics>>,
+	yield_point: Option<YieldPoint<'a>>,
+	events: Vec<Event>,
+	quota_left: Option<u64>,
+	violation: RefCell<Option<Violation>>,
+	batch_mismatch: Cell<Option<BatchMismatch>>,
+	// distinct keys read or written during this call, when they are limited.
+	touched: RefCell<HashSet<Vec<u8>>>,
+	// hash of the code as of the last `code_hash`; cleared by any write to the code.
+	code_hash: Cell<Option<[u8; 32]>>,
+	// overlay entry of each key as of its first write during this call.
+	originals: HashMap<Vec<u8>, Option<Option<Vec<u8>>>>,
+	// seeded from the storage root on the first draw of randomness.
+	drbg: Option<Drbg>,
+}
+
+impl<'a, B: 'a> Ext<'a, B> {
+	/// Create a new `Ext` writing to the given overlay on top of the given backend.
+	pub fn new(overlay: &'a mut OverlayedChanges, backend: &'a B) -> Self {
+		Self::with_config(overlay, backend, ExecutionConfig::default())
+	}
+
+	/// Create a new `Ext` with the given configuration.
+	pub fn with_config(overlay: &'a mut OverlayedChanges, backend: &'a B, config: ExecutionConfig) -> Self {
+		let quota_left = config.storage_quota;
+		let mut ext = Ext {
+			overlay,
+			backend,
+			config,
+			validators: OnceCell::new(),
+			diagnostics: None,
+			yield_point: None,
+			events: Vec::new(),
+			quota_left,
+			violation: RefCell::new(None),
+			batch_mismatch: Cell::new(None),
+			touched: RefCell::new(HashSet::new()),
+			code_hash: Cell::new(None),
+			originals: HashMap::new(),
+			drbg: None,
+		};
+		if ext.config.record_diagnostics || ext.config.warn_on_duplicate_write {
+			ext.record_diagnostics();
+		}
+		ext
+	}
+
+	/// Start recording diagnostics about storage accesses.
+	pub fn record_diagnostics(&mut self) {
+		if self.diagnostics.is_none() {
+			self.diagnostics = Some(RefCell::new(Diagnostics::default()));
+		}
+	}
+
+	/// Warn about keys written more than once, recording them in the diagnostics.
+	pub fn warn_on_duplicate_write(&mut self) {
+		self.config.warn_on_duplicate_write = true;
+		self.record_diagnostics();
+	}
+
+	/// Invoke `on_yield` after every `every` storage operations.
+	///
+	/// This only inserts yield points between storage operations; it has no
+	/// effect on the outcome of the call. An `every` of zero never yields.
+	pub fn yield_every(&mut self, every: u32, on_yield: &'a mut FnMut()) {
+		self.yield_point = if every == 0 {
+			None
+		} else {
+			Some(YieldPoint { every, ops: Cell::new(0), on_yield: RefCell::new(on_yield) })
+		};
+	}
+
+	/// Take the diagnostics recorded so far, if any.
+	pub fn take_diagnostics(&mut self) -> Diagnostics {
+		self.diagnostics.as_mut()
+			.map(|d| ::std::mem::replace(d.get_mut(), Diagnostics::default()))
+			.unwrap_or_default()
+	}
+
+	/// Take the events emitted so far.
+	pub fn take_events(&mut self) -> Vec<Event> {
+		::std::mem::replace(&mut self.events, Vec::new())
+	}
+
+	/// Take the first batched read the backend answered with the wrong number of values, if any.
+	pub fn take_batch_mismatch(&mut self) -> Option<BatchMismatch> {
+		self.batch_mismatch.take()
+	}
+
+	/// Take the first rule the call broke, if any.
+	pub fn take_violation(&mut self) -> Option<Violation> {
+		self.violation.get_mut().take()
+	}
+
+	// whether the access control list lets the origin write `key`.
+	fn write_allowed(&self, key: &[u8]) -> bool {
+		let origin = self.config.origin.as_ref();
+		self.config.acl.iter()
+			.filter(|&(prefix, _)| key.starts_with(prefix))
+			.all(|(_, allowed)| origin.map_or(false, |o| allowed.contains(o)))
+	}
+
+	// the key `key` is stored under in the overlay and backend.
+	fn physical_key<'k>(&self, key: &'k [u8]) -> Cow<'k, [u8]> {
+		match self.config.key_namespace {
+			Some(ref namespace) => {
+				let mut physical = namespace.clone();
+				physical.extend_from_slice(key);
+				Cow::Owned(physical)
+			}
+			None => Cow::Borrowed(key),
+		}
+	}
+
+	fn into_physical_key(&self, key: Vec<u8>) -> Vec<u8> {
+		match self.config.key_namespace {
+			Some(ref namespace) => {
+				let mut physical = namespace.clone();
+				physical.extend(key);
+				physical
+			}
+			None => key,
+		}
+	}
+
+	// the key a stored key is known by during the call, if it is in the namespace.
+	fn logical_key<'k>(&self, physical: &'k [u8]) -> Option<&'k [u8]> {
+		match self.config.key_namespace {
+			Some(ref namespace) if physical.starts_with(namespace) => Some(&physical[namespace.len()..]),
+			Some(_) => None,
+			None => Some(physical),
+		}
+	}
+
+	// record a broken rule, keeping the first one.
+	fn violate(&self, violation: Violation) {
+		let mut first = self.violation.borrow_mut();
+		if first.is_none() {
+			*first = Some(violation);
+		}
+	}
+
+	fn storage_op(&self) {
+		if let Some(ref point) = self.yield_point {
+			let ops = point.ops.get() + 1;
+			if ops == point.every {
+				point.ops.set(0);
+				let mut on_yield = point.on_yield.borrow_mut();
+				(*on_yield)();
+			} else {
+				point.ops.set(ops);
+			}
+		}
+	}
+
+	// note `key` as touched, returning whether the call is still within its limit.
+	fn touch(&self, key: &[u8]) -> bool {
+		let limit = match self.config.max_touched_keys {
+			Some(limit) => limit,
+			None => return true,
+		};
+
+		let mut touched = self.touched.borrow_mut();
+		if !touched.contains(key) {
+			touched.insert(key.to_vec());
+		}
+		if touched.len() > limit {
+			self.violate(Violation::TooManyKeys(limit));
+			false
+		} else {
+			true
+		}
+	}
+
+	fn note_read(&self, key: &[u8]) {
+		if let Some(ref diagnostics) = self.diagnostics {
+			diagnostics.borrow_mut().reads.push(key.to_vec());
+		}
+		self.touch(key);
+		self.storage_op();
+	}
+
+	fn note_write(&mut self, key: &[u8]) {
+		if let Some(ref mut diagnostics) = self.diagnostics {
+			let diagnostics = diagnostics.get_mut();
+			if self.config.warn_on_duplicate_write
+				&& diagnostics.writes.iter().any(|k| &k[..] == key)
+				&& !diagnostics.duplicate_writes.iter().any(|k| &k[..] == key)
+			{
+				diagnostics.duplicate_writes.push(key.to_vec());
+			}
+			diagnostics.writes.push(key.to_vec());
+		}
+		self.storage_op();
+	}
+}
+
+impl<'a, B: Backend + 'a> Ext<'a, B> {
+	/// Get all keys starting with `prefix` in the overlay or the backend, in byte order.
+	pub fn keys_with_prefix(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, B::Error> {
+		let prefix = self.physical_key(prefix);
+		let mut keys = self.backend.keys_with_prefix(&prefix)?;
+		keys.extend(self.overlay.keys_with_prefix(&prefix));
+		keys.sort();
+		keys.dedup();
+
+		let mut present = Vec::with_capacity(keys.len());
+		for key in keys {
+			let is_present = match self.overlay.entry(&key) {
+				Some(value) => value.is_some(),
+				None => !self.backend.storage(&key)?.is_empty(),
+			};
+			if is_present {
+				present.extend(self.logical_key(&key).map(|k| k.to_vec()));
+			}
+		}
+		Ok(present)
+	}
+
+	/// Get the code, borrowed straight from the backend unless the overlay has changed it.
+	///
+	/// Code changed in the overlay, e.g. by a `set_code` earlier in the block,
+	/// is copied out so the externalities can be borrowed mutably while it runs.
+	pub fn code_for_call(&self) -> Result<Cow<'a, [u8]>, B::Error> {
+		let key = self.reserved_key(b"code");
+		self.note_read(&key);
+
+		let key = self.physical_key(&key);
+		match self.overlay.entry(&key) {
+			Some(code) => Ok(Cow::Owned(code.unwrap_or(&[]).to_vec())),
+			None => {
+				let backend: &'a B = self.backend;
+				backend.storage(&key).map(Cow::Borrowed)
+			}
+		}
+	}
+
+	// root of the child storage stored under the given physical key.
+	fn child_root(&self, storage_key: &[u8]) -> Result<[u8; 32], B::Error> {
+		let mut child: HashMap<_, _> = self.backend.child_pairs(storage_key)?.into_iter().collect();
+		for (key, value) in self.overlay.child_net_changes(storage_key) {
+			child.insert(key.to_vec(), value.to_vec());
+		}
+		Ok(::backend::storage_root(child))
+	}
+
+	// the reserved item the top-level state keeps the root of a child storage under.
+	fn child_root_key(&self, storage_key: &[u8]) -> Vec<u8> {
+		child_root_key(&self.config, storage_key)
+	}
+
+	/// Get the first key following `key` in the overlay or the backend, in byte order.
+	///
+	/// Both are sought from `key` onward, skipping keys the overlay deleted.
+	pub fn next_key(&self, key: &[u8]) -> Result<Option<Vec<u8>>, B::Error> {
+		let mut cursor = self.physical_key(key).into_owned();
+		loop {
+			let from_backend = self.backend.next_key(&cursor)?;
+			let next = match (from_backend, self.overlay.next_changed_key(&cursor)) {
+				(Some(b), Some(o)) => if &b[..] <= o { b } else { o.to_vec() },
+				(Some(b), None) => b,
+				(None, Some(o)) => o.to_vec(),
+				(None, None) => return Ok(None),
+			};
+
+			// keys past the namespace are past every key in it.
+			let logical = match self.logical_key(&next) {
+				Some(logical) => logical.to_vec(),
+				None => return Ok(None),
+			};
+			let present = match self.overlay.entry(&next) {
+				Some(value) => value.is_some(),
+				None => true,
+			};
+			if present {
+				return Ok(Some(logical));
+			}
+			cursor = next;
+		}
+	}
+
+	// run the checks and bookkeeping for a write, returning whether it may go ahead.
+	fn admit_write(&mut self, key: &[u8], value: &[u8]) -> bool {
+		self.note_write(key);
+		if self.config.read_only {
+			self.violate(Violation::ReadOnlyWrite(key.to_vec()));
+			return false;
+		}
+		if !self.touch(key) {
+			return false;
+		}
+		// whoever may write the upgrade key may upgrade, so it is guarded like the code.
+		let guarded = key == &self.reserved_key(b"code")[..] || key == &self.reserved_key(b"upgrade_key")[..];
+		let unauthorized = match self.config.origin {
+			Some(ref origin) if guarded => !self.upgrade_authorized(origin).unwrap_or(false),
+			_ => false,
+		};
+		if unauthorized {
+			self.violate(Violation::UnauthorizedUpgrade);
+			return false;
+		}
+		if !self.write_allowed(key) {
+			self.violate(Violation::UnauthorizedWrite(key.to_vec()));
+			return false;
+		}
+		if self.quota_left.map_or(false, |left| value.len() as u64 > left) {
+			self.violate(Violation::QuotaExceeded(self.config.storage_quota.unwrap_or(0)));
+			return false;
+		}
+		if let Some(ref mut left) = self.quota_left {
+			*left -= value.len() as u64;
+		}
+		if key.starts_with(&self.reserved_key(b"validator")) {
+			self.validators.take();
+		}
+		if key == &self.reserved_key(b"code")[..] {
+			self.code_hash.set(None);
+		}
+		if !self.originals.contains_key(key) {
+			let original = self.overlay.entry(&self.physical_key(key)).map(|v| v.map(|v| v.to_vec()));
+			self.originals.insert(key.to_vec(), original);
+		}
+		true
+	}
+
+	// read the validator set, fetching the entries not in the overlay with one batched backend read.
+	fn read_validators(&self) -> Result<Vec<Vec<u8>>, B::Error> {
+		let keys: Vec<_> = (0..::read_validator_count(self)?)
+			.map(|i| ::value_vec(i, self.reserved_key(b"validator")))
+			.inspect(|key| self.note_read(key))
+			.map(|key| self.into_physical_key(key))
+			.collect();
+
+		let missing: Vec<_> = keys.iter()
+			.filter(|key| self.overlay.entry(key).is_none())
+			.cloned()
+			.collect();
+		let mut fetched = self.backend.storage_batch(&missing)?;
+
+		// such a backend can't be trusted with the batch; the call fails once it returns.
+		if fetched.len() != missing.len() {
+			if self.batch_mismatch.get().is_none() {
+				self.batch_mismatch.set(Some(BatchMismatch { requested: missing.len(), returned: fetched.len() }));
+			}
+			fetched = missing.iter().map(|key| self.backend.storage(key)).collect::<Result<_, _>>()?;
+		}
+
+		let mut fetched = fetched.into_iter();
+		let mut values = Vec::with_capacity(keys.len());
+		for key in &keys {
+			let value = match self.overlay.entry(key) {
+				Some(value) => value.unwrap_or(&[]),
+				None => fetched.next().unwrap_or(&[]),
+			};
+			values.push(value.to_vec());
+		}
+		Ok(values)
+	}
 }
 
 impl<'a, B: 'a> Externalities for Ext<'a, B>
@@ -64,13 +491,649 @@ impl<'a, B: 'a> Externalities for Ext<'a, B>
 	type Error = B::Error;
 
 	fn storage(&self, key: &[u8]) -> Result<&[u8], Self::Error> {
-		match self.overlay.storage(key) {
-			Some(x) => Ok(x),
-			None => self.backend.storage(key)
+		self.note_read(key);
+
+		let key = self.physical_key(key);
+		match self.overlay.entry(&key) {
+			Some(Some(x)) => Ok(x),
+			Some(None) => Ok(&[]),
+			None => self.backend.storage(&key)
+		}
+	}
+
+	fn storage_len(&self, key: &[u8]) -> Result<Option<usize>, Self::Error> {
+		self.note_read(key);
+
+		let key = self.physical_key(key);
+		match self.overlay.entry(&key) {
+			Some(Some(x)) => Ok(Some(x.len())),
+			Some(None) => Ok(None),
+			None => self.backend.storage_len(&key),
+		}
+	}
+
+	fn exists_storage(&self, key: &[u8]) -> Result<bool, Self::Error> {
+		self.note_read(key);
+
+		let key = self.physical_key(key);
+		match self.overlay.entry(&key) {
+			Some(value) => Ok(value.is_some()),
+			None => self.backend.storage(&key).map(|v| !v.is_empty()),
+		}
+	}
+
+	fn original_storage(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+		self.note_read(key);
+
+		let physical = self.physical_key(key);
+		let original = match self.originals.get(key) {
+			Some(&Some(ref value)) => return Ok(value.clone()),
+			Some(&None) => None,
+			None => self.overlay.entry(&physical).map(|v| v.map(|v| v.to_vec())),
+		};
+		match original {
+			Some(value) => Ok(value),
+			None => self.backend.storage(&physical).map(|v| if v.is_empty() { None } else { Some(v.to_vec()) }),
 		}
 	}
 
 	fn set_storage(&mut self, key: Vec<u8>, value: Vec<u8>) {
-		self.overlay.set_storage(key, value);
+		if self.admit_write(&key, &value) {
+			let key = self.into_physical_key(key);
+			self.overlay.set_storage(key, value);
+		}
+	}
+
+	fn set_storages<I>(&mut self, values: I)
+		where I: IntoIterator<Item=(Vec<u8>, Vec<u8>)>
+	{
+		let admitted: Vec<_> = values.into_iter()
+			.filter(|&(ref key, ref value)| self.admit_write(key, value))
+			.collect();
+		let admitted: Vec<_> = admitted.into_iter()
+			.map(|(key, value)| (self.into_physical_key(key), value))
+			.collect();
+		self.overlay.set_storages(admitted);
+	}
+
+	fn clear_prefix(&mut self, prefix: &[u8]) -> Result<(), Self::Error> {
+		for key in self.keys_with_prefix(prefix)? {
+			self.set_storage(key, Vec::new());
+		}
+		Ok(())
+	}
+
+	fn next_storage_key(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+		self.next_key(key)
+	}
+
+	fn storage_keys_with_prefix_by<F>(&self, prefix: &[u8], mut cmp: F) -> Result<Vec<Vec<u8>>, Self::Error>
+		where F: FnMut(&[u8], &[u8]) -> Ordering
+	{
+		let mut keys = self.keys_with_prefix(prefix)?;
+		keys.sort_by(|a, b| cmp(&a[..], &b[..]));
+		Ok(keys)
+	}
+
+	fn child_storage(&self, storage_key: &[u8], key: &[u8]) -> Result<&[u8], Self::Error> {
+		self.note_read(&self.child_root_key(storage_key));
+
+		let storage_key = self.physical_key(storage_key);
+		match self.overlay.child_entry(&storage_key, key) {
+			Some(value) => Ok(value.unwrap_or(&[])),
+			None => self.backend.child_storage(&storage_key, key),
+		}
+	}
+
+	fn set_child_storage(&mut self, storage_key: Vec<u8>, key: Vec<u8>, value: Vec<u8>) {
+		// a child write changes the child's root in the top-level state, so it is admitted as a write to that item.
+		let root_key = self.child_root_key(&storage_key);
+		if self.admit_write(&root_key, &value) {
+			let storage_key = self.into_physical_key(storage_key);
+			self.overlay.set_child_storage(storage_key, key, value);
+		}
+	}
+
+	fn child_storage_root(&self, storage_key: &[u8]) -> Result<[u8; 32], Self::Error> {
+		self.child_root(&self.physical_key(storage_key))
+	}
+
+	fn touched_child_roots(&self) -> Result<Vec<(Vec<u8>, [u8; 32])>, Self::Error> {
+		let mut roots = Vec::new();
+		for physical in self.overlay.child_storage_keys() {
+			if let Some(storage_key) = self.logical_key(physical) {
+				roots.push((storage_key.to_vec(), self.child_root(physical)?));
+			}
+		}
+		Ok(roots)
+	}
+
+	fn storage_root(&self) -> Result<[u8; 32], Self::Error> {
+		let mut state: HashMap<_, _> = self.backend.pairs()?.into_iter().collect();
+		for (key, value) in self.overlay.net_changes() {
+			if value.is_empty() {
+				state.remove(key);
+			} else {
+				state.insert(key.to_vec(), value.to_vec());
+			}
+		}
+		if self.config.key_namespace.is_some() {
+			state = state.into_iter()
+				.filter_map(|(key, value)| self.logical_key(&key).map(|k| (k.to_vec(), value)))
+				.collect();
+		}
+
+		let empty_root = ::backend::storage_root(Vec::new());
+		for (storage_key, root) in self.touched_child_roots()? {
+			let key = self.child_root_key(&storage_key);
+			if root == empty_root {
+				state.remove(&key);
+			} else {
+				state.insert(key, root.to_vec());
+			}
+		}
+		Ok(::backend::storage_root(state))
+	}
+
+	fn reserved_key(&self, name: &[u8]) -> Vec<u8> {
+		let mut key = vec![self.config.reserved_prefix];
+		key.extend_from_slice(name);
+		key
+	}
+
+	fn genesis_hash(&self) -> Option<[u8; 32]> {
+		self.config.genesis_hash
+	}
+
+	fn timestamp(&self) -> u64 {
+		self.config.timestamp.unwrap_or(0)
+	}
+
+	fn random(&mut self, len: usize) -> Vec<u8> {
+		if self.drbg.is_none() {
+			// a failing backend fails the call anyway.
+			let seed = self.storage_root().unwrap_or_default();
+			self.drbg = Some(Drbg::new(seed));
+		}
+		self.drbg.as_mut().expect("set above; qed").next_bytes(len)
+	}
+
+	fn code_hash(&self) -> Result<[u8; 32], Self::Error> {
+		if let Some(hash) = self.code_hash.get() {
+			return Ok(hash);
+		}
+
+		let hash = ::keccak_hash::keccak(self.code()?).0;
+		self.code_hash.set(Some(hash));
+		Ok(hash)
+	}
+
+	fn storage_quota_left(&self) -> u64 {
+		self.quota_left.unwrap_or(u64::max_value())
+	}
+
+	fn emit_event(&mut self, topic: Vec<u8>, data: Vec<u8>) {
+		self.events.push(Event { topic, data });
+	}
+
+	fn storage_generation(&self) -> u64 {
+		self.overlay.generation()
+	}
+
+	fn validators(&self) -> Result<Vec<&[u8]>, Self::Error> {
+		if self.validators.get().is_none() {
+			let fresh = self.read_validators()?;
+			let _ = self.validators.set(fresh);
+		}
+
+		Ok(self.validators.get().map_or_else(Vec::new, |v| v.iter().map(|v| &v[..]).collect()))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::cell::Cell;
+	use backend::{storage_root, Backend, Committed, InMemory, LazyEnumerable, Void};
+	use {BatchMismatch, ExecutionConfig, Externalities, OverlayedChanges, Update, Violation};
+	use super::Ext;
+
+	#[derive(Default)]
+	struct CountingBackend {
+		inner: InMemory,
+		reads: Cell<usize>,
+	}
+
+	impl Backend for CountingBackend {
+		type Error = Void;
+
+		fn storage(&self, key: &[u8]) -> Result<&[u8], Void> {
+			self.reads.set(self.reads.get() + 1);
+			self.inner.storage(key)
+		}
+
+		fn pairs(&self) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Void> {
+			self.inner.pairs()
+		}
+
+		fn commit<I>(&mut self, changes: I) -> Committed
+			where I: IntoIterator<Item=Update>
+		{
+			self.inner.commit(changes)
+		}
+	}
+
+	#[derive(Default)]
+	struct BatchingBackend {
+		inner: InMemory,
+		reads: Cell<usize>,
+		batches: Cell<usize>,
+		// whether batches leave out the last value, as a broken backend might.
+		short: bool,
+	}
+
+	impl Backend for BatchingBackend {
+		type Error = Void;
+
+		fn storage(&self, key: &[u8]) -> Result<&[u8], Void> {
+			self.reads.set(self.reads.get() + 1);
+			self.inner.storage(key)
+		}
+
+		fn storage_batch(&self, keys: &[Vec<u8>]) -> Result<Vec<&[u8]>, Void> {
+			self.batches.set(self.batches.get() + 1);
+			let keys = if self.short { &keys[..keys.len().saturating_sub(1)] } else { keys };
+			keys.iter().map(|key| self.inner.storage(key)).collect()
+		}
+
+		fn pairs(&self) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Void> {
+			self.inner.pairs()
+		}
+
+		fn commit<I>(&mut self, changes: I) -> Committed
+			where I: IntoIterator<Item=Update>
+		{
+			self.inner.commit(changes)
+		}
+	}
+
+	#[test]
+	fn validators_are_read_in_one_batch() {
+		let mut backend = BatchingBackend::default();
+		backend.commit(vec![
+			Update::Storage(b"\0validator_count".to_vec(), vec![3]),
+			Update::Storage(b"\0validator".to_vec(), b"first".to_vec()),
+			Update::Storage(b"\0validator\x01".to_vec(), b"second".to_vec()),
+			Update::Storage(b"\0validator\x02".to_vec(), b"third".to_vec()),
+		]);
+
+		let mut overlay = OverlayedChanges::default();
+		let ext = Ext::new(&mut overlay, &backend);
+
+		assert_eq!(ext.validators().unwrap(), vec![&b"first"[..], &b"second"[..], &b"third"[..]]);
+		assert_eq!(ext.backend.reads.get(), 1);
+		assert_eq!(ext.backend.batches.get(), 1);
+	}
+
+	#[test]
+	fn short_validator_batch_is_reported() {
+		let mut backend = BatchingBackend { short: true, ..Default::default() };
+		backend.commit(vec![
+			Update::Storage(b"\0validator_count".to_vec(), vec![2]),
+			Update::Storage(b"\0validator".to_vec(), b"first".to_vec()),
+			Update::Storage(b"\0validator\x01".to_vec(), b"second".to_vec()),
+		]);
+
+		let mut overlay = OverlayedChanges::default();
+		let mut ext = Ext::new(&mut overlay, &backend);
+
+		assert_eq!(ext.validators().unwrap(), vec![&b"first"[..], &b"second"[..]]);
+		assert_eq!(ext.take_batch_mismatch(), Some(BatchMismatch { requested: 2, returned: 1 }));
+	}
+
+	#[test]
+	fn validators_are_read_once_per_call() {
+		let mut backend = CountingBackend::default();
+		backend.commit(vec![
+			Update::Storage(b"\0validator_count".to_vec(), vec![2]),
+			Update::Storage(b"\0validator".to_vec(), b"first".to_vec()),
+			Update::Storage(b"\0validator\x01".to_vec(), b"second".to_vec()),
+		]);
+
+		let mut overlay = OverlayedChanges::default();
+		let mut ext = Ext::new(&mut overlay, &backend);
+
+		assert_eq!(ext.validators().unwrap(), vec![&b"first"[..], &b"second"[..]]);
+		assert_eq!(ext.backend.reads.get(), 3);
+
+		assert_eq!(ext.validators().unwrap(), vec![&b"first"[..], &b"second"[..]]);
+		assert_eq!(ext.backend.reads.get(), 3);
+
+		ext.set_storage(b"unrelated".to_vec(), vec![1]);
+		assert_eq!(ext.validators().unwrap(), vec![&b"first"[..], &b"second"[..]]);
+		assert_eq!(ext.backend.reads.get(), 3);
+
+		ext.set_storage(b"\0validator\x01".to_vec(), b"changed".to_vec());
+		assert_eq!(ext.validators().unwrap(), vec![&b"first"[..], &b"changed"[..]]);
+		assert_eq!(ext.backend.reads.get(), 5);
+	}
+
+	#[test]
+	fn code_hash_follows_code_changes() {
+		use keccak_hash::keccak;
+
+		let mut backend = CountingBackend::default();
+		backend.commit(vec![Update::Storage(b"\0code".to_vec(), b"old".to_vec())]);
+
+		let mut overlay = OverlayedChanges::default();
+		let mut ext = Ext::new(&mut overlay, &backend);
+
+		assert_eq!(ext.code_hash().unwrap(), keccak(b"old").0);
+		assert_eq!(ext.code_hash().unwrap(), keccak(b"old").0);
+		assert_eq!(ext.backend.reads.get(), 1);
+
+		ext.set_storage(b"\0code".to_vec(), b"new".to_vec());
+		assert_eq!(ext.code_hash().unwrap(), keccak(b"new").0);
+	}
+
+	#[test]
+	fn key_iteration_spans_overlay_and_backend() {
+		let backend = LazyEnumerable::new(
+			|key: &[u8]| Some(key.to_vec()),
+			|| vec![b"a1".to_vec(), b"a2".to_vec(), b"b1".to_vec()],
+		);
+
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(b"a3".to_vec(), vec![1]);
+		overlay.commit_prospective();
+		overlay.set_storage(b"a0".to_vec(), vec![2]);
+		let ext = Ext::new(&mut overlay, &backend);
+
+		assert_eq!(ext.keys_with_prefix(b"a").unwrap(), vec![b"a0".to_vec(), b"a1".to_vec(), b"a2".to_vec(), b"a3".to_vec()]);
+		assert_eq!(ext.next_key(b"a2").unwrap(), Some(b"a3".to_vec()));
+		assert_eq!(ext.next_key(b"a3").unwrap(), Some(b"b1".to_vec()));
+		assert_eq!(ext.next_key(b"b1").unwrap(), None);
+	}
+
+	#[test]
+	fn key_iteration_by_comparator_spans_overlay_and_backend() {
+		// orders keys by their last byte, then in byte order.
+		fn by_last_byte(a: &[u8], b: &[u8]) -> ::std::cmp::Ordering {
+			a.last().cmp(&b.last()).then_with(|| a.cmp(b))
+		}
+
+		let backend = LazyEnumerable::new(
+			|key: &[u8]| Some(key.to_vec()),
+			|| vec![b"a3".to_vec(), b"b2".to_vec(), b"old".to_vec()],
+		);
+
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(b"a1".to_vec(), vec![1]);
+		overlay.commit_prospective();
+		overlay.set_storage(b"old".to_vec(), vec![]);
+		let ext = Ext::new(&mut overlay, &backend);
+
+		assert_eq!(ext.storage_keys_with_prefix_by(b"", by_last_byte).unwrap(), vec![b"a1".to_vec(), b"b2".to_vec(), b"a3".to_vec()]);
+		assert_eq!(ext.storage_keys_with_prefix_by(b"a", by_last_byte).unwrap(), vec![b"a1".to_vec(), b"a3".to_vec()]);
+
+		// `b2` is only in the backend.
+		assert_eq!(ext.next_storage_key_by(b"a1", by_last_byte).unwrap(), Some(b"b2".to_vec()));
+		assert_eq!(ext.next_storage_key_by(b"b2", by_last_byte).unwrap(), Some(b"a3".to_vec()));
+		assert_eq!(ext.next_storage_key_by(b"a3", by_last_byte).unwrap(), None);
+	}
+
+	#[test]
+	fn deletions_in_overlay_shadow_backend() {
+		let mut backend = InMemory::default();
+		backend.commit(vec![
+			Update::Storage(b"committed".to_vec(), vec![1]),
+			Update::Storage(b"prospective".to_vec(), vec![2]),
+		]);
+
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(b"committed".to_vec(), vec![]);
+		overlay.commit_prospective();
+		overlay.set_storage(b"prospective".to_vec(), vec![]);
+		let ext = Ext::new(&mut overlay, &backend);
+
+		assert!(ext.storage(b"committed").unwrap().is_empty());
+		assert!(ext.storage(b"prospective").unwrap().is_empty());
+		assert_eq!(ext.storage_len(b"committed").unwrap(), None);
+		assert!(ext.keys_with_prefix(b"").unwrap().is_empty());
+	}
+
+	#[test]
+	fn exists_storage_consults_backend_only_when_overlay_has_no_opinion() {
+		let mut backend = CountingBackend::default();
+		backend.commit(vec![
+			Update::Storage(b"backend".to_vec(), vec![1]),
+			Update::Storage(b"deleted".to_vec(), vec![2]),
+		]);
+
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(b"deleted".to_vec(), vec![]);
+		overlay.set_storage(b"overlay".to_vec(), vec![3]);
+		let ext = Ext::new(&mut overlay, &backend);
+
+		assert!(ext.exists_storage(b"overlay").unwrap());
+		assert!(!ext.exists_storage(b"deleted").unwrap());
+		assert_eq!(ext.backend.reads.get(), 0);
+
+		assert!(ext.exists_storage(b"backend").unwrap());
+		assert!(!ext.exists_storage(b"absent").unwrap());
+		assert_eq!(ext.backend.reads.get(), 2);
+	}
+
+	#[test]
+	fn original_storage_is_the_value_before_the_call() {
+		let mut backend = InMemory::default();
+		backend.commit(vec![
+			Update::Storage(b"backend".to_vec(), vec![1]),
+			Update::Storage(b"untouched".to_vec(), vec![2]),
+		]);
+
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(b"overlay".to_vec(), vec![3]);
+		let mut ext = Ext::new(&mut overlay, &backend);
+
+		ext.set_storage(b"backend".to_vec(), vec![4]);
+		ext.set_storage(b"overlay".to_vec(), vec![5]);
+		ext.set_storage(b"fresh".to_vec(), vec![6]);
+		assert_eq!(ext.original_storage(b"backend").unwrap(), Some(vec![1]));
+
+		ext.set_storage(b"backend".to_vec(), vec![]);
+		ext.set_storage(b"overlay".to_vec(), vec![7]);
+		assert_eq!(ext.original_storage(b"backend").unwrap(), Some(vec![1]));
+		assert_eq!(ext.original_storage(b"overlay").unwrap(), Some(vec![3]));
+		assert_eq!(ext.original_storage(b"fresh").unwrap(), None);
+		assert_eq!(ext.original_storage(b"untouched").unwrap(), Some(vec![2]));
+		assert_eq!(ext.storage(b"overlay").unwrap(), &[7]);
+	}
+
+	#[test]
+	fn storage_root_honors_overlay_deletions() {
+		let mut backend = InMemory::default();
+		{
+			let mut overlay = OverlayedChanges::default();
+			let ext = Ext::new(&mut overlay, &backend);
+			assert_eq!(ext.storage_root().unwrap(), [
+				0x56, 0xe8, 0x1f, 0x17, 0x1b, 0xcc, 0x55, 0xa6, 0xff, 0x83, 0x45, 0xe6, 0x92, 0xc0, 0xf8, 0x6e,
+				0x5b, 0x48, 0xe0, 0x1b, 0x99, 0x6c, 0xad, 0xc0, 0x01, 0x62, 0x2f, 0xb5, 0xe3, 0x63, 0xb4, 0x21,
+			]);
+		}
+
+		backend.commit(vec![
+			Update::Storage(b"a".to_vec(), vec![1]),
+			Update::Storage(b"b".to_vec(), vec![2]),
+		]);
+
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(b"b".to_vec(), vec![]);
+		overlay.commit_prospective();
+		overlay.set_storage(b"c".to_vec(), vec![3]);
+		let ext = Ext::new(&mut overlay, &backend);
+
+		let expected = storage_root(vec![(b"a".to_vec(), vec![1]), (b"c".to_vec(), vec![3])]);
+		assert_eq!(ext.storage_root().unwrap(), expected);
+		assert_eq!(backend.storage_root().unwrap(), storage_root(vec![(b"a".to_vec(), vec![1]), (b"b".to_vec(), vec![2])]));
+	}
+
+	#[test]
+	fn namespaced_exts_do_not_interfere() {
+		let mut backend = InMemory::default();
+		backend.commit(vec![Update::Storage(b"akey".to_vec(), vec![9])]);
+		let mut overlay = OverlayedChanges::default();
+		let namespaced = |namespace: &[u8]| ExecutionConfig { key_namespace: Some(namespace.to_vec()), ..Default::default() };
+
+		{
+			let mut a = Ext::with_config(&mut overlay, &backend, namespaced(b"a"));
+			assert_eq!(a.storage(b"key").unwrap(), &[9]);
+			a.set_storage(b"key".to_vec(), vec![1]);
+			a.set_storage(b"\0code".to_vec(), b"code a".to_vec());
+		}
+		{
+			let mut b = Ext::with_config(&mut overlay, &backend, namespaced(b"b"));
+			assert_eq!(b.storage(b"key").unwrap(), &[] as &[u8]);
+			assert_eq!(b.code().unwrap(), &[] as &[u8]);
+			b.set_storage(b"key".to_vec(), vec![2]);
+			b.set_storage(b"\0code".to_vec(), b"code b".to_vec());
+		}
+
+		let a = Ext::with_config(&mut overlay, &backend, namespaced(b"a"));
+		assert_eq!(a.storage(b"key").unwrap(), &[1]);
+		assert_eq!(a.code().unwrap(), b"code a");
+		assert_eq!(a.keys_with_prefix(b"").unwrap(), vec![b"\0code".to_vec(), b"key".to_vec()]);
+		assert_eq!(a.storage_root().unwrap(), storage_root(vec![
+			(b"\0code".to_vec(), b"code a".to_vec()),
+			(b"key".to_vec(), vec![1]),
+		]));
+		drop(a);
+
+		assert_eq!(overlay.storage(b"akey").unwrap(), &[1]);
+		assert_eq!(overlay.storage(b"bkey").unwrap(), &[2]);
+		assert_eq!(overlay.storage(b"b\0code").unwrap(), b"code b");
+	}
+
+	#[test]
+	fn child_storages_do_not_collide() {
+		let backend = InMemory::default();
+		let mut overlay = OverlayedChanges::default();
+		let mut ext = Ext::new(&mut overlay, &backend);
+
+		ext.set_child_storage(b"alice".to_vec(), b"key".to_vec(), vec![1]);
+		ext.set_child_storage(b"bob".to_vec(), b"key".to_vec(), vec![2]);
+
+		assert_eq!(ext.child_storage(b"alice", b"key").unwrap(), &[1]);
+		assert_eq!(ext.child_storage(b"bob", b"key").unwrap(), &[2]);
+		assert_eq!(ext.storage(b"key").unwrap(), &[] as &[u8]);
+		assert_eq!(ext.child_storage_root(b"alice").unwrap(), storage_root(vec![(b"key".to_vec(), vec![1])]));
+		assert!(ext.child_storage_root(b"alice").unwrap() != ext.child_storage_root(b"bob").unwrap());
+	}
+
+	#[test]
+	fn touched_child_roots_lists_every_changed_child() {
+		let backend = InMemory::default();
+		let mut overlay = OverlayedChanges::default();
+		let mut ext = Ext::new(&mut overlay, &backend);
+		assert!(ext.touched_child_roots().unwrap().is_empty());
+
+		ext.set_child_storage(b"bob".to_vec(), b"key".to_vec(), vec![2]);
+		ext.set_child_storage(b"alice".to_vec(), b"key".to_vec(), vec![1]);
+
+		assert_eq!(ext.touched_child_roots().unwrap(), vec![
+			(b"alice".to_vec(), ext.child_storage_root(b"alice").unwrap()),
+			(b"bob".to_vec(), ext.child_storage_root(b"bob").unwrap()),
+		]);
+		assert_eq!(ext.touched_child_roots().unwrap()[0].1, storage_root(vec![(b"key".to_vec(), vec![1])]));
+	}
+
+	#[test]
+	fn discarding_child_changes_restores_child_root() {
+		let backend = InMemory::default();
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_child_storage(b"alice".to_vec(), b"key".to_vec(), vec![1]);
+		overlay.commit_prospective();
+
+		let (child_root, root) = {
+			let ext = Ext::new(&mut overlay, &backend);
+			(ext.child_storage_root(b"alice").unwrap(), ext.storage_root().unwrap())
+		};
+		assert!(root != storage_root(Vec::new()));
+
+		{
+			let mut ext = Ext::new(&mut overlay, &backend);
+			ext.set_child_storage(b"alice".to_vec(), b"key".to_vec(), vec![2]);
+			assert!(ext.child_storage_root(b"alice").unwrap() != child_root);
+			assert!(ext.storage_root().unwrap() != root);
+		}
+		overlay.discard_prospective();
+
+		let ext = Ext::new(&mut overlay, &backend);
+		assert_eq!(ext.child_storage(b"alice", b"key").unwrap(), &[1]);
+		assert_eq!(ext.child_storage_root(b"alice").unwrap(), child_root);
+		assert_eq!(ext.storage_root().unwrap(), root);
+	}
+
+	#[test]
+	fn child_writes_are_admitted_like_top_level_writes() {
+		let backend = InMemory::default();
+		let mut overlay = OverlayedChanges::default();
+		let mut config = ExecutionConfig { origin: Some(b"bob".to_vec()), max_touched_keys: Some(1), ..Default::default() };
+		config.acl.insert(b"\0child_storage:alice".to_vec(), vec![b"alice".to_vec()].into_iter().collect());
+		let mut ext = Ext::with_config(&mut overlay, &backend, config);
+
+		ext.set_child_storage(b"alice".to_vec(), b"key".to_vec(), vec![1]);
+		assert_eq!(ext.take_violation(), Some(Violation::UnauthorizedWrite(b"\0child_storage:alice".to_vec())));
+		assert_eq!(ext.child_storage(b"alice", b"key").unwrap(), &[] as &[u8]);
+
+		ext.set_child_storage(b"bob".to_vec(), b"key".to_vec(), vec![2]);
+		assert_eq!(ext.take_violation(), Some(Violation::TooManyKeys(1)));
+		assert_eq!(ext.child_storage(b"bob", b"key").unwrap(), &[] as &[u8]);
+	}
+
+	#[test]
+	fn clear_prefix_deletes_overlay_and_backend_keys() {
+		let mut backend = InMemory::default();
+		backend.commit(vec![
+			Update::Storage(b"\0validator_count".to_vec(), vec![2]),
+			Update::Storage(b"\0validator".to_vec(), b"first".to_vec()),
+			Update::Storage(b"\0validator\x01".to_vec(), b"second".to_vec()),
+			Update::Storage(b"other".to_vec(), vec![1]),
+		]);
+
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(b"\0validator\x02".to_vec(), b"third".to_vec());
+		let mut ext = Ext::new(&mut overlay, &backend);
+		assert_eq!(ext.validators().unwrap().len(), 2);
+
+		ext.clear_prefix(b"\0validator").unwrap();
+		assert!(ext.validators().unwrap().is_empty());
+		assert!(ext.keys_with_prefix(b"\0validator").unwrap().is_empty());
+		assert_eq!(ext.storage(b"other").unwrap(), &[1]);
+	}
+
+	#[test]
+	fn next_storage_key_skips_overlay_deletions() {
+		let mut backend = InMemory::default();
+		backend.commit(vec![
+			Update::Storage(b"b".to_vec(), vec![1]),
+			Update::Storage(b"c".to_vec(), vec![1]),
+			Update::Storage(b"e".to_vec(), vec![1]),
+		]);
+
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(b"a".to_vec(), vec![2]);
+		overlay.set_storage(b"c".to_vec(), vec![]);
+		overlay.commit_prospective();
+		overlay.set_storage(b"d".to_vec(), vec![2]);
+		overlay.set_storage(b"e".to_vec(), vec![]);
+		let ext = Ext::new(&mut overlay, &backend);
+
+		let mut walked = Vec::new();
+		let mut key = Vec::new();
+		while let Some(next) = ext.next_storage_key(&key).unwrap() {
+			walked.push(next.clone());
+			key = next;
+		}
+		assert_eq!(walked, vec![b"a".to_vec(), b"b".to_vec(), b"d".to_vec()]);
 	}
 }
@@ -20,6 +20,7 @@
 
 extern crate polkadot_primitives as primitives;
 
+extern crate ethcore_bigint;
 extern crate hashdb;
 extern crate memorydb;
 extern crate keccak_hash;
@@ -28,242 +29,3238 @@ extern crate patricia_trie;
 extern crate triehash;
 
 extern crate byteorder;
+extern crate blake2_rfc;
 
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, Bound, HashMap, HashSet};
+use std::sync::Arc;
+use std::cmp::Ordering;
 use std::fmt;
 
+use primitives::codec::{Decode, Encode};
 use primitives::contract::{CallData};
 
 pub mod backend;
+pub mod codec;
 mod ext;
 
+pub use ext::Error as ExecutionError;
+pub use primitives::fees::FeeParams;
+
 /// Updates to be committed to the state.
 pub enum Update {
 	/// Set storage of object at given key -- empty is deletion.
 	Storage(Vec<u8>, Vec<u8>),
 }
 
+// entries of a key space in key order; `None` records a deletion.
+type Entries = BTreeMap<Vec<u8>, Option<Vec<u8>>>;
+
 // in-memory section of the state.
-#[derive(Default)]
+//
+// Everything is kept in key order, so iterating, encoding or hashing a state
+// gives the same result however its changes were made.
+#[derive(Default, Clone)]
 struct MemoryState {
-	storage: HashMap<Vec<u8>, Vec<u8>>,
+	storage: Entries,
+	// child storages by storage key, each a key space of its own.
+	children: BTreeMap<Vec<u8>, Entries>,
+}
+
+// an empty value is a deletion.
+fn entry_value(val: Vec<u8>) -> Option<Vec<u8>> {
+	if val.is_empty() { None } else { Some(val) }
+}
+
+// the value of an entry, empty for a deletion.
+fn entry_slice(entry: &Option<Vec<u8>>) -> &[u8] {
+	entry.as_ref().map_or(&[][..], |v| &v[..])
 }
 
 impl MemoryState {
-	fn storage(&self, key: &[u8]) -> Option<&[u8]> {
-		self.storage.get(key).map(|v| &v[..])
+	// `None` if this state has no opinion on `key`, `Some(None)` if it deleted it.
+	fn storage(&self, key: &[u8]) -> Option<Option<&[u8]>> {
+		self.storage.get(key).map(|v| v.as_ref().map(|v| &v[..]))
 	}
 
 	fn set_storage(&mut self, key: Vec<u8>, val: Vec<u8>) {
-		self.storage.insert(key, val);
+		self.storage.insert(key, entry_value(val));
 	}
 
-	fn update<I>(&mut self, changes: I) where I: IntoIterator<Item=Update> {
-		for update in changes {
-			match update {
-				Update::Storage(key, val) => {
-					if val.is_empty() {
-						self.storage.remove(&key);
-					} else {
-						self.storage.insert(key, val);
-					}
+	// as `storage`, within the child storage under `storage_key`.
+	fn child_storage(&self, storage_key: &[u8], key: &[u8]) -> Option<Option<&[u8]>> {
+		self.children.get(storage_key)
+			.and_then(|child| child.get(key))
+			.map(|v| v.as_ref().map(|v| &v[..]))
+	}
+
+	fn set_child_storage(&mut self, storage_key: Vec<u8>, key: Vec<u8>, val: Vec<u8>) {
+		self.children.entry(storage_key).or_insert_with(Entries::new).insert(key, entry_value(val));
+	}
+
+	fn is_empty(&self) -> bool {
+		self.storage.is_empty() && self.children.values().all(|child| child.is_empty())
+	}
+
+	fn clear(&mut self) {
+		self.storage.clear();
+		self.children.clear();
+	}
+
+	// fold the changes of `other` over this state.
+	fn absorb(&mut self, other: MemoryState) {
+		self.storage.extend(other.storage);
+		for (storage_key, child) in other.children {
+			self.children.entry(storage_key).or_insert_with(Entries::new).extend(child);
+		}
+	}
+
+	// merge `other` into this state, returning whether anything changed.
+	fn merge<F>(&mut self, other: MemoryState, resolve: &F) -> bool
+		where F: Fn(&[u8], &[u8], &[u8]) -> Vec<u8>
+	{
+		let mut changed = merge_entries(&mut self.storage, other.storage, resolve);
+		for (storage_key, theirs) in other.children {
+			let ours = self.children.entry(storage_key).or_insert_with(Entries::new);
+			changed |= merge_entries(ours, theirs, resolve);
+		}
+		changed
+	}
+}
+
+// merge `theirs` into `ours`, returning whether anything changed.
+fn merge_entries<F>(ours: &mut Entries, theirs: Entries, resolve: &F) -> bool
+	where F: Fn(&[u8], &[u8], &[u8]) -> Vec<u8>
+{
+	let mut changed = false;
+	for (key, their_value) in theirs {
+		let merged = match ours.get(&key) {
+			Some(our_value) if *our_value == their_value => continue,
+			Some(our_value) => entry_value(resolve(&key, entry_slice(our_value), entry_slice(&their_value))),
+			None => their_value,
+		};
+		changed |= ours.get(&key) != Some(&merged);
+		ours.insert(key, merged);
+	}
+	changed
+}
+
+// entries in key order, each a key followed by 0 for a deletion or 1 and the value.
+fn encode_entries(entries: &Entries, dest: &mut Vec<u8>) {
+	(entries.len() as u32).encode_to(dest);
+	for (key, value) in entries {
+		key.encode_to(dest);
+		match *value {
+			Some(ref value) => {
+				dest.push(1);
+				value.encode_to(dest);
+			}
+			None => dest.push(0),
+		}
+	}
+}
+
+fn decode_entries(input: &mut &[u8]) -> Option<Entries> {
+	let len = u32::decode(input)?;
+	let mut entries = Entries::new();
+	for _ in 0..len {
+		let key = Vec::<u8>::decode(input)?;
+		let (&tag, rest) = input.split_first()?;
+		*input = rest;
+		let value = match tag {
+			0 => None,
+			1 => {
+				let value = Vec::<u8>::decode(input)?;
+				// deletions are never recorded as empty values.
+				if value.is_empty() {
+					return None;
+				}
+				Some(value)
+			}
+			_ => return None,
+		};
+		entries.insert(key, value);
+	}
+	Some(entries)
+}
+
+impl Encode for MemoryState {
+	fn encode_to(&self, dest: &mut Vec<u8>) {
+		encode_entries(&self.storage, dest);
+
+		(self.children.len() as u32).encode_to(dest);
+		for (storage_key, child) in &self.children {
+			storage_key.encode_to(dest);
+			encode_entries(child, dest);
+		}
+	}
+}
+
+impl Decode for MemoryState {
+	fn decode(input: &mut &[u8]) -> Option<Self> {
+		let storage = decode_entries(input)?;
+		let len = u32::decode(input)?;
+		let mut children = BTreeMap::new();
+		for _ in 0..len {
+			let storage_key = Vec::<u8>::decode(input)?;
+			children.insert(storage_key, decode_entries(input)?);
+		}
+		Some(MemoryState { storage, children })
+	}
+}
+
+// derives an index key from a storage key and its value.
+type Extractor = Arc<Fn(&[u8], &[u8]) -> Option<Vec<u8>> + Send + Sync>;
+
+// secondary index over committed changes, from index key to storage keys.
+#[derive(Clone)]
+struct Index {
+	extractor: Extractor,
+	entries: HashMap<Vec<u8>, BTreeSet<Vec<u8>>>,
+}
+
+impl Index {
+	fn insert(&mut self, key: &[u8], value: &[u8]) {
+		if let Some(index_key) = (self.extractor)(key, value) {
+			self.entries.entry(index_key).or_insert_with(BTreeSet::new).insert(key.to_vec());
+		}
+	}
+
+	fn remove(&mut self, key: &[u8], value: &[u8]) {
+		if let Some(index_key) = (self.extractor)(key, value) {
+			let now_empty = match self.entries.get_mut(&index_key) {
+				Some(keys) => {
+					keys.remove(key);
+					keys.is_empty()
+				}
+				None => false,
+			};
+			if now_empty {
+				self.entries.remove(&index_key);
+			}
+		}
+	}
+
+	fn rebuild(&mut self, state: &MemoryState) {
+		self.entries.clear();
+		for (key, value) in &state.storage {
+			if let Some(ref value) = *value {
+				self.insert(key, value);
+			}
+		}
+	}
+}
+
+const CHANGES_TRIE_DEPTH: usize = 256;
+
+// sparse merkle tree over committed entries, each a leaf at the path given by
+// the keccak of its key. Committing rehashes only the paths of the keys which
+// changed, and the root only depends on the set of committed entries.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct ChangesTrie {
+	// nodes which differ from the empty subtree of their height, by height and
+	// path with the bits below that height cleared.
+	nodes: HashMap<(usize, [u8; 32]), [u8; 32]>,
+}
+
+impl ChangesTrie {
+	fn of(state: &MemoryState) -> Self {
+		let mut trie = ChangesTrie::default();
+		trie.update(state.storage.iter().map(|(key, value)| (&key[..], Some(value))));
+		trie
+	}
+
+	// deletions hash differently from any value, the empty one included.
+	fn leaf(key: &[u8], value: &Option<Vec<u8>>) -> [u8; 32] {
+		let mut input = Vec::new();
+		(key.len() as u32).encode_to(&mut input);
+		input.extend_from_slice(key);
+		match *value {
+			Some(ref value) => {
+				input.push(1);
+				(value.len() as u32).encode_to(&mut input);
+				input.extend_from_slice(value);
+			}
+			None => input.push(0),
+		}
+		keccak_hash::keccak(&input).0
+	}
+
+	fn branch(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+		let mut input = [0u8; 64];
+		input[..32].copy_from_slice(left);
+		input[32..].copy_from_slice(right);
+		keccak_hash::keccak(&input[..]).0
+	}
+
+	// hash of the empty subtree of each height, leaves being height 0.
+	fn empty() -> Vec<[u8; 32]> {
+		let mut empty = vec![[0u8; 32]];
+		for height in 0..CHANGES_TRIE_DEPTH {
+			let below = empty[height];
+			empty.push(Self::branch(&below, &below));
+		}
+		empty
+	}
+
+	fn node(&self, height: usize, path: [u8; 32], empty: &[[u8; 32]]) -> [u8; 32] {
+		self.nodes.get(&(height, path)).cloned().unwrap_or(empty[height])
+	}
+
+	// give each key its committed entry, or remove it for `None`, rehashing its path to the root.
+	fn update<'a, I>(&mut self, entries: I)
+		where I: IntoIterator<Item = (&'a [u8], Option<&'a Option<Vec<u8>>>)>
+	{
+		let empty = Self::empty();
+		for (key, entry) in entries {
+			let mut path = keccak_hash::keccak(key).0;
+			match entry {
+				Some(value) => { self.nodes.insert((0, path), Self::leaf(key, value)); }
+				None => { self.nodes.remove(&(0, path)); }
+			}
+
+			for height in 1..CHANGES_TRIE_DEPTH + 1 {
+				// the bit telling apart the two children of the node at this height.
+				let bit = CHANGES_TRIE_DEPTH - height;
+				path[bit / 8] &= !(0x80 >> (bit % 8));
+				let mut right = path;
+				right[bit / 8] |= 0x80 >> (bit % 8);
+
+				let hash = Self::branch(&self.node(height - 1, path, &empty), &self.node(height - 1, right, &empty));
+				if hash == empty[height] {
+					self.nodes.remove(&(height, path));
+				} else {
+					self.nodes.insert((height, path), hash);
 				}
 			}
 		}
 	}
+
+	fn root(&self) -> [u8; 32] {
+		match self.nodes.get(&(CHANGES_TRIE_DEPTH, [0u8; 32])) {
+			Some(root) => *root,
+			None => Self::empty()[CHANGES_TRIE_DEPTH],
+		}
+	}
 }
 
+/// An opaque copy of the committed changes of an `OverlayedChanges`.
+#[derive(Clone)]
+pub struct MemoryStateSnapshot(MemoryState);
+
 /// The overlayed changes to state to be queried on top of the backend.
 ///
 /// A transaction shares all prospective changes within an inner overlay
-/// that can be cleared.
-#[derive(Default)]
+/// that can be cleared. Nested transactions stack further layers on top of
+/// the prospective changes, each of which can be rolled back on its own.
+#[derive(Default, Clone)]
 pub struct OverlayedChanges {
 	prospective: MemoryState,
 	committed: MemoryState,
+	// open nested transactions, innermost last.
+	transactions: Vec<MemoryState>,
+	backend_stamp: Option<u64>,
+	generation: u64,
+	indexes: HashMap<String, Index>,
+	changes: ChangesTrie,
+	// backend values read ahead by `warm_start`; never changes, never drained.
+	warmed: BTreeMap<Vec<u8>, Vec<u8>>,
 }
 
 impl OverlayedChanges {
-	fn storage(&self, key: &[u8]) -> Option<&[u8]> {
-		self.prospective.storage(key)
+	// `None` if the overlay has no opinion on `key`, `Some(None)` if it deleted it.
+	fn entry(&self, key: &[u8]) -> Option<Option<&[u8]>> {
+		self.transactions.iter().rev().filter_map(|layer| layer.storage(key)).next()
+			.or_else(|| self.prospective.storage(key))
 			.or_else(|| self.committed.storage(key))
-			.and_then(|v| if v.is_empty() { None } else { Some(v) })
+			.or_else(|| self.warmed.get(key).map(|v| Some(&v[..])))
+	}
+
+	// the value of `key` in the overlay; `None` whether it is absent or deleted.
+	fn storage(&self, key: &[u8]) -> Option<&[u8]> {
+		self.entry(key).and_then(|v| v)
 	}
 
 	fn set_storage(&mut self, key: Vec<u8>, val: Vec<u8>) {
-		self.prospective.set_storage(key, val);
+		match self.transactions.last_mut() {
+			Some(layer) => layer.set_storage(key, val),
+			None => self.prospective.set_storage(key, val),
+		}
 	}
 
-	/// Discard prospective changes to state.
-	pub fn discard_prospective(&mut self) {
-		self.prospective.storage.clear();
+	// as `entry`, within the child storage under `storage_key`.
+	fn child_entry(&self, storage_key: &[u8], key: &[u8]) -> Option<Option<&[u8]>> {
+		self.transactions.iter().rev().filter_map(|layer| layer.child_storage(storage_key, key)).next()
+			.or_else(|| self.prospective.child_storage(storage_key, key))
+			.or_else(|| self.committed.child_storage(storage_key, key))
 	}
 
-	/// Commit prospective changes to state.
-	pub fn commit_prospective(&mut self) {
-		let storage_updates = self.prospective.storage.drain()
-			.map(|(key, value)| Update::Storage(key, value));
+	fn set_child_storage(&mut self, storage_key: Vec<u8>, key: Vec<u8>, val: Vec<u8>) {
+		match self.transactions.last_mut() {
+			Some(layer) => layer.set_child_storage(storage_key, key, val),
+			None => self.prospective.set_child_storage(storage_key, key, val),
+		}
+	}
 
-		self.committed.update(storage_updates);
+	// storage keys of every child storage changed in any layer, in byte order.
+	fn child_storage_keys(&self) -> BTreeSet<&[u8]> {
+		::std::iter::once(&self.committed)
+			.chain(::std::iter::once(&self.prospective))
+			.chain(self.transactions.iter())
+			.flat_map(|layer| layer.children.keys())
+			.map(|k| &k[..])
+			.collect()
 	}
-}
 
-/// State Machine Error bound.
-///
-/// This should reflect WASM error type bound for future compatibility.
-pub trait Error: 'static + fmt::Debug + fmt::Display + Send {}
-impl<E> Error for E where E: 'static + fmt::Debug + fmt::Display + Send {}
+	// net changes to the child storage under `storage_key`; empty values are deletions.
+	fn child_net_changes(&self, storage_key: &[u8]) -> HashMap<&[u8], &[u8]> {
+		::std::iter::once(&self.committed)
+			.chain(::std::iter::once(&self.prospective))
+			.chain(self.transactions.iter())
+			.filter_map(|layer| layer.children.get(storage_key))
+			.flat_map(|child| child.iter())
+			.map(|(k, v)| (&k[..], entry_slice(v)))
+			.collect()
+	}
 
-fn value_vec(mut value: usize, initial: Vec<u8>) -> Vec<u8> {
-	let mut acc = initial;
-	while value > 0 {
-		acc.push(value as u8);
-		value /= 256;
+	fn set_storages<I>(&mut self, values: I) where I: IntoIterator<Item=(Vec<u8>, Vec<u8>)> {
+		let layer = match self.transactions.last_mut() {
+			Some(layer) => layer,
+			None => &mut self.prospective,
+		};
+		layer.storage.extend(values.into_iter().map(|(key, val)| (key, entry_value(val))));
 	}
-	acc
-}
 
-/// Externalities: pinned to specific active address.
-pub trait Externalities {
-	/// Externalities error type.
-	type Error: Error;
+	/// Open a nested transaction on top of the prospective changes.
+	pub fn start_transaction(&mut self) {
+		self.transactions.push(MemoryState::default());
+	}
 
-	/// Read storage of current contract being called.
-	fn storage(&self, key: &[u8]) -> Result<&[u8], Self::Error>;
+	/// Fold the innermost transaction into the layer beneath it.
+	pub fn commit_transaction(&mut self) {
+		debug_assert!(!self.transactions.is_empty(), "no transaction to commit");
+		if let Some(top) = self.transactions.pop() {
+			let below = self.transactions.last_mut().unwrap_or(&mut self.prospective);
+			below.absorb(top);
+		}
+	}
 
-	/// Set storage of current contract being called (effective immediately).
-	fn set_storage(&mut self, key: Vec<u8>, value: Vec<u8>);
+	/// Discard the changes of the innermost transaction.
+	pub fn rollback_transaction(&mut self) {
+		debug_assert!(!self.transactions.is_empty(), "no transaction to roll back");
+		self.transactions.pop();
+	}
 
-	/// Get the current set of validators.
-	fn validators(&self) -> Result<Vec<&[u8]>, Self::Error> {
-		(0..self.storage(b"\0validator_count")?.into_iter()
-				.rev()
-				.fold(0, |acc, &i| (acc << 8) + (i as usize)))
-			.map(|i| self.storage(&value_vec(i, b"\0validator".to_vec())))
+	/// Number of nested transactions currently open.
+	pub fn transaction_depth(&self) -> usize {
+		self.transactions.len()
+	}
+
+	/// Create an overlay for building on top of a parent block's unpersisted changes.
+	///
+	/// All of the parent's changes, prospective ones included, become the
+	/// committed layer of the new overlay, so its deletions shadow the backend
+	/// just as the parent's own would.
+	pub fn on_parent(parent: &OverlayedChanges) -> Self {
+		let mut committed = parent.committed.clone();
+		committed.absorb(parent.prospective.clone());
+		for layer in &parent.transactions {
+			committed.absorb(layer.clone());
+		}
+
+		let mut overlay = OverlayedChanges {
+			committed,
+			backend_stamp: parent.backend_stamp,
+			generation: parent.generation,
+			indexes: parent.indexes.clone(),
+			warmed: parent.warmed.clone(),
+			..Default::default()
+		};
+		overlay.rebuild_derived();
+		overlay
+	}
+
+	/// Get the code set in this overlay for calls under `config`, or empty if it hasn't been changed.
+	///
+	/// The code lives under the reserved prefix and key namespace of `config`.
+	pub fn code(&self, config: &ExecutionConfig) -> &[u8] {
+		self.storage(&ext::physical_reserved_key(config, b"code")).unwrap_or(&[])
+	}
+
+	/// Change the code for calls under `config`, subject to the same commit and discard as any other change.
+	pub fn set_code(&mut self, config: &ExecutionConfig, code: Vec<u8>) {
+		self.set_storage(ext::physical_reserved_key(config, b"code"), code);
+	}
+
+	// all keys which currently hold a non-empty value.
+	fn keys(&self) -> HashSet<&[u8]> {
+		self.prospective.storage.keys()
+			.chain(self.committed.storage.keys())
+			.chain(self.transactions.iter().flat_map(|layer| layer.storage.keys()))
+			.map(|k| &k[..])
+			.filter(|k| self.storage(k).is_some())
 			.collect()
 	}
-}
 
-/// Code execution engine.
-pub trait CodeExecutor: Sized {
-	/// Externalities error type.
-	type Error: Error;
+	// the smallest key greater than `key` changed in any layer, deletions included.
+	fn next_changed_key(&self, key: &[u8]) -> Option<&[u8]> {
+		let after = (Bound::Excluded(key.to_vec()), Bound::Unbounded);
+		::std::iter::once(&self.committed)
+			.chain(::std::iter::once(&self.prospective))
+			.chain(self.transactions.iter())
+			.filter_map(|layer| layer.storage.range(after.clone()).next())
+			.map(|(k, _)| &k[..])
+			.min()
+	}
 
-	/// Call a given method in the runtime.
-	fn call<E: Externalities>(
-		&self,
-		ext: &mut E,
-		code: &[u8],
-		method: &str,
-		data: &CallData,
-	) -> Result<Vec<u8>, Self::Error>;
-}
+	// committed changes with prospective ones folded over them; empty values are deletions.
+	fn net_changes(&self) -> HashMap<&[u8], &[u8]> {
+		self.committed.storage.iter()
+			.chain(self.prospective.storage.iter())
+			.chain(self.transactions.iter().flat_map(|layer| layer.storage.iter()))
+			.map(|(k, v)| (&k[..], entry_slice(v)))
+			.collect()
+	}
 
-/// Execute a call using the given state backend, overlayed changes, and call executor.
-///
-/// On an error, no prospective changes are written to the overlay.
-///
-/// Note: changes to code will be in place if this call is made again. For running partial
-/// blocks (e.g. a transaction at a time), ensure a differrent method is used.
-pub fn execute<B: backend::Backend, Exec: CodeExecutor>(
-	backend: &B,
-	overlay: &mut OverlayedChanges,
-	exec: &Exec,
-	method: &str,
-	call_data: &CallData,
-) -> Result<Vec<u8>, Box<Error>> {
+	/// Whether both overlays would leave any backend in the same state.
+	///
+	/// Only the net changes are compared, not how they were arrived at.
+	pub fn same_net_effect(&self, other: &Self) -> bool {
+		let children = self.child_storage_keys();
+		self.net_changes() == other.net_changes()
+			&& children == other.child_storage_keys()
+			&& children.iter().all(|k| self.child_net_changes(k) == other.child_net_changes(k))
+	}
 
-	let result = {
-		let mut externalities = ext::Ext {
-			backend,
-			overlay: &mut *overlay
-		};
-		// make a copy.
-		let code = externalities.storage(b"\0code").unwrap_or(&[]).to_vec();
-
-		exec.call(
-			&mut externalities,
-			&code,
-			method,
-			call_data,
-		)
-	};
+	/// Get all keys starting with the given prefix, in byte order.
+	pub fn keys_with_prefix(&self, prefix: &[u8]) -> Vec<Vec<u8>> {
+		self.keys_with_prefix_by(prefix, |a, b| a.cmp(b))
+	}
 
-	match result {
-		Ok(out) => {
-			overlay.commit_prospective();
-			Ok(out)
+	/// Get all keys starting with the given prefix, ordered by the given comparator.
+	///
+	/// Only keys changed in the overlay are seen; `Externalities::storage_keys_with_prefix_by`
+	/// also covers the backend.
+	pub fn keys_with_prefix_by<F>(&self, prefix: &[u8], mut cmp: F) -> Vec<Vec<u8>>
+		where F: FnMut(&[u8], &[u8]) -> Ordering
+	{
+		let mut keys: Vec<Vec<u8>> = self.keys().into_iter()
+			.filter(|k| k.starts_with(prefix))
+			.map(|k| k.to_vec())
+			.collect();
+
+		keys.sort_by(|a, b| cmp(&a[..], &b[..]));
+		keys
+	}
+
+	/// Get the first key following the given one, in byte order.
+	pub fn next_key(&self, key: &[u8]) -> Option<Vec<u8>> {
+		self.next_key_by(key, |a, b| a.cmp(b))
+	}
+
+	/// Get the first key following the given one, ordered by the given comparator.
+	///
+	/// Only keys changed in the overlay are seen; `Externalities::next_storage_key_by`
+	/// also covers the backend.
+	pub fn next_key_by<F>(&self, key: &[u8], mut cmp: F) -> Option<Vec<u8>>
+		where F: FnMut(&[u8], &[u8]) -> Ordering
+	{
+		let mut next: Option<&[u8]> = None;
+		for candidate in self.keys() {
+			if cmp(candidate, key) != Ordering::Greater { continue }
+			match next {
+				Some(n) if cmp(candidate, n) != Ordering::Less => {}
+				_ => next = Some(candidate),
+			}
+		}
+		next.map(|k| k.to_vec())
+	}
+
+	/// Apply a batch of updates to the prospective changes, all or nothing.
+	///
+	/// Every update is checked with `validate` before any is applied. If one is
+	/// rejected, its index is returned and the overlay is left untouched.
+	pub fn try_apply_updates<I, F>(&mut self, updates: I, validate: F) -> Result<(), usize>
+		where I: IntoIterator<Item=Update>, F: Fn(&Update) -> bool
+	{
+		let updates: Vec<_> = updates.into_iter().collect();
+		if let Some(invalid) = updates.iter().position(|u| !validate(u)) {
+			return Err(invalid);
 		}
-		Err(e) => {
-			overlay.discard_prospective();
-			Err(Box::new(e))
+
+		for update in updates {
+			match update {
+				Update::Storage(key, value) => self.set_storage(key, value),
+			}
 		}
+		Ok(())
 	}
-}
 
-#[cfg(test)]
-mod tests {
-	use std::collections::HashMap;
-	use super::{OverlayedChanges, Externalities};
+	/// Maintain a secondary index over the committed changes under the given name.
+	///
+	/// `extractor` maps a key and its value to the index key to file it under,
+	/// if any. The index follows every change to the committed layer, including
+	/// it being drained into a state db.
+	pub fn add_index<F>(&mut self, name: &str, extractor: F)
+		where F: Fn(&[u8], &[u8]) -> Option<Vec<u8>> + Send + Sync + 'static
+	{
+		let mut index = Index {
+			extractor: Arc::new(extractor),
+			entries: HashMap::new(),
+		};
+		index.rebuild(&self.committed);
+		self.indexes.insert(name.to_owned(), index);
+	}
 
-	#[test]
-	fn overlayed_storage_works() {
-		let mut overlayed = OverlayedChanges::default();
+	/// Get the committed keys filed under `index_key` in the named index, in byte order.
+	pub fn index_lookup(&self, name: &str, index_key: &[u8]) -> Vec<Vec<u8>> {
+		self.indexes.get(name)
+			.and_then(|index| index.entries.get(index_key))
+			.map_or_else(Vec::new, |keys| keys.iter().cloned().collect())
+	}
 
-		let key = vec![42, 69, 169, 142];
+	// rebuild what is kept up to date with the committed changes from scratch.
+	fn rebuild_derived(&mut self) {
+		for index in self.indexes.values_mut() {
+			index.rebuild(&self.committed);
+		}
+		self.changes = ChangesTrie::of(&self.committed);
+	}
 
-		assert!(overlayed.storage(&key).is_none());
+	/// Hash of the committed changes, deletions included; child storage changes aren't covered.
+	///
+	/// This is the root of a binary merkle tree with each committed entry at
+	/// the path given by the keccak of its key. It is kept up to date as changes
+	/// are committed, hashing only the paths of the keys which changed, and it
+	/// only depends on the set of committed changes, not on how they came to be
+	/// committed.
+	pub fn changes_root(&self) -> [u8; 32] {
+		self.changes.root()
+	}
 
-		overlayed.set_storage(key.clone(), vec![1, 2, 3]);
-		assert_eq!(overlayed.storage(&key).unwrap(), &[1, 2, 3]);
+	/// Pre-read the given keys from the backend into a read-only cache.
+	///
+	/// Later reads of these keys are served from the overlay, below any change
+	/// to them. Keys absent from the backend, or already changed in the overlay,
+	/// are skipped. Cached values are not changes: they are never drained,
+	/// encoded or hashed.
+	pub fn warm_start<B: backend::Backend>(&mut self, backend: &B, keys: &[&[u8]]) -> Result<(), B::Error> {
+		for key in keys {
+			if self.entry(key).is_some() {
+				continue;
+			}
+			let value = backend.storage(key)?;
+			if !value.is_empty() {
+				self.warmed.insert(key.to_vec(), value.to_vec());
+			}
+		}
+		Ok(())
+	}
 
-		overlayed.commit_prospective();
-		assert_eq!(overlayed.storage(&key).unwrap(), &[1, 2, 3]);
+	/// Check the internal consistency of the overlay, describing the first problem found.
+	///
+	/// Debug builds check this after every change to the committed layer.
+	pub fn check_invariants(&self) -> Result<(), &'static str> {
+		let layers = ::std::iter::once(&self.committed)
+			.chain(::std::iter::once(&self.prospective))
+			.chain(self.transactions.iter());
+		for layer in layers {
+			let mut entries = layer.storage.values()
+				.chain(layer.children.values().flat_map(|child| child.values()));
+			if entries.any(|v| v.as_ref().map_or(false, |v| v.is_empty())) {
+				return Err("deletion recorded as an empty value rather than `None`");
+			}
+		}
+		Ok(())
+	}
 
-		overlayed.set_storage(key.clone(), vec![]);
-		assert!(overlayed.storage(&key).is_none());
+	/// Merge the changes of another overlay, e.g. a speculatively executed clone, into this one.
+	///
+	/// Prospective and committed changes are merged layer by layer. Where both
+	/// overlays hold different values for a key, `resolve(key, ours, theirs)`
+	/// gives the value to keep.
+	pub fn merge_with<F>(&mut self, other: OverlayedChanges, resolve: F)
+		where F: Fn(&[u8], &[u8], &[u8]) -> Vec<u8>
+	{
+		self.prospective.merge(other.prospective, &resolve);
+		if self.committed.merge(other.committed, &resolve) {
+			self.generation += 1;
+		}
+		self.rebuild_derived();
+		debug_assert_eq!(self.check_invariants(), Ok(()));
+	}
 
-		overlayed.discard_prospective();
-		assert_eq!(overlayed.storage(&key).unwrap(), &[1, 2, 3]);
+	/// Capture the committed changes, to be restored later with `replace_committed`.
+	pub fn capture_committed(&self) -> MemoryStateSnapshot {
+		MemoryStateSnapshot(self.committed.clone())
+	}
 
-		overlayed.set_storage(key.clone(), vec![]);
-		overlayed.commit_prospective();
-		assert!(overlayed.storage(&key).is_none());
+	/// Replace the committed changes with a previously captured snapshot.
+	///
+	/// Prospective changes are left as they are.
+	pub fn replace_committed(&mut self, snapshot: MemoryStateSnapshot) {
+		self.committed = snapshot.0;
+		self.generation += 1;
+		self.rebuild_derived();
+		debug_assert_eq!(self.check_invariants(), Ok(()));
 	}
 
-	#[derive(Debug, Default)]
-	struct TestExternalities {
-		storage: HashMap<Vec<u8>, Vec<u8>>,
+	/// Encode the committed changes, deletions included, for restoring with `from_bytes`.
+	///
+	/// Prospective changes, the backend stamp and indexes are not encoded.
+	pub fn to_bytes(&self) -> Vec<u8> {
+		self.committed.encode()
 	}
-	impl Externalities for TestExternalities {
-		type Error = u8;
 
-		fn storage(&self, key: &[u8]) -> Result<&[u8], Self::Error> {
-			Ok(self.storage.get(&key.to_vec()).map_or(&[] as &[u8], Vec::as_slice))
+	/// Restore an overlay from the output of `to_bytes`, with no prospective changes.
+	///
+	/// Returns `None` if `bytes` is malformed.
+	pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+		let mut input = bytes;
+		let committed = MemoryState::decode(&mut input)?;
+		if !input.is_empty() {
+			return None;
 		}
 
-		fn set_storage(&mut self, key: Vec<u8>, value: Vec<u8>) {
-			self.storage.insert(key, value);
+		let mut overlay = OverlayedChanges {
+			committed,
+			..Default::default()
+		};
+		overlay.rebuild_derived();
+		Some(overlay)
+	}
+
+	/// Stamp these changes as belonging to the backend with the given identity.
+	///
+	/// `execute` will refuse to run against a backend with a different stamp.
+	pub fn set_backend_stamp(&mut self, stamp: u64) {
+		self.backend_stamp = Some(stamp);
+	}
+
+	/// Visit each committed entry in key order; an empty value means deletion.
+	pub fn for_each_committed<F>(&self, mut f: F)
+		where F: FnMut(&[u8], &[u8])
+	{
+		for (key, value) in &self.committed.storage {
+			f(key, entry_slice(value));
 		}
 	}
 
-	#[test]
-	fn validators_call_works() {
-		let mut ext = TestExternalities::default();
+	/// Take every committed change in key order, leaving the committed layer empty.
+	///
+	/// Each key comes with its final value, `None` meaning deletion; this is
+	/// the delta to apply to a persistent backend. Child storage changes are
+	/// not included; they are taken by `drain_committed_children`.
+	pub fn drain_committed(&mut self) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+		let changes: Vec<_> = ::std::mem::replace(&mut self.committed.storage, Entries::new()).into_iter().collect();
+		// the backend is about to take these values, so cached reads of them go stale.
+		for &(ref key, _) in &changes {
+			self.warmed.remove(key);
+		}
+		self.rebuild_derived();
+		changes
+	}
 
-		assert_eq!(ext.validators(), Ok(vec![]));
+	/// Take every committed child storage change, leaving none committed.
+	///
+	/// Changes are grouped by storage key, in key order, each group as
+	/// `drain_committed` would give it.
+	pub fn drain_committed_children(&mut self) -> Vec<(Vec<u8>, Vec<(Vec<u8>, Option<Vec<u8>>)>)> {
+		let children = ::std::mem::replace(&mut self.committed.children, BTreeMap::new());
+		self.rebuild_derived();
+		children.into_iter().map(|(storage_key, child)| (storage_key, child.into_iter().collect())).collect()
+	}
 
-		ext.set_storage(b"\0validator_count".to_vec(), vec![]);
-		assert_eq!(ext.validators(), Ok(vec![]));
+	// prospective changes, open transactions included, in key order with `None` for deletions.
+	fn prospective_changes(&self) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+		let changes: BTreeMap<_, _> = self.prospective.storage.iter()
+			.chain(self.transactions.iter().flat_map(|layer| layer.storage.iter()))
+			.collect();
+		changes.into_iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+	}
 
-		ext.set_storage(b"\0validator_count".to_vec(), vec![1]);
-		assert_eq!(ext.validators(), Ok(vec![&[][..]]));
+	/// Discard prospective changes to state, including any open transactions.
+	pub fn discard_prospective(&mut self) {
+		debug_assert!(self.transactions.is_empty(), "prospective changes discarded with a transaction open");
+		self.transactions.clear();
+		self.prospective.clear();
+	}
 
-		ext.set_storage(b"\0validator".to_vec(), b"first".to_vec());
-		assert_eq!(ext.validators(), Ok(vec![&b"first"[..]]));
+	/// Number of commits so far which actually changed state.
+	pub fn generation(&self) -> u64 {
+		self.generation
+	}
 
-		ext.set_storage(b"\0validator_count".to_vec(), vec![2]);
-		ext.set_storage(b"\0validator\x01".to_vec(), b"second".to_vec());
-		assert_eq!(ext.validators(), Ok(vec![&b"first"[..], &b"second"[..]]));
+	/// Commit prospective changes to state, including any open transactions.
+	pub fn commit_prospective(&mut self) {
+		debug_assert!(self.transactions.is_empty(), "prospective changes committed with a transaction open");
+		while !self.transactions.is_empty() {
+			self.commit_transaction();
+		}
+
+		if !self.prospective.is_empty() {
+			self.generation += 1;
+		}
+
+		for index in self.indexes.values_mut() {
+			for (key, value) in &self.prospective.storage {
+				if let Some(Some(old)) = self.committed.storage(key) {
+					index.remove(key, old);
+				}
+				if let Some(ref value) = *value {
+					index.insert(key, value);
+				}
+			}
+		}
+
+		let prospective = ::std::mem::replace(&mut self.prospective, MemoryState::default());
+		let changed: Vec<_> = prospective.storage.keys().cloned().collect();
+		self.committed.absorb(prospective);
+
+		let committed = &self.committed.storage;
+		self.changes.update(changed.iter().map(|key| (&key[..], committed.get(key))));
+		debug_assert_eq!(self.check_invariants(), Ok(()));
+	}
+}
+
+/// Hashing algorithms available to the runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgo {
+	/// BLAKE2b with a 128-bit output.
+	Blake2_128,
+	/// BLAKE2b with a 256-bit output.
+	Blake2_256,
+	/// Keccak with a 256-bit output.
+	Keccak256,
+}
+
+impl HashAlgo {
+	/// Get the algorithm for a selector passed over the WASM boundary.
+	pub fn from_selector(selector: u32) -> Option<Self> {
+		match selector {
+			0 => Some(HashAlgo::Blake2_128),
+			1 => Some(HashAlgo::Blake2_256),
+			2 => Some(HashAlgo::Keccak256),
+			_ => None,
+		}
+	}
+
+	/// Hash the given data.
+	pub fn hash(self, data: &[u8]) -> Vec<u8> {
+		match self {
+			HashAlgo::Blake2_128 => blake2_rfc::blake2b::blake2b(16, &[], data).as_bytes().to_vec(),
+			HashAlgo::Blake2_256 => blake2_rfc::blake2b::blake2b(32, &[], data).as_bytes().to_vec(),
+			HashAlgo::Keccak256 => keccak_hash::keccak(data).0.to_vec(),
+		}
+	}
+}
+
+/// Deterministic random bytes, drawn from the keccak of a seed and a counter.
+///
+/// The counter advances with every 32 bytes drawn, so bytes are never repeated
+/// while the same seed always gives the same sequence.
+#[derive(Debug, Clone)]
+pub struct Drbg {
+	seed: [u8; 32],
+	counter: u64,
+}
+
+impl Drbg {
+	/// Create a generator for the given seed.
+	pub fn new(seed: [u8; 32]) -> Self {
+		Drbg { seed, counter: 0 }
+	}
+
+	/// Draw the next `len` bytes.
+	pub fn next_bytes(&mut self, len: usize) -> Vec<u8> {
+		let mut out = Vec::with_capacity(len);
+		while out.len() < len {
+			let mut input = self.seed.to_vec();
+			self.counter.encode_to(&mut input);
+			self.counter += 1;
+
+			let block = keccak_hash::keccak(&input).0;
+			let take = ::std::cmp::min(block.len(), len - out.len());
+			out.extend_from_slice(&block[..take]);
+		}
+		out
+	}
+}
+
+/// Version of a runtime, as reported by its `version` export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RuntimeVersion {
+	/// Version of the specification the runtime implements.
+	pub spec_version: u32,
+	/// Version of this implementation of the specification.
+	pub impl_version: u32,
+}
+
+impl Encode for RuntimeVersion {
+	fn encode_to(&self, dest: &mut Vec<u8>) {
+		self.spec_version.encode_to(dest);
+		self.impl_version.encode_to(dest);
+	}
+}
+
+impl Decode for RuntimeVersion {
+	fn decode(input: &mut &[u8]) -> Option<Self> {
+		Some(RuntimeVersion {
+			spec_version: u32::decode(input)?,
+			impl_version: u32::decode(input)?,
+		})
+	}
+}
+
+/// An event emitted by the runtime during a call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+	/// Topic the event is filed under.
+	pub topic: Vec<u8>,
+	/// Payload of the event.
+	pub data: Vec<u8>,
+}
+
+impl Encode for Event {
+	fn encode_to(&self, dest: &mut Vec<u8>) {
+		self.topic.encode_to(dest);
+		self.data.encode_to(dest);
+	}
+}
+
+impl Decode for Event {
+	fn decode(input: &mut &[u8]) -> Option<Self> {
+		Some(Event {
+			topic: Vec::decode(input)?,
+			data: Vec::decode(input)?,
+		})
+	}
+}
+
+/// Everything a successful call produced: the canonical record of its execution.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecutionOutcome {
+	/// Output of the called method.
+	pub output: Vec<u8>,
+	/// Changes committed by the call in key order; `None` is a deletion.
+	pub changes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+	/// Events emitted during the call.
+	pub events: Vec<Event>,
+	/// Root of the state after the call.
+	pub storage_root: [u8; 32],
+}
+
+impl Encode for ExecutionOutcome {
+	fn encode_to(&self, dest: &mut Vec<u8>) {
+		self.output.encode_to(dest);
+		(self.changes.len() as u32).encode_to(dest);
+		for &(ref key, ref value) in &self.changes {
+			key.encode_to(dest);
+			match *value {
+				Some(ref value) => {
+					dest.push(1);
+					value.encode_to(dest);
+				}
+				None => dest.push(0),
+			}
+		}
+		(self.events.len() as u32).encode_to(dest);
+		for event in &self.events {
+			event.encode_to(dest);
+		}
+		self.storage_root.encode_to(dest);
+	}
+}
+
+impl Decode for ExecutionOutcome {
+	fn decode(input: &mut &[u8]) -> Option<Self> {
+		let output = Vec::decode(input)?;
+
+		let count = u32::decode(input)?;
+		let mut changes = Vec::new();
+		for _ in 0..count {
+			let key = Vec::decode(input)?;
+			let (&tag, rest) = input.split_first()?;
+			*input = rest;
+			let value = match tag {
+				0 => None,
+				1 => Some(Vec::decode(input)?),
+				_ => return None,
+			};
+			changes.push((key, value));
+		}
+
+		let count = u32::decode(input)?;
+		let mut events = Vec::new();
+		for _ in 0..count {
+			events.push(Event::decode(input)?);
+		}
+
+		Some(ExecutionOutcome {
+			output,
+			changes,
+			events,
+			storage_root: Decode::decode(input)?,
+		})
+	}
+}
+
+/// The runtime's `version` export returned something which isn't a `RuntimeVersion`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidVersion;
+
+impl fmt::Display for InvalidVersion {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Runtime returned an invalid version")
+	}
+}
+
+/// A storage item which must exist was absent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissingStorage(pub Vec<u8>);
+
+impl fmt::Display for MissingStorage {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Missing storage item: {:?}", self.0)
+	}
+}
+
+/// A rule enforced by the externalities which a call broke.
+///
+/// The offending operation is dropped and the call fails once it returns.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+	/// The call tried to change the code or the upgrade key without an authorized origin.
+	UnauthorizedUpgrade,
+	/// The call tried to write a key protected by the access control list.
+	UnauthorizedWrite(Vec<u8>),
+	/// The call touched more distinct keys than the given limit.
+	TooManyKeys(usize),
+	/// The call tried to write a key while running read-only.
+	ReadOnlyWrite(Vec<u8>),
+	/// The call tried to write more value bytes than its storage quota.
+	QuotaExceeded(u64),
+}
+
+impl fmt::Display for Violation {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Violation::UnauthorizedUpgrade => write!(f, "Code change by an unauthorized origin"),
+			Violation::UnauthorizedWrite(ref key) => write!(f, "Write to protected key {:?} by an unauthorized origin", key),
+			Violation::TooManyKeys(limit) => write!(f, "More than {} distinct keys touched", limit),
+			Violation::ReadOnlyWrite(ref key) => write!(f, "Write to key {:?} in a read-only call", key),
+			Violation::QuotaExceeded(quota) => write!(f, "More than {} bytes written to storage", quota),
+		}
+	}
+}
+
+/// State Machine Error bound.
+///
+/// This should reflect WASM error type bound for future compatibility.
+pub trait Error: 'static + fmt::Debug + fmt::Display + Send {}
+impl<E> Error for E where E: 'static + fmt::Debug + fmt::Display + Send {}
+
+/// The overlayed changes were built against a different backend than the one
+/// being executed on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackendMismatch {
+	/// Stamp the overlay was built against.
+	pub expected: u64,
+	/// Stamp of the backend actually provided.
+	pub found: Option<u64>,
+}
+
+impl fmt::Display for BackendMismatch {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self.found {
+			Some(found) => write!(f, "Overlay built for backend {} used with backend {}", self.expected, found),
+			None => write!(f, "Overlay built for backend {} used with unstamped backend", self.expected),
+		}
+	}
+}
+
+/// A backend answered a batched read with a different number of values than
+/// keys asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchMismatch {
+	/// Number of keys read.
+	pub requested: usize,
+	/// Number of values returned.
+	pub returned: usize,
+}
+
+impl fmt::Display for BatchMismatch {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Backend returned {} values for a batch of {} keys", self.returned, self.requested)
+	}
+}
+
+// `prefix` followed by the encoding of `value`, e.g. the key of a validator.
+fn value_vec(value: usize, prefix: Vec<u8>) -> Vec<u8> {
+	let mut key = prefix;
+	codec::encode_usize(value, &mut key);
+	key
+}
+
+/// Externalities: pinned to specific active address.
+pub trait Externalities {
+	/// Externalities error type.
+	type Error: Error;
+
+	/// Read storage of current contract being called.
+	fn storage(&self, key: &[u8]) -> Result<&[u8], Self::Error>;
+
+	/// Set storage of current contract being called (effective immediately).
+	fn set_storage(&mut self, key: Vec<u8>, value: Vec<u8>);
+
+	/// Set the storage of many keys at once, in order.
+	fn set_storages<I>(&mut self, values: I)
+		where I: IntoIterator<Item=(Vec<u8>, Vec<u8>)>
+	{
+		for (key, value) in values {
+			self.set_storage(key, value);
+		}
+	}
+
+	/// Root of the trie holding the current state, changes made so far included.
+	fn storage_root(&self) -> Result<[u8; 32], Self::Error>;
+
+	/// Delete every key starting with `prefix`, whether changed in this call or not.
+	fn clear_prefix(&mut self, prefix: &[u8]) -> Result<(), Self::Error>;
+
+	/// Get the smallest key holding a value which is strictly greater than `key`.
+	fn next_storage_key(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error>;
+
+	/// Get every key holding a value which starts with `prefix`, ordered by `cmp`.
+	///
+	/// `|a, b| a.cmp(b)` gives byte order. By default keys are found by walking
+	/// `next_storage_key` from `prefix`.
+	fn storage_keys_with_prefix_by<F>(&self, prefix: &[u8], mut cmp: F) -> Result<Vec<Vec<u8>>, Self::Error>
+		where F: FnMut(&[u8], &[u8]) -> Ordering
+	{
+		let mut keys = Vec::new();
+		if !self.storage(prefix)?.is_empty() {
+			keys.push(prefix.to_vec());
+		}
+		let mut cursor = prefix.to_vec();
+		while let Some(key) = self.next_storage_key(&cursor)? {
+			if !key.starts_with(prefix) {
+				break;
+			}
+			keys.push(key.clone());
+			cursor = key;
+		}
+
+		keys.sort_by(|a, b| cmp(&a[..], &b[..]));
+		Ok(keys)
+	}
+
+	/// Get the key holding a value which comes first after `key` as ordered by `cmp`.
+	///
+	/// Unless `cmp` is byte order every key has to be visited; prefer
+	/// `next_storage_key` for byte order.
+	fn next_storage_key_by<F>(&self, key: &[u8], mut cmp: F) -> Result<Option<Vec<u8>>, Self::Error>
+		where F: FnMut(&[u8], &[u8]) -> Ordering
+	{
+		let mut next: Option<Vec<u8>> = None;
+		for candidate in self.storage_keys_with_prefix_by(&[], |a, b| a.cmp(b))? {
+			if cmp(&candidate, key) != Ordering::Greater {
+				continue;
+			}
+			let closer = match next {
+				Some(ref n) => cmp(&candidate, n) == Ordering::Less,
+				None => true,
+			};
+			if closer {
+				next = Some(candidate);
+			}
+		}
+		Ok(next)
+	}
+
+	/// Read the child storage under `storage_key`; empty if absent.
+	///
+	/// Each child storage is a key space of its own, e.g. one per contract.
+	fn child_storage(&self, storage_key: &[u8], key: &[u8]) -> Result<&[u8], Self::Error>;
+
+	/// Set the child storage under `storage_key` (effective immediately).
+	fn set_child_storage(&mut self, storage_key: Vec<u8>, key: Vec<u8>, value: Vec<u8>);
+
+	/// Root of the trie holding the child storage under `storage_key`.
+	///
+	/// The top-level `storage_root` commits to it under the reserved item
+	/// `child_storage:` followed by `storage_key`.
+	fn child_storage_root(&self, storage_key: &[u8]) -> Result<[u8; 32], Self::Error>;
+
+	/// Storage key and current root of every child storage changed so far, in key order.
+	///
+	/// Externalities without child storage have none.
+	fn touched_child_roots(&self) -> Result<Vec<(Vec<u8>, [u8; 32])>, Self::Error> {
+		Ok(Vec::new())
+	}
+
+	/// Get the value stored under `key`, failing with `MissingStorage` if absent or deleted.
+	fn storage_required(&self, key: &[u8]) -> Result<Vec<u8>, Self::Error>
+		where Self::Error: From<MissingStorage>
+	{
+		let value = self.storage(key)?;
+		if value.is_empty() {
+			Err(MissingStorage(key.to_vec()).into())
+		} else {
+			Ok(value.to_vec())
+		}
+	}
+
+	/// Get the length of the value stored under `key`, or `None` if absent.
+	fn storage_len(&self, key: &[u8]) -> Result<Option<usize>, Self::Error> {
+		self.storage(key).map(|v| if v.is_empty() { None } else { Some(v.len()) })
+	}
+
+	/// Whether a non-empty value is stored under `key`.
+	fn exists_storage(&self, key: &[u8]) -> Result<bool, Self::Error> {
+		self.storage(key).map(|v| !v.is_empty())
+	}
+
+	/// Get the value `key` held at the start of the current call, `None` if absent.
+	///
+	/// By default writes made during the call aren't tracked, so this is the current value.
+	fn original_storage(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+		self.storage(key).map(|v| if v.is_empty() { None } else { Some(v.to_vec()) })
+	}
+
+	/// Read storage, initializing it with the value produced by `f` if it is absent.
+	///
+	/// `f` is only called when there is no existing value.
+	fn storage_or_insert_with<F>(&mut self, key: &[u8], f: F) -> Result<Vec<u8>, Self::Error>
+		where F: FnOnce() -> Vec<u8>
+	{
+		let existing = self.storage(key)?.to_vec();
+		if !existing.is_empty() {
+			return Ok(existing);
+		}
+
+		let value = f();
+		self.set_storage(key.to_vec(), value.clone());
+		Ok(value)
+	}
+
+	/// Storage key of the reserved item with the given name, e.g. `code`.
+	///
+	/// Reserved items live under a sentinel prefix byte, `\0` by default.
+	fn reserved_key(&self, name: &[u8]) -> Vec<u8> {
+		let mut key = vec![0];
+		key.extend_from_slice(name);
+		key
+	}
+
+	/// Hash the given data with the given algorithm.
+	fn hash(&self, algo: HashAlgo, data: &[u8]) -> Vec<u8> {
+		algo.hash(data)
+	}
+
+	/// A number which changes whenever committed state changes.
+	///
+	/// Externalities which don't track this always return 0.
+	fn storage_generation(&self) -> u64 { 0 }
+
+	/// Get the current set of validators.
+	fn validators(&self) -> Result<Vec<&[u8]>, Self::Error> {
+		read_validators(self)
+	}
+
+	/// Get `len` validators starting at index `start`, clamped to the current set.
+	///
+	/// Only the requested entries are read.
+	fn validators_range(&self, start: usize, len: usize) -> Result<Vec<&[u8]>, Self::Error> {
+		let end = ::std::cmp::min(read_validator_count(self)?, start.saturating_add(len));
+		(start..::std::cmp::max(start, end))
+			.map(|i| self.storage(&value_vec(i, self.reserved_key(b"validator"))))
+			.collect()
+	}
+
+	/// Number of bytes which may still be written to storage during this call.
+	///
+	/// Externalities without a quota always return `u64::max_value()`.
+	fn storage_quota_left(&self) -> u64 { u64::max_value() }
+
+	/// Add `delta` to the little-endian `u64` stored under `key`, returning the previous value.
+	///
+	/// Absent or malformed values count as 0, and the addition saturates.
+	fn fetch_add_u64(&mut self, key: &[u8], delta: u64) -> Result<u64, Self::Error> {
+		let old = u64::decode(&mut self.storage(key)?).unwrap_or(0);
+		self.set_storage(key.to_vec(), old.saturating_add(delta).encode());
+		Ok(old)
+	}
+
+	/// Delete `key` if its current value is `expected`, returning whether it was deleted.
+	///
+	/// An absent key is never deleted.
+	fn delete_if(&mut self, key: &[u8], expected: &[u8]) -> Result<bool, Self::Error> {
+		let matches = {
+			let current = self.storage(key)?;
+			!current.is_empty() && current == expected
+		};
+		if matches {
+			self.set_storage(key.to_vec(), Vec::new());
+		}
+		Ok(matches)
+	}
+
+	/// Push `value` into the ring of `capacity` slots under `base_key`, overwriting the oldest once full.
+	///
+	/// `base_key` holds the number of pushes so far as a little-endian `u64`; the value
+	/// of push `n` is stored under `base_key` followed by `n % capacity` as a
+	/// little-endian `u32`. A ring with no capacity holds nothing.
+	fn push_ring(&mut self, base_key: &[u8], value: Vec<u8>, capacity: usize) -> Result<(), Self::Error> {
+		if capacity == 0 {
+			return Ok(());
+		}
+
+		let head = self.fetch_add_u64(base_key, 1)?;
+		let mut slot = base_key.to_vec();
+		((head % capacity as u64) as u32).encode_to(&mut slot);
+		self.set_storage(slot, value);
+		Ok(())
+	}
+
+	/// Hash of the genesis block of the chain being executed, if known.
+	fn genesis_hash(&self) -> Option<[u8; 32]> { None }
+
+	/// Timestamp of the block being executed, or 0 if unknown.
+	///
+	/// This is fixed for the duration of a call.
+	fn timestamp(&self) -> u64 { 0 }
+
+	/// Get `len` bytes of deterministic randomness.
+	///
+	/// Bytes are drawn from a `Drbg` seeded from the storage root, so repeated calls
+	/// give distinct bytes and the same calls over the same state give the same bytes.
+	fn random(&mut self, len: usize) -> Vec<u8>;
+
+	/// Get the current code.
+	fn code(&self) -> Result<&[u8], Self::Error> {
+		self.storage(&self.reserved_key(b"code"))
+	}
+
+	/// Change the code.
+	fn set_code(&mut self, code: Vec<u8>) {
+		let key = self.reserved_key(b"code");
+		self.set_storage(key, code);
+	}
+
+	/// Keccak-256 hash of the current code.
+	fn code_hash(&self) -> Result<[u8; 32], Self::Error> {
+		Ok(keccak_hash::keccak(self.code()?).0)
+	}
+
+	/// Whether the given origin may change the code, or the upgrade key itself.
+	///
+	/// The authorized origin is declared under the reserved `upgrade_key` item;
+	/// while none is declared, no origin is authorized.
+	fn upgrade_authorized(&self, origin: &[u8]) -> Result<bool, Self::Error> {
+		let authorized = self.storage(&self.reserved_key(b"upgrade_key"))?;
+		Ok(!authorized.is_empty() && authorized == origin)
+	}
+
+	/// Emit an event with the given topic and payload.
+	///
+	/// Externalities which don't collect events drop them.
+	fn emit_event(&mut self, _topic: Vec<u8>, _data: Vec<u8>) {}
+
+	/// Get the current fee parameters, or the default if none are stored.
+	fn fee_params(&self) -> Result<FeeParams, Self::Error> {
+		let encoded = self.storage(&self.reserved_key(b"fees"))?;
+		Ok(FeeParams::decode(&mut &encoded[..]).unwrap_or_default())
+	}
+}
+
+/// Upper bound on the number of validators read from storage.
+///
+/// Guards against a corrupt `\0validator_count` causing an unbounded number of reads.
+pub const MAX_VALIDATORS: usize = 1 << 16;
+
+// read the stored validator count, bounded by `MAX_VALIDATORS`.
+fn read_validator_count<E: Externalities + ?Sized>(ext: &E) -> Result<usize, E::Error> {
+	let count = codec::decode_usize(ext.storage(&ext.reserved_key(b"validator_count"))?);
+	Ok(::std::cmp::min(count, MAX_VALIDATORS))
+}
+
+// read the validator set entry by entry from storage, failing on the first bad read.
+fn read_validators<E: Externalities + ?Sized>(ext: &E) -> Result<Vec<&[u8]>, E::Error> {
+	(0..read_validator_count(ext)?)
+		.map(|i| ext.storage(&value_vec(i, ext.reserved_key(b"validator"))))
+		.collect()
+}
+
+/// Code execution engine.
+pub trait CodeExecutor: Sized {
+	/// Externalities error type.
+	type Error: Error;
+
+	/// Call a given method in the runtime.
+	fn call<E: Externalities>(
+		&self,
+		ext: &mut E,
+		code: &[u8],
+		method: &str,
+		data: &CallData,
+	) -> Result<Vec<u8>, Self::Error>;
+
+	/// Call a given method in the runtime, using up at most `gas`.
+	///
+	/// `gas` is left holding what remains. Executors which can't meter
+	/// execution run the call unbounded, leaving `gas` untouched.
+	fn call_with_gas<E: Externalities>(
+		&self,
+		ext: &mut E,
+		code: &[u8],
+		method: &str,
+		data: &CallData,
+		_gas: &mut u64,
+	) -> Result<Vec<u8>, Self::Error> {
+		self.call(ext, code, method, data)
+	}
+
+	/// Get the version of the given runtime code by calling its `version` export.
+	fn runtime_version<E: Externalities>(
+		&self,
+		ext: &mut E,
+		code: &[u8],
+	) -> Result<RuntimeVersion, Self::Error>
+		where Self::Error: From<InvalidVersion>
+	{
+		let encoded = self.call(ext, code, "version", &CallData(Vec::new()))?;
+		RuntimeVersion::decode(&mut &encoded[..]).ok_or_else(|| InvalidVersion.into())
+	}
+}
+
+/// Configuration of the externalities a call is executed with.
+#[derive(Debug, Default, Clone)]
+pub struct ExecutionConfig {
+	/// Sentinel byte prefixing reserved keys such as code and validators.
+	pub reserved_prefix: u8,
+	/// Whether to record `Diagnostics` during the call.
+	pub record_diagnostics: bool,
+	/// Whether to warn, through `Diagnostics`, about keys written more than once.
+	pub warn_on_duplicate_write: bool,
+	/// Whether events emitted by a failed call are returned rather than discarded.
+	pub keep_events_on_error: bool,
+	/// Number of value bytes the call may write, if limited.
+	///
+	/// A write which would take the call past its quota is refused, failing
+	/// the call with `Violation::QuotaExceeded`.
+	pub storage_quota: Option<u64>,
+	/// Origin of the call; when set, only an authorized origin may change the code or the upgrade key.
+	pub origin: Option<Vec<u8>>,
+	/// Hash of the genesis block of the chain; fixed for the lifetime of the chain.
+	pub genesis_hash: Option<[u8; 32]>,
+	/// Timestamp of the block the call is executed in.
+	pub timestamp: Option<u64>,
+	/// Whether the call may only read state; any write is a violation.
+	pub read_only: bool,
+	/// Gas the call may use, if limited; see `CodeExecutor::call_with_gas`.
+	///
+	/// Gas is only charged per host call, so this does not bound runtime code
+	/// which loops without calling out to the host.
+	pub gas_limit: Option<u64>,
+	/// Prefix applied to every key the call reads or writes, code and validators included.
+	///
+	/// Lets several chains share one backend. The call sees keys without the
+	/// prefix, and its `storage_root` only covers keys under it.
+	pub key_namespace: Option<Vec<u8>>,
+	/// Most distinct keys the call may read or write, if limited.
+	///
+	/// Reading the code counts. Writes past the limit are dropped.
+	pub max_touched_keys: Option<usize>,
+	/// Origins allowed to write keys under each protected prefix.
+	///
+	/// A key under several protected prefixes may only be written by an origin
+	/// allowed for all of them. Keys under no protected prefix are always writable.
+	pub acl: HashMap<Vec<u8>, HashSet<Vec<u8>>>,
+}
+
+/// Information gathered about a single call, whatever its outcome.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Diagnostics {
+	/// Keys read during the call, in order.
+	pub reads: Vec<Vec<u8>>,
+	/// Keys written during the call, in order.
+	pub writes: Vec<Vec<u8>>,
+	/// Keys written more than once during the call, in order of their second write.
+	///
+	/// Only recorded when `warn_on_duplicate_write` is set.
+	pub duplicate_writes: Vec<Vec<u8>>,
+}
+
+/// Execute a call using the given state backend, overlayed changes, and call executor.
+///
+/// On an error, no prospective changes are written to the overlay.
+///
+/// Note: changes to code will be in place if this call is made again. For running partial
+/// blocks (e.g. a transaction at a time), use `execute_block`.
+///
+/// A backend read failing during the call fails it with `ExecutionError::Backend`,
+/// whatever the executor made of the failure.
+pub fn execute<B: backend::Backend, Exec: CodeExecutor>(
+	backend: &B,
+	overlay: &mut OverlayedChanges,
+	exec: &Exec,
+	method: &str,
+	call_data: &CallData,
+) -> Result<Vec<u8>, ExecutionError<B::Error, Exec::Error>> {
+	execute_typed(backend, overlay, exec, method, call_data, ExecutionConfig::default(), Hooks::default()).0
+}
+
+/// Like `execute`, but with the error boxed.
+///
+/// Kept for callers of the former signature of `execute`; will be removed in
+/// the next release.
+pub fn execute_boxed<B: backend::Backend, Exec: CodeExecutor>(
+	backend: &B,
+	overlay: &mut OverlayedChanges,
+	exec: &Exec,
+	method: &str,
+	call_data: &CallData,
+) -> Result<Vec<u8>, Box<Error>> {
+	execute_with_config(backend, overlay, exec, method, call_data, ExecutionConfig::default())
+}
+
+/// Execute the calls of a block in order, on one overlay, returning their outputs.
+///
+/// The changes of each call are committed before the next runs, so a call sees
+/// everything the calls before it did, code changes included. The first failing
+/// call aborts the block: its changes are discarded and the calls after it aren't
+/// run, while the changes of the calls before it stay committed in the overlay.
+pub fn execute_block<B: backend::Backend, Exec: CodeExecutor>(
+	backend: &B,
+	overlay: &mut OverlayedChanges,
+	exec: &Exec,
+	calls: &[(String, CallData)],
+) -> Result<Vec<Vec<u8>>, ExecutionError<B::Error, Exec::Error>> {
+	calls.iter()
+		.map(|&(ref method, ref call_data)| execute(backend, overlay, exec, method, call_data))
+		.collect()
+}
+
+/// Execute a call and flush the overlay's committed changes into the given state db.
+///
+/// On success the committed layer of the overlay is emptied, as its contents
+/// now live in the db. Child storage changes are written to the db's child
+/// storage, and each changed child's new root to the top-level state under
+/// the reserved item `child_storage:` followed by its storage key. On error
+/// nothing is written.
+pub fn execute_and_persist<S: backend::StateDb, Exec: CodeExecutor>(
+	state_db: &mut S,
+	overlay: &mut OverlayedChanges,
+	exec: &Exec,
+	method: &str,
+	call_data: &CallData,
+) -> Result<Vec<u8>, Box<Error>> {
+	let config = ExecutionConfig::default();
+	let out = execute_with_config(&*state_db, overlay, exec, method, call_data, config.clone())?;
+	let to_boxed = |e: S::Error| Box::new(e) as Box<Error>;
+
+	let children = overlay.drain_committed_children();
+	let mut changes = overlay.drain_committed();
+	let empty_root = backend::storage_root(Vec::new());
+	for &(ref storage_key, ref child) in &children {
+		let key = match ext::physical_child_root_key(&config, storage_key) {
+			Some(key) => key,
+			None => continue,
+		};
+		let mut state: HashMap<_, _> = state_db.child_pairs(storage_key).map_err(&to_boxed)?.into_iter().collect();
+		state.extend(child.iter().map(|&(ref key, ref value)| (key.clone(), value.clone().unwrap_or_default())));
+		let root = backend::storage_root(state);
+
+		changes.retain(|&(ref k, _)| k != &key);
+		changes.push((key, if root == empty_root { None } else { Some(root.to_vec()) }));
+	}
+	changes.sort();
+
+	for &(ref storage_key, ref child) in &children {
+		backend::StateDb::commit_child(state_db, storage_key, child).map_err(&to_boxed)?;
+	}
+	backend::StateDb::commit(state_db, &changes).map_err(&to_boxed)?;
+	Ok(out)
+}
+
+/// Like `execute`, but with the externalities configured by `config`.
+pub fn execute_with_config<B: backend::Backend, Exec: CodeExecutor>(
+	backend: &B,
+	overlay: &mut OverlayedChanges,
+	exec: &Exec,
+	method: &str,
+	call_data: &CallData,
+	config: ExecutionConfig,
+) -> Result<Vec<u8>, Box<Error>> {
+	execute_inner(backend, overlay, exec, method, call_data, config, Hooks::default()).0
+}
+
+/// Like `execute_with_config`, but also returns the events emitted during the call.
+///
+/// The events of a failed call are discarded unless `config.keep_events_on_error` is set.
+pub fn execute_with_events<B: backend::Backend, Exec: CodeExecutor>(
+	backend: &B,
+	overlay: &mut OverlayedChanges,
+	exec: &Exec,
+	method: &str,
+	call_data: &CallData,
+	config: ExecutionConfig,
+) -> (Result<Vec<u8>, Box<Error>>, Vec<Event>) {
+	let (result, _, events) = execute_inner(backend, overlay, exec, method, call_data, config, Hooks::default());
+	(result, events)
+}
+
+/// Like `execute`, but also returns the changes the call committed, in key order.
+///
+/// A value of `None` is a deletion. A failed call commits nothing.
+pub fn execute_with_delta<B: backend::Backend, Exec: CodeExecutor>(
+	backend: &B,
+	overlay: &mut OverlayedChanges,
+	exec: &Exec,
+	method: &str,
+	call_data: &CallData,
+) -> Result<(Vec<u8>, Vec<(Vec<u8>, Option<Vec<u8>>)>), Box<Error>> {
+	let mut delta = Vec::new();
+	let (result, _, _) = {
+		let hooks = Hooks { delta: Some(&mut delta), ..Default::default() };
+		execute_inner(backend, overlay, exec, method, call_data, ExecutionConfig::default(), hooks)
+	};
+	result.map(|output| (output, delta))
+}
+
+/// Like `execute`, but also applies the changes the call committed to `shadow`.
+///
+/// Deleted keys are removed from `shadow`. A failed call leaves it untouched.
+pub fn execute_with_shadow<B: backend::Backend, Exec: CodeExecutor>(
+	backend: &B,
+	overlay: &mut OverlayedChanges,
+	exec: &Exec,
+	method: &str,
+	call_data: &CallData,
+	shadow: &mut HashMap<Vec<u8>, Vec<u8>>,
+) -> Result<Vec<u8>, Box<Error>> {
+	let (output, delta) = execute_with_delta(backend, overlay, exec, method, call_data)?;
+	for (key, value) in delta {
+		match value {
+			Some(value) => shadow.insert(key, value),
+			None => shadow.remove(&key),
+		};
+	}
+	Ok(output)
+}
+
+/// Like `execute_with_config`, but returns everything the call produced as an `ExecutionOutcome`.
+pub fn execute_full<B: backend::Backend, Exec: CodeExecutor>(
+	backend: &B,
+	overlay: &mut OverlayedChanges,
+	exec: &Exec,
+	method: &str,
+	call_data: &CallData,
+	config: ExecutionConfig,
+) -> Result<ExecutionOutcome, Box<Error>> {
+	let mut changes = Vec::new();
+	let (result, _, events) = {
+		let hooks = Hooks { delta: Some(&mut changes), ..Default::default() };
+		execute_inner(backend, overlay, exec, method, call_data, config, hooks)
+	};
+	let output = result?;
+	let storage_root = ext::Ext::new(overlay, backend).storage_root()
+		.map_err(|e| Box::new(e) as Box<Error>)?;
+
+	Ok(ExecutionOutcome {
+		output,
+		changes,
+		events,
+		storage_root,
+	})
+}
+
+/// Run a call against a throwaway overlay, failing it if it tries to write anything.
+///
+/// Useful to read state through the runtime, e.g. for RPC, without any risk
+/// of the call's changes being persisted.
+pub fn execute_read_only<B: backend::Backend, Exec: CodeExecutor>(
+	backend: &B,
+	exec: &Exec,
+	method: &str,
+	call_data: &CallData,
+) -> Result<Vec<u8>, Box<Error>> {
+	let mut overlay = OverlayedChanges::default();
+	let config = ExecutionConfig { read_only: true, ..Default::default() };
+	execute_with_config(backend, &mut overlay, exec, method, call_data, config)
+}
+
+/// Like `execute`, but invokes `on_yield` after every `every` storage operations.
+///
+/// This lets a caller cooperatively hand control back to an async runtime
+/// while executing long blocks. It does not change the outcome of the call.
+pub fn execute_with_yield<B: backend::Backend, Exec: CodeExecutor, F: FnMut()>(
+	backend: &B,
+	overlay: &mut OverlayedChanges,
+	exec: &Exec,
+	method: &str,
+	call_data: &CallData,
+	every: u32,
+	mut on_yield: F,
+) -> Result<Vec<u8>, Box<Error>> {
+	let on_yield: &mut FnMut() = &mut on_yield;
+	let hooks = Hooks { on_yield: Some((every, on_yield)), ..Default::default() };
+	execute_inner(backend, overlay, exec, method, call_data, ExecutionConfig::default(), hooks).0
+}
+
+/// Like `execute`, but also returns diagnostics collected during the call.
+///
+/// The diagnostics are returned even when the call fails, which helps with
+/// investigating failed blocks. Prospective changes are still discarded on error.
+pub fn execute_preserving<B: backend::Backend, Exec: CodeExecutor>(
+	backend: &B,
+	overlay: &mut OverlayedChanges,
+	exec: &Exec,
+	method: &str,
+	call_data: &CallData,
+) -> (Result<Vec<u8>, Box<Error>>, Diagnostics) {
+	let config = ExecutionConfig { record_diagnostics: true, ..Default::default() };
+	let (result, diagnostics, _) = execute_inner(backend, overlay, exec, method, call_data, config, Hooks::default());
+	(result, diagnostics)
+}
+
+// call-scoped hooks into the execution, which can't live in `ExecutionConfig`.
+#[derive(Default)]
+struct Hooks<'a> {
+	on_yield: Option<(u32, &'a mut FnMut())>,
+	// receives the changes the call commits, if it succeeds.
+	delta: Option<&'a mut Vec<(Vec<u8>, Option<Vec<u8>>)>>,
+}
+
+// wraps the backend of a call, keeping the first error it gives so the call
+// fails with it whatever the executor makes of the failure.
+struct CaptureErrors<'b, B: 'b + backend::Backend> {
+	inner: &'b B,
+	error: RefCell<Option<B::Error>>,
+}
+
+// stands in for a backend error, which is kept by `CaptureErrors`.
+#[derive(Debug)]
+struct BackendFailure;
+
+impl fmt::Display for BackendFailure {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Storage backend failure")
+	}
+}
+
+impl<'b, B: 'b + backend::Backend> CaptureErrors<'b, B> {
+	fn capture(&self, error: B::Error) -> BackendFailure {
+		let mut first = self.error.borrow_mut();
+		if first.is_none() {
+			*first = Some(error);
+		}
+		BackendFailure
+	}
+}
+
+impl<'b, B: 'b + backend::Backend> backend::Backend for CaptureErrors<'b, B> {
+	type Error = BackendFailure;
+
+	fn storage(&self, key: &[u8]) -> Result<&[u8], BackendFailure> {
+		self.inner.storage(key).map_err(|e| self.capture(e))
+	}
+
+	fn storage_batch(&self, keys: &[Vec<u8>]) -> Result<Vec<&[u8]>, BackendFailure> {
+		self.inner.storage_batch(keys).map_err(|e| self.capture(e))
+	}
+
+	fn storage_len(&self, key: &[u8]) -> Result<Option<usize>, BackendFailure> {
+		self.inner.storage_len(key).map_err(|e| self.capture(e))
+	}
+
+	fn pairs(&self) -> Result<Vec<(Vec<u8>, Vec<u8>)>, BackendFailure> {
+		self.inner.pairs().map_err(|e| self.capture(e))
+	}
+
+	fn keys_with_prefix(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, BackendFailure> {
+		self.inner.keys_with_prefix(prefix).map_err(|e| self.capture(e))
+	}
+
+	fn next_key(&self, key: &[u8]) -> Result<Option<Vec<u8>>, BackendFailure> {
+		self.inner.next_key(key).map_err(|e| self.capture(e))
+	}
+
+	fn storage_root(&self) -> Result<[u8; 32], BackendFailure> {
+		self.inner.storage_root().map_err(|e| self.capture(e))
+	}
+
+	fn child_storage(&self, storage_key: &[u8], key: &[u8]) -> Result<&[u8], BackendFailure> {
+		self.inner.child_storage(storage_key, key).map_err(|e| self.capture(e))
+	}
+
+	fn child_pairs(&self, storage_key: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, BackendFailure> {
+		self.inner.child_pairs(storage_key).map_err(|e| self.capture(e))
+	}
+
+	fn stamp(&self) -> Option<u64> {
+		self.inner.stamp()
+	}
+
+	fn commit<I>(&mut self, _changes: I) -> backend::Committed
+		where I: IntoIterator<Item=Update>
+	{
+		unreachable!("calls never commit to their backend")
+	}
+}
+
+fn execute_inner<B: backend::Backend, Exec: CodeExecutor>(
+	backend: &B,
+	overlay: &mut OverlayedChanges,
+	exec: &Exec,
+	method: &str,
+	call_data: &CallData,
+	config: ExecutionConfig,
+	hooks: Hooks,
+) -> (Result<Vec<u8>, Box<Error>>, Diagnostics, Vec<Event>) {
+	let (result, diagnostics, events) = execute_typed(backend, overlay, exec, method, call_data, config, hooks);
+	(result.map_err(ext::Error::into_boxed), diagnostics, events)
+}
+
+fn execute_typed<B: backend::Backend, Exec: CodeExecutor>(
+	backend: &B,
+	overlay: &mut OverlayedChanges,
+	exec: &Exec,
+	method: &str,
+	call_data: &CallData,
+	config: ExecutionConfig,
+	hooks: Hooks,
+) -> (Result<Vec<u8>, ExecutionError<B::Error, Exec::Error>>, Diagnostics, Vec<Event>) {
+	if let Some(expected) = overlay.backend_stamp {
+		let found = backend.stamp();
+		if found != Some(expected) {
+			return (Err(ext::Error::BackendMismatch(BackendMismatch { expected, found })), Diagnostics::default(), Vec::new());
+		}
+	}
+
+	let backend = CaptureErrors { inner: backend, error: RefCell::new(None) };
+	let keep_events_on_error = config.keep_events_on_error;
+	let gas_limit = config.gas_limit;
+	let (result, diagnostics, mut events, violation, batch_mismatch) = {
+		let mut externalities = ext::Ext::with_config(&mut *overlay, &backend, config);
+		if let Some((every, on_yield)) = hooks.on_yield {
+			externalities.yield_every(every, on_yield);
+		}
+
+		let code = externalities.code_for_call().unwrap_or_default();
+
+		let result = match gas_limit {
+			Some(mut gas) => exec.call_with_gas(&mut externalities, &code, method, call_data, &mut gas),
+			None => exec.call(
+				&mut externalities,
+				&code,
+				method,
+				call_data,
+			),
+		};
+
+		let batch_mismatch = externalities.take_batch_mismatch();
+		(result, externalities.take_diagnostics(), externalities.take_events(), externalities.take_violation(), batch_mismatch)
+	};
+
+	let error = match (result, backend.error.into_inner(), batch_mismatch, violation) {
+		(_, Some(e), _, _) => ext::Error::Backend(e),
+		(_, None, Some(mismatch), _) => ext::Error::BatchMismatch(mismatch),
+		(_, None, None, Some(violation)) => ext::Error::Violation(violation),
+		(Ok(out), None, None, None) => {
+			if let Some(delta) = hooks.delta {
+				*delta = overlay.prospective_changes();
+			}
+			overlay.commit_prospective();
+			return (Ok(out), diagnostics, events);
+		}
+		(Err(e), None, None, None) => ext::Error::Executor(e),
+	};
+
+	overlay.discard_prospective();
+	if !keep_events_on_error {
+		events.clear();
+	}
+	(Err(error), diagnostics, events)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::borrow::Cow;
+	use std::collections::HashMap;
+	use primitives::contract::CallData;
+	use backend::InMemory;
+	use backend::{Backend, RecordOrder};
+	use primitives::codec::{Decode, Encode};
+	use super::{OverlayedChanges, Externalities, CodeExecutor, ExecutionConfig, HashAlgo, Update};
+	use super::{Drbg, Event, FeeParams, InvalidVersion, MissingStorage, RuntimeVersion, Violation};
+	use super::{execute, execute_boxed, execute_and_persist, execute_with_config, execute_preserving, execute_with_yield};
+	use super::{execute_block, execute_typed, ExecutionError};
+	use super::{execute_read_only, execute_with_delta, execute_with_shadow, execute_with_events, execute_full, execute_inner, ExecutionOutcome, Hooks};
+	use super::MAX_VALIDATORS;
+
+	struct TestExecutor;
+
+	impl From<InvalidVersion> for u8 {
+		fn from(_: InvalidVersion) -> u8 { 2 }
+	}
+
+	impl From<MissingStorage> for u8 {
+		fn from(_: MissingStorage) -> u8 { 3 }
+	}
+
+	impl CodeExecutor for TestExecutor {
+		type Error = u8;
+
+		fn call<E: Externalities>(
+			&self,
+			ext: &mut E,
+			code: &[u8],
+			method: &str,
+			data: &CallData,
+		) -> Result<Vec<u8>, u8> {
+			match method {
+				"set" => {
+					ext.set_storage(b"value".to_vec(), data.0.clone());
+					Ok(Vec::new())
+				}
+				"code" => Ok(code.to_vec()),
+				"version" => Ok(RuntimeVersion { spec_version: 1, impl_version: 7 }.encode()),
+				"many" => {
+					for i in 0..data.0[0] {
+						ext.set_storage(vec![i], vec![1]);
+						let _ = ext.storage(&[i]);
+					}
+					Ok(Vec::new())
+				}
+				"set_code" => {
+					ext.set_code(data.0.clone());
+					Ok(Vec::new())
+				}
+				"take_upgrade" => {
+					ext.set_storage(b"\0upgrade_key".to_vec(), data.0.clone());
+					ext.set_code(b"taken".to_vec());
+					Ok(Vec::new())
+				}
+				"genesis" => Ok(ext.genesis_hash().map_or_else(Vec::new, |h| h.to_vec())),
+				"timestamp" => Ok(ext.timestamp().encode()),
+				"random" => {
+					let mut out = ext.random(data.0[0] as usize);
+					out.extend(ext.random(data.0[0] as usize));
+					Ok(out)
+				}
+				"read" => {
+					for &i in &data.0 {
+						let _ = ext.storage(&[i]);
+					}
+					Ok(Vec::new())
+				}
+				"emit" => {
+					ext.emit_event(b"topic".to_vec(), data.0.clone());
+					ext.emit_event(b"topic".to_vec(), b"second".to_vec());
+					if data.0.is_empty() { Err(1) } else { Ok(Vec::new()) }
+				}
+				"fail" => {
+					ext.set_storage(b"value".to_vec(), data.0.clone());
+					Err(1)
+				}
+				"get" => ext.storage(&data.0).map(|v| v.to_vec()).map_err(|_| 4),
+				"set_twice" => {
+					ext.set_storage(b"value".to_vec(), data.0.clone());
+					ext.set_storage(b"other".to_vec(), data.0.clone());
+					ext.set_storage(b"value".to_vec(), data.0.clone());
+					ext.set_storage(b"value".to_vec(), data.0.clone());
+					Ok(Vec::new())
+				}
+				_ => Err(0),
+			}
+		}
+	}
+
+	#[test]
+	fn overlayed_storage_works() {
+		let mut overlayed = OverlayedChanges::default();
+
+		let key = vec![42, 69, 169, 142];
+
+		assert!(overlayed.storage(&key).is_none());
+
+		overlayed.set_storage(key.clone(), vec![1, 2, 3]);
+		assert_eq!(overlayed.storage(&key).unwrap(), &[1, 2, 3]);
+
+		overlayed.commit_prospective();
+		assert_eq!(overlayed.storage(&key).unwrap(), &[1, 2, 3]);
+
+		overlayed.set_storage(key.clone(), vec![]);
+		assert!(overlayed.storage(&key).is_none());
+
+		overlayed.discard_prospective();
+		assert_eq!(overlayed.storage(&key).unwrap(), &[1, 2, 3]);
+
+		overlayed.set_storage(key.clone(), vec![]);
+		overlayed.commit_prospective();
+		assert!(overlayed.storage(&key).is_none());
+	}
+
+	#[test]
+	fn in_memory_backend_takes_committed_changes() {
+		let mut seed = HashMap::new();
+		seed.insert(b"\0code".to_vec(), b"the code".to_vec());
+		seed.insert(b"seeded".to_vec(), vec![1]);
+		let mut backend = InMemory::from(seed);
+		let mut overlay = OverlayedChanges::default();
+
+		assert!(execute(&backend, &mut overlay, &TestExecutor, "set", &CallData(vec![2])).is_ok());
+		assert!(backend.storage(b"value").unwrap().is_empty());
+
+		let mut updates = Vec::new();
+		overlay.for_each_committed(|k, v| updates.push(Update::Storage(k.to_vec(), v.to_vec())));
+		backend.update(updates);
+
+		assert_eq!(backend.storage(b"value").unwrap(), &[2]);
+		assert_eq!(backend.storage(b"seeded").unwrap(), &[1]);
+	}
+
+	#[test]
+	fn execution_on_parent_sees_its_deletions() {
+		let mut backend = InMemory::default();
+		backend.commit(vec![
+			Update::Storage(b"\0code".to_vec(), b"the code".to_vec()),
+			Update::Storage(b"value".to_vec(), vec![1]),
+		]);
+
+		let mut parent = OverlayedChanges::default();
+		parent.set_storage(b"value".to_vec(), vec![]);
+		parent.set_storage(b"parent".to_vec(), vec![2]);
+
+		let mut overlay = OverlayedChanges::on_parent(&parent);
+		assert_eq!(execute(&backend, &mut overlay, &TestExecutor, "get", &CallData(b"value".to_vec())).unwrap(), Vec::<u8>::new());
+		assert_eq!(execute(&backend, &mut overlay, &TestExecutor, "get", &CallData(b"parent".to_vec())).unwrap(), vec![2]);
+
+		assert!(execute(&backend, &mut overlay, &TestExecutor, "set", &CallData(vec![3])).is_ok());
+		assert_eq!(overlay.storage(b"value").unwrap(), &[3]);
+		assert!(parent.storage(b"value").is_none());
+	}
+
+	#[test]
+	fn bulk_set_storages_works() {
+		let mut overlayed = OverlayedChanges::default();
+		overlayed.set_storage(b"b".to_vec(), vec![9]);
+		overlayed.set_storages(vec![
+			(b"a".to_vec(), vec![1]),
+			(b"b".to_vec(), vec![]),
+			(b"c".to_vec(), vec![3]),
+		]);
+
+		assert_eq!(overlayed.storage(b"a").unwrap(), &[1]);
+		assert!(overlayed.storage(b"b").is_none());
+		assert_eq!(overlayed.storage(b"c").unwrap(), &[3]);
+
+		let mut ext = TestExternalities::default();
+		ext.set_storages(vec![(b"a".to_vec(), vec![1]), (b"b".to_vec(), vec![2])]);
+		assert_eq!(ext.storage(b"b"), Ok(&[2][..]));
+	}
+
+	#[test]
+	fn nested_transactions_roll_back_independently() {
+		let mut overlayed = OverlayedChanges::default();
+		overlayed.set_storage(b"outer".to_vec(), vec![1]);
+
+		overlayed.start_transaction();
+		overlayed.set_storage(b"outer".to_vec(), vec![2]);
+		overlayed.set_storage(b"inner".to_vec(), vec![2]);
+
+		overlayed.start_transaction();
+		overlayed.set_storage(b"innermost".to_vec(), vec![3]);
+		assert_eq!(overlayed.storage(b"outer").unwrap(), &[2]);
+		assert_eq!(overlayed.transaction_depth(), 2);
+
+		overlayed.rollback_transaction();
+		assert!(overlayed.storage(b"innermost").is_none());
+		assert_eq!(overlayed.storage(b"inner").unwrap(), &[2]);
+
+		overlayed.rollback_transaction();
+		assert!(overlayed.storage(b"inner").is_none());
+		assert_eq!(overlayed.storage(b"outer").unwrap(), &[1]);
+
+		overlayed.start_transaction();
+		overlayed.set_storage(b"inner".to_vec(), vec![4]);
+		overlayed.start_transaction();
+		overlayed.set_storage(b"innermost".to_vec(), vec![5]);
+		overlayed.commit_transaction();
+		overlayed.commit_transaction();
+		assert_eq!(overlayed.transaction_depth(), 0);
+
+		overlayed.commit_prospective();
+		assert_eq!(overlayed.storage(b"outer").unwrap(), &[1]);
+		assert_eq!(overlayed.storage(b"inner").unwrap(), &[4]);
+		assert_eq!(overlayed.storage(b"innermost").unwrap(), &[5]);
+	}
+
+	#[test]
+	fn overlayed_code_works() {
+		let mut overlayed = OverlayedChanges::default();
+		let config = ExecutionConfig::default();
+
+		assert!(overlayed.code(&config).is_empty());
+
+		overlayed.set_code(&config, vec![1, 2, 3]);
+		assert_eq!(overlayed.code(&config), &[1, 2, 3]);
+
+		overlayed.commit_prospective();
+		assert_eq!(overlayed.code(&config), &[1, 2, 3]);
+
+		overlayed.set_code(&config, vec![4, 5]);
+		assert_eq!(overlayed.code(&config), &[4, 5]);
+
+		overlayed.discard_prospective();
+		assert_eq!(overlayed.code(&config), &[1, 2, 3]);
+
+		overlayed.set_code(&config, vec![4, 5]);
+		overlayed.commit_prospective();
+		assert_eq!(overlayed.code(&config), &[4, 5]);
+		assert_eq!(overlayed.storage(b"\0code").unwrap(), &[4, 5]);
+	}
+
+	#[test]
+	fn overlayed_code_follows_the_reserved_prefix_and_namespace() {
+		let backend = InMemory::default();
+		let mut overlay = OverlayedChanges::default();
+		let config = ExecutionConfig {
+			reserved_prefix: b'!',
+			key_namespace: Some(b"chain/".to_vec()),
+			..Default::default()
+		};
+
+		overlay.set_code(&config, b"namespaced".to_vec());
+		assert_eq!(overlay.storage(b"chain/!code").unwrap(), &b"namespaced"[..]);
+		assert!(overlay.code(&ExecutionConfig::default()).is_empty());
+
+		let ext = ::ext::Ext::with_config(&mut overlay, &backend, config);
+		assert_eq!(&*ext.code_for_call().unwrap(), b"namespaced");
+	}
+
+	#[test]
+	fn overlayed_iteration_with_comparator() {
+		use std::cmp::Ordering;
+		use byteorder::{LittleEndian, ByteOrder};
+
+		fn suffixed(n: u32) -> Vec<u8> {
+			let mut key = b"item".to_vec();
+			let mut suffix = [0u8; 4];
+			LittleEndian::write_u32(&mut suffix, n);
+			key.extend_from_slice(&suffix);
+			key
+		}
+
+		fn by_suffix(a: &[u8], b: &[u8]) -> Ordering {
+			LittleEndian::read_u32(&a[a.len() - 4..]).cmp(&LittleEndian::read_u32(&b[b.len() - 4..]))
+		}
+
+		let mut overlayed = OverlayedChanges::default();
+		overlayed.set_storage(suffixed(256), vec![1]);
+		overlayed.set_storage(suffixed(1), vec![1]);
+		overlayed.commit_prospective();
+		overlayed.set_storage(suffixed(2), vec![1]);
+		overlayed.set_storage(b"other".to_vec(), vec![1]);
+
+		assert_eq!(overlayed.keys_with_prefix(b"item"), vec![suffixed(256), suffixed(1), suffixed(2)]);
+		assert_eq!(overlayed.keys_with_prefix_by(b"item", by_suffix), vec![suffixed(1), suffixed(2), suffixed(256)]);
+
+		assert_eq!(overlayed.next_key(&suffixed(1)), Some(suffixed(2)));
+		assert_eq!(overlayed.next_key_by(&suffixed(1), by_suffix), Some(suffixed(2)));
+		assert_eq!(overlayed.next_key_by(&suffixed(2), by_suffix), Some(suffixed(256)));
+		assert_eq!(overlayed.next_key_by(&suffixed(256), by_suffix), None);
+
+		overlayed.set_storage(suffixed(2), vec![]);
+		assert_eq!(overlayed.next_key_by(&suffixed(1), by_suffix), Some(suffixed(256)));
+	}
+
+	#[test]
+	fn generation_tracks_committing_writes() {
+		let backend = InMemory::default();
+		let mut overlay = OverlayedChanges::default();
+		assert_eq!(overlay.generation(), 0);
+
+		overlay.commit_prospective();
+		assert_eq!(overlay.generation(), 0);
+
+		overlay.set_storage(b"key".to_vec(), vec![1]);
+		overlay.commit_prospective();
+		assert_eq!(overlay.generation(), 1);
+
+		overlay.commit_prospective();
+		assert_eq!(overlay.generation(), 1);
+
+		execute(&backend, &mut overlay, &TestExecutor, "set", &CallData(vec![2])).unwrap();
+		assert_eq!(overlay.generation(), 2);
+		assert_eq!(::ext::Ext::new(&mut overlay, &backend).storage_generation(), 2);
+	}
+
+	#[test]
+	fn committed_snapshot_can_be_restored() {
+		let mut overlayed = OverlayedChanges::default();
+		overlayed.set_storage(b"kept".to_vec(), vec![1]);
+		overlayed.set_storage(b"changed".to_vec(), vec![1]);
+		overlayed.commit_prospective();
+
+		let snapshot = overlayed.capture_committed();
+
+		overlayed.set_storage(b"changed".to_vec(), vec![2]);
+		overlayed.set_storage(b"added".to_vec(), vec![2]);
+		overlayed.set_storage(b"kept".to_vec(), vec![]);
+		overlayed.commit_prospective();
+		assert!(overlayed.storage(b"kept").is_none());
+
+		overlayed.replace_committed(snapshot.clone());
+		assert_eq!(overlayed.storage(b"kept").unwrap(), &[1]);
+		assert_eq!(overlayed.storage(b"changed").unwrap(), &[1]);
+		assert!(overlayed.storage(b"added").is_none());
+	}
+
+	#[test]
+	fn drain_committed_yields_final_values() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(b"a".to_vec(), vec![1]);
+		overlay.set_storage(b"b".to_vec(), vec![2]);
+		overlay.set_storage(b"a".to_vec(), vec![3]);
+		overlay.set_storage(b"c".to_vec(), vec![]);
+		overlay.commit_prospective();
+		overlay.set_storage(b"d".to_vec(), vec![4]);
+
+		assert_eq!(overlay.drain_committed(), vec![
+			(b"a".to_vec(), Some(vec![3])),
+			(b"b".to_vec(), Some(vec![2])),
+			(b"c".to_vec(), None),
+		]);
+		assert!(overlay.drain_committed().is_empty());
+		assert!(overlay.storage(b"a").is_none());
+		assert_eq!(overlay.storage(b"d").unwrap(), &[4]);
+	}
+
+	#[test]
+	fn changes_root_is_kept_up_to_date() {
+		let mut batched = OverlayedChanges::default();
+		batched.set_storage(b"a".to_vec(), vec![1]);
+		batched.set_storage(b"b".to_vec(), vec![2]);
+		batched.commit_prospective();
+		batched.set_storage(b"b".to_vec(), vec![]);
+		batched.set_storage(b"c".to_vec(), vec![3]);
+		batched.commit_prospective();
+
+		let mut at_once = OverlayedChanges::default();
+		at_once.set_storage(b"c".to_vec(), vec![3]);
+		at_once.set_storage(b"b".to_vec(), vec![]);
+		at_once.set_storage(b"a".to_vec(), vec![1]);
+		at_once.commit_prospective();
+		assert_eq!(batched.changes_root(), at_once.changes_root());
+
+		// a deletion isn't the same as leaving the key alone.
+		let mut without_b = OverlayedChanges::default();
+		without_b.set_storage(b"a".to_vec(), vec![1]);
+		without_b.set_storage(b"c".to_vec(), vec![3]);
+		without_b.commit_prospective();
+		assert!(batched.changes_root() != without_b.changes_root());
+
+		batched.drain_committed();
+		assert_eq!(batched.changes_root(), OverlayedChanges::default().changes_root());
+	}
+
+	// root of the changes trie over the committed entries, computed top-down from the sorted leaves.
+	fn changes_root_from_scratch(overlay: &OverlayedChanges) -> [u8; 32] {
+		fn subtree(leaves: &[([u8; 32], [u8; 32])], height: usize, empty: &[[u8; 32]]) -> [u8; 32] {
+			if leaves.is_empty() {
+				return empty[height];
+			}
+			if height == 0 {
+				return leaves[0].1;
+			}
+			let bit = super::CHANGES_TRIE_DEPTH - height;
+			let split = leaves.iter()
+				.position(|&(ref path, _)| path[bit / 8] & (0x80 >> (bit % 8)) != 0)
+				.unwrap_or(leaves.len());
+			super::ChangesTrie::branch(
+				&subtree(&leaves[..split], height - 1, empty),
+				&subtree(&leaves[split..], height - 1, empty),
+			)
+		}
+
+		let mut leaves: Vec<_> = overlay.committed.storage.iter()
+			.map(|(key, value)| (::keccak_hash::keccak(key).0, super::ChangesTrie::leaf(key, value)))
+			.collect();
+		leaves.sort();
+		subtree(&leaves, super::CHANGES_TRIE_DEPTH, &super::ChangesTrie::empty())
+	}
+
+	#[test]
+	fn changes_root_is_updated_incrementally() {
+		let mut overlay = OverlayedChanges::default();
+		assert_eq!(overlay.changes_root(), changes_root_from_scratch(&overlay));
+
+		overlay.set_storage(b"a".to_vec(), vec![1]);
+		overlay.set_storage(b"b".to_vec(), vec![2]);
+		overlay.set_storage(b"c".to_vec(), vec![3]);
+		overlay.commit_prospective();
+		assert_eq!(overlay.changes_root(), changes_root_from_scratch(&overlay));
+
+		// the second batch touches only some of the keys, so only their paths are rehashed.
+		overlay.set_storage(b"b".to_vec(), vec![]);
+		overlay.set_storage(b"c".to_vec(), vec![4]);
+		overlay.set_storage(b"d".to_vec(), vec![5]);
+		overlay.commit_prospective();
+		assert_eq!(overlay.changes_root(), changes_root_from_scratch(&overlay));
+		assert_eq!(overlay.changes, super::ChangesTrie::of(&overlay.committed));
+	}
+
+	#[test]
+	fn overlay_round_trips_through_bytes() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(b"value".to_vec(), vec![1, 2]);
+		overlay.set_storage(b"deleted".to_vec(), vec![]);
+		overlay.set_child_storage(b"child".to_vec(), b"value".to_vec(), vec![3]);
+		overlay.commit_prospective();
+		overlay.set_storage(b"prospective".to_vec(), vec![4]);
+
+		let restored = OverlayedChanges::from_bytes(&overlay.to_bytes()).unwrap();
+		for key in &[&b"value"[..], b"deleted", b"absent"] {
+			assert_eq!(restored.storage(key), overlay.storage(key));
+			assert_eq!(restored.entry(key), overlay.entry(key));
+		}
+		assert_eq!(restored.entry(b"deleted"), Some(None));
+		assert_eq!(restored.child_entry(b"child", b"value"), Some(Some(&[3][..])));
+		assert!(restored.storage(b"prospective").is_none());
+		assert_eq!(restored.changes_root(), overlay.changes_root());
+
+		let bytes = overlay.to_bytes();
+		assert!(OverlayedChanges::from_bytes(&bytes[..bytes.len() - 1]).is_none());
+	}
+
+	#[test]
+	fn committed_set_is_independent_of_insertion_order() {
+		let entries = vec![
+			(b"alpha".to_vec(), vec![1]),
+			(b"beta".to_vec(), vec![]),
+			(b"gamma".to_vec(), vec![3]),
+			(b"delta".to_vec(), vec![4, 4]),
+		];
+		let committed = |order: &[usize]| {
+			let mut overlay = OverlayedChanges::default();
+			for &i in order {
+				overlay.set_storage(entries[i].0.clone(), entries[i].1.clone());
+				overlay.set_child_storage(b"child".to_vec(), entries[i].0.clone(), entries[i].1.clone());
+			}
+			overlay.commit_prospective();
+
+			let mut visited = Vec::new();
+			overlay.for_each_committed(|k, v| visited.push((k.to_vec(), v.to_vec())));
+			(overlay.to_bytes(), visited)
+		};
+
+		let first = committed(&[0, 1, 2, 3]);
+		for order in &[[3, 2, 1, 0], [2, 0, 3, 1], [1, 3, 0, 2]] {
+			assert_eq!(committed(order), first);
+		}
+	}
+
+	#[test]
+	fn for_each_committed_visits_in_key_order() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(b"b".to_vec(), vec![2]);
+		overlay.set_storage(b"a".to_vec(), vec![1]);
+		overlay.set_storage(b"c".to_vec(), vec![3]);
+		overlay.commit_prospective();
+		overlay.set_storage(b"d".to_vec(), vec![4]);
+
+		let mut visited = Vec::new();
+		overlay.for_each_committed(|k, v| visited.push((k.to_vec(), v.to_vec())));
+		assert_eq!(visited, vec![
+			(b"a".to_vec(), vec![1]),
+			(b"b".to_vec(), vec![2]),
+			(b"c".to_vec(), vec![3]),
+		]);
+	}
+
+	#[test]
+	fn merge_with_resolves_conflicts() {
+		let mut base = OverlayedChanges::default();
+		base.set_storage(b"shared".to_vec(), vec![1]);
+		base.commit_prospective();
+
+		let mut left = base.clone();
+		left.set_storage(b"shared".to_vec(), vec![5]);
+		left.set_storage(b"left".to_vec(), vec![2]);
+		left.commit_prospective();
+
+		let mut right = base.clone();
+		right.set_storage(b"shared".to_vec(), vec![3]);
+		right.set_storage(b"right".to_vec(), vec![4]);
+		right.commit_prospective();
+
+		left.merge_with(right, |_, ours, theirs| ::std::cmp::max(ours, theirs).to_vec());
+		assert_eq!(left.storage(b"shared").unwrap(), &[5]);
+		assert_eq!(left.storage(b"left").unwrap(), &[2]);
+		assert_eq!(left.storage(b"right").unwrap(), &[4]);
+	}
+
+	#[test]
+	fn invariant_check_catches_corruption() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(b"a".to_vec(), vec![1]);
+		overlay.commit_prospective();
+		assert_eq!(overlay.check_invariants(), Ok(()));
+
+		overlay.committed.storage.insert(b"b".to_vec(), Some(vec![]));
+		assert!(overlay.check_invariants().is_err());
+	}
+
+	#[test]
+	fn same_net_effect_ignores_history() {
+		let mut direct = OverlayedChanges::default();
+		direct.set_storage(b"a".to_vec(), vec![2]);
+		direct.set_storage(b"b".to_vec(), vec![3]);
+		direct.commit_prospective();
+
+		let mut winding = OverlayedChanges::default();
+		winding.set_storage(b"b".to_vec(), vec![9]);
+		winding.commit_prospective();
+		winding.set_storage(b"a".to_vec(), vec![1]);
+		winding.commit_prospective();
+		winding.set_storage(b"a".to_vec(), vec![2]);
+		winding.set_storage(b"b".to_vec(), vec![3]);
+
+		assert!(direct.same_net_effect(&winding));
+
+		winding.set_storage(b"c".to_vec(), vec![]);
+		assert!(!direct.same_net_effect(&winding));
+	}
+
+	#[test]
+	fn index_follows_committed_writes() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.add_index("kind", |_, value| value.first().map(|b| vec![*b]));
+
+		overlay.set_storage(b"a".to_vec(), vec![1]);
+		overlay.set_storage(b"b".to_vec(), vec![2]);
+		overlay.set_storage(b"c".to_vec(), vec![1, 9]);
+		assert!(overlay.index_lookup("kind", &[1]).is_empty());
+
+		overlay.commit_prospective();
+		assert_eq!(overlay.index_lookup("kind", &[1]), vec![b"a".to_vec(), b"c".to_vec()]);
+		assert_eq!(overlay.index_lookup("kind", &[2]), vec![b"b".to_vec()]);
+
+		overlay.set_storage(b"a".to_vec(), vec![2]);
+		overlay.set_storage(b"c".to_vec(), vec![]);
+		overlay.commit_prospective();
+		assert!(overlay.index_lookup("kind", &[1]).is_empty());
+		assert_eq!(overlay.index_lookup("kind", &[2]), vec![b"a".to_vec(), b"b".to_vec()]);
+		assert!(overlay.index_lookup("missing", &[2]).is_empty());
+	}
+
+	#[test]
+	fn try_apply_updates_is_all_or_nothing() {
+		let mut overlayed = OverlayedChanges::default();
+		let not_reserved = |u: &Update| match *u {
+			Update::Storage(ref key, _) => !key.starts_with(b"\0"),
+		};
+
+		let batch = vec![
+			Update::Storage(b"a".to_vec(), vec![1]),
+			Update::Storage(b"b".to_vec(), vec![2]),
+			Update::Storage(b"\0code".to_vec(), vec![3]),
+			Update::Storage(b"c".to_vec(), vec![4]),
+		];
+		assert_eq!(overlayed.try_apply_updates(batch, &not_reserved), Err(2));
+		assert!(overlayed.storage(b"a").is_none());
+		assert!(overlayed.storage(b"b").is_none());
+		assert!(overlayed.storage(b"\0code").is_none());
+		assert!(overlayed.storage(b"c").is_none());
+
+		let batch = vec![
+			Update::Storage(b"a".to_vec(), vec![1]),
+			Update::Storage(b"b".to_vec(), vec![2]),
+		];
+		assert_eq!(overlayed.try_apply_updates(batch, &not_reserved), Ok(()));
+		assert_eq!(overlayed.storage(b"a").unwrap(), &[1]);
+		assert_eq!(overlayed.storage(b"b").unwrap(), &[2]);
+	}
+
+	#[derive(Debug, Default)]
+	struct TestExternalities {
+		storage: HashMap<Vec<u8>, Vec<u8>>,
+		children: HashMap<Vec<u8>, HashMap<Vec<u8>, Vec<u8>>>,
+		fail_on: Option<Vec<u8>>,
+		drbg: Option<Drbg>,
+	}
+	impl Externalities for TestExternalities {
+		type Error = u8;
+
+		fn storage(&self, key: &[u8]) -> Result<&[u8], Self::Error> {
+			if self.fail_on.as_ref().map_or(false, |k| &k[..] == key) {
+				return Err(1);
+			}
+			Ok(self.storage.get(&key.to_vec()).map_or(&[] as &[u8], Vec::as_slice))
+		}
+
+		fn set_storage(&mut self, key: Vec<u8>, value: Vec<u8>) {
+			self.storage.insert(key, value);
+		}
+
+		fn storage_root(&self) -> Result<[u8; 32], Self::Error> {
+			Ok(::backend::storage_root(self.storage.clone()))
+		}
+
+		fn clear_prefix(&mut self, prefix: &[u8]) -> Result<(), Self::Error> {
+			self.storage.retain(|key, _| !key.starts_with(prefix));
+			Ok(())
+		}
+
+		fn next_storage_key(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+			Ok(self.storage.iter()
+				.filter(|&(k, v)| &k[..] > key && !v.is_empty())
+				.map(|(k, _)| k)
+				.min()
+				.cloned())
+		}
+
+		fn child_storage(&self, storage_key: &[u8], key: &[u8]) -> Result<&[u8], Self::Error> {
+			Ok(self.children.get(storage_key).and_then(|child| child.get(key)).map_or(&[] as &[u8], Vec::as_slice))
+		}
+
+		fn set_child_storage(&mut self, storage_key: Vec<u8>, key: Vec<u8>, value: Vec<u8>) {
+			self.children.entry(storage_key).or_insert_with(HashMap::new).insert(key, value);
+		}
+
+		fn child_storage_root(&self, storage_key: &[u8]) -> Result<[u8; 32], Self::Error> {
+			Ok(::backend::storage_root(self.children.get(storage_key).cloned().unwrap_or_default()))
+		}
+
+		fn random(&mut self, len: usize) -> Vec<u8> {
+			if self.drbg.is_none() {
+				self.drbg = Some(Drbg::new(::backend::storage_root(self.storage.clone())));
+			}
+			self.drbg.as_mut().unwrap().next_bytes(len)
+		}
+
+		fn touched_child_roots(&self) -> Result<Vec<(Vec<u8>, [u8; 32])>, Self::Error> {
+			let mut roots: Vec<_> = self.children.iter()
+				.map(|(storage_key, child)| (storage_key.clone(), ::backend::storage_root(child.clone())))
+				.collect();
+			roots.sort();
+			Ok(roots)
+		}
+	}
+
+	#[test]
+	fn validators_call_works() {
+		let mut ext = TestExternalities::default();
+
+		assert_eq!(ext.validators(), Ok(vec![]));
+
+		ext.set_storage(b"\0validator_count".to_vec(), vec![]);
+		assert_eq!(ext.validators(), Ok(vec![]));
+
+		ext.set_storage(b"\0validator_count".to_vec(), vec![1]);
+		assert_eq!(ext.validators(), Ok(vec![&[][..]]));
+
+		ext.set_storage(b"\0validator".to_vec(), b"first".to_vec());
+		assert_eq!(ext.validators(), Ok(vec![&b"first"[..]]));
+
+		ext.set_storage(b"\0validator_count".to_vec(), vec![2]);
+		ext.set_storage(b"\0validator\x01".to_vec(), b"second".to_vec());
+		assert_eq!(ext.validators(), Ok(vec![&b"first"[..], &b"second"[..]]));
+	}
+
+	#[test]
+	fn multi_byte_validator_count_is_read() {
+		let mut ext = TestExternalities::default();
+		let mut count = Vec::new();
+		::codec::encode_usize(300, &mut count);
+		assert_eq!(count, vec![44, 1]);
+
+		ext.set_storage(b"\0validator_count".to_vec(), count);
+		for i in 0..300 {
+			let mut key = b"\0validator".to_vec();
+			::codec::encode_usize(i, &mut key);
+			ext.set_storage(key, (i as u32).encode());
+		}
+
+		let validators = ext.validators().unwrap();
+		assert_eq!(validators.len(), 300);
+		assert_eq!(validators[299], &299u32.encode()[..]);
+		assert_eq!(ext.validators_range(256, 100).unwrap().len(), 44);
+	}
+
+	#[test]
+	fn fee_params_default_when_absent() {
+		let mut ext = TestExternalities::default();
+		assert_eq!(ext.fee_params(), Ok(FeeParams::default()));
+
+		let params = FeeParams { base_fee: 10, byte_fee: 3 };
+		ext.set_storage(b"\0fees".to_vec(), params.encode());
+		assert_eq!(ext.fee_params(), Ok(params));
+
+		ext.set_storage(b"\0fees".to_vec(), vec![1, 2, 3]);
+		assert_eq!(ext.fee_params(), Ok(FeeParams::default()));
+	}
+
+	#[test]
+	fn storage_len_works() {
+		let mut ext = TestExternalities::default();
+		ext.set_storage(b"present".to_vec(), b"four".to_vec());
+
+		assert_eq!(ext.storage_len(b"present"), Ok(Some(4)));
+		assert_eq!(ext.storage_len(b"absent"), Ok(None));
+
+		let backend = InMemory::default();
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(b"deleted".to_vec(), b"value".to_vec());
+		overlay.commit_prospective();
+		overlay.set_storage(b"deleted".to_vec(), vec![]);
+
+		let ext = ::ext::Ext::new(&mut overlay, &backend);
+		assert_eq!(ext.storage_len(b"deleted").unwrap(), None);
+	}
+
+	#[test]
+	fn storage_required_fails_on_absent() {
+		let mut ext = TestExternalities::default();
+		ext.set_storage(b"present".to_vec(), b"value".to_vec());
+		ext.set_storage(b"deleted".to_vec(), vec![]);
+
+		assert_eq!(ext.storage_required(b"present"), Ok(b"value".to_vec()));
+		assert_eq!(ext.storage_required(b"absent"), Err(3));
+		assert_eq!(ext.storage_required(b"deleted"), Err(3));
+	}
+
+	#[test]
+	fn delete_if_only_deletes_expected_value() {
+		let mut ext = TestExternalities::default();
+		ext.set_storage(b"key".to_vec(), b"value".to_vec());
+
+		assert_eq!(ext.delete_if(b"key", b"other"), Ok(false));
+		assert_eq!(ext.storage(b"key"), Ok(&b"value"[..]));
+
+		assert_eq!(ext.delete_if(b"key", b"value"), Ok(true));
+		assert_eq!(ext.storage(b"key"), Ok(&[][..]));
+
+		assert_eq!(ext.delete_if(b"absent", b""), Ok(false));
+		assert_eq!(ext.delete_if(b"absent", b"value"), Ok(false));
+	}
+
+	#[test]
+	fn fetch_add_u64_works() {
+		let mut ext = TestExternalities::default();
+
+		assert_eq!(ext.fetch_add_u64(b"counter", 5), Ok(0));
+		assert_eq!(ext.storage(b"counter"), Ok(&5u64.encode()[..]));
+
+		assert_eq!(ext.fetch_add_u64(b"counter", 2), Ok(5));
+		assert_eq!(ext.storage(b"counter"), Ok(&7u64.encode()[..]));
+
+		assert_eq!(ext.fetch_add_u64(b"counter", u64::max_value()), Ok(7));
+		assert_eq!(ext.storage(b"counter"), Ok(&u64::max_value().encode()[..]));
+	}
+
+	#[test]
+	fn push_ring_keeps_most_recent_values() {
+		let mut ext = TestExternalities::default();
+		let slot = |index: u32| {
+			let mut key = b"ring".to_vec();
+			index.encode_to(&mut key);
+			key
+		};
+
+		for value in 1..6u8 {
+			ext.push_ring(b"ring", vec![value], 3).unwrap();
+		}
+		assert_eq!(ext.storage(b"ring"), Ok(&5u64.encode()[..]));
+		assert_eq!(ext.storage(&slot(0)), Ok(&[4][..]));
+		assert_eq!(ext.storage(&slot(1)), Ok(&[5][..]));
+		assert_eq!(ext.storage(&slot(2)), Ok(&[3][..]));
+		assert!(ext.storage(&slot(3)).unwrap().is_empty());
+	}
+
+	#[test]
+	fn storage_or_insert_with_works() {
+		let mut ext = TestExternalities::default();
+		ext.set_storage(b"present".to_vec(), b"existing".to_vec());
+
+		assert_eq!(ext.storage_or_insert_with(b"present", || panic!("must not be called")), Ok(b"existing".to_vec()));
+
+		let mut called = false;
+		assert_eq!(ext.storage_or_insert_with(b"absent", || { called = true; b"new".to_vec() }), Ok(b"new".to_vec()));
+		assert!(called);
+		assert_eq!(ext.storage(b"absent"), Ok(&b"new"[..]));
+	}
+
+	#[test]
+	fn hash_known_vectors() {
+		let ext = TestExternalities::default();
+
+		assert_eq!(ext.hash(HashAlgo::Blake2_128, b"abc"), vec![
+			0xcf, 0x4a, 0xb7, 0x91, 0xc6, 0x2b, 0x8d, 0x2b, 0x21, 0x09, 0xc9, 0x02, 0x75, 0x28, 0x78, 0x16
+		]);
+		assert_eq!(ext.hash(HashAlgo::Blake2_256, b"abc"), vec![
+			0xbd, 0xdd, 0x81, 0x3c, 0x63, 0x42, 0x39, 0x72, 0x31, 0x71, 0xef, 0x3f, 0xee, 0x98, 0x57, 0x9b,
+			0x94, 0x96, 0x4e, 0x3b, 0xb1, 0xcb, 0x3e, 0x42, 0x72, 0x62, 0xc8, 0xc0, 0x68, 0xd5, 0x23, 0x19
+		]);
+		assert_eq!(ext.hash(HashAlgo::Keccak256, b""), vec![
+			0xc5, 0xd2, 0x46, 0x01, 0x86, 0xf7, 0x23, 0x3c, 0x92, 0x7e, 0x7d, 0xb2, 0xdc, 0xc7, 0x03, 0xc0,
+			0xe5, 0x00, 0xb6, 0x53, 0xca, 0x82, 0x27, 0x3b, 0x7b, 0xfa, 0xd8, 0x04, 0x5d, 0x85, 0xa4, 0x70
+		]);
+
+		assert_eq!(HashAlgo::from_selector(0), Some(HashAlgo::Blake2_128));
+		assert_eq!(HashAlgo::from_selector(2), Some(HashAlgo::Keccak256));
+		assert_eq!(HashAlgo::from_selector(3), None);
+	}
+
+	#[test]
+	fn validators_range_is_clamped() {
+		let mut ext = TestExternalities::default();
+		ext.set_storage(b"\0validator_count".to_vec(), vec![3]);
+		ext.set_storage(b"\0validator".to_vec(), b"first".to_vec());
+		ext.set_storage(b"\0validator\x01".to_vec(), b"second".to_vec());
+		ext.set_storage(b"\0validator\x02".to_vec(), b"third".to_vec());
+
+		assert_eq!(ext.validators_range(1, 1), Ok(vec![&b"second"[..]]));
+		assert_eq!(ext.validators_range(1, 10), Ok(vec![&b"second"[..], &b"third"[..]]));
+		assert_eq!(ext.validators_range(5, 2), Ok(vec![]));
+	}
+
+	#[test]
+	fn validators_call_fails_on_bad_read() {
+		let mut ext = TestExternalities::default();
+		ext.set_storage(b"\0validator_count".to_vec(), vec![4]);
+		ext.set_storage(b"\0validator".to_vec(), b"first".to_vec());
+		ext.set_storage(b"\0validator\x01".to_vec(), b"second".to_vec());
+		ext.set_storage(b"\0validator\x03".to_vec(), b"fourth".to_vec());
+
+		ext.fail_on = Some(b"\0validator\x02".to_vec());
+		assert_eq!(ext.validators(), Err(1));
+	}
+
+	#[test]
+	fn validators_call_is_bounded() {
+		let mut ext = TestExternalities::default();
+		ext.set_storage(b"\0validator_count".to_vec(), vec![0xff; 8]);
+
+		assert_eq!(ext.validators().unwrap().len(), MAX_VALIDATORS);
+	}
+
+	#[test]
+	fn execute_rejects_mismatched_backend() {
+		let backend = InMemory::with_stamp(2);
+		let mut overlay = OverlayedChanges::default();
+
+		overlay.set_backend_stamp(1);
+		assert!(execute(&backend, &mut overlay, &TestExecutor, "set", &CallData(vec![1])).is_err());
+		assert!(overlay.storage(b"value").is_none());
+
+		overlay.set_backend_stamp(2);
+		assert!(execute(&backend, &mut overlay, &TestExecutor, "set", &CallData(vec![1])).is_ok());
+		assert_eq!(overlay.storage(b"value").unwrap(), &[1]);
+	}
+
+	// fails every read of `b"broken"`.
+	struct BrokenKey(InMemory);
+
+	impl Backend for BrokenKey {
+		type Error = &'static str;
+
+		fn storage(&self, key: &[u8]) -> Result<&[u8], &'static str> {
+			if key == b"broken" { return Err("unavailable") }
+			Ok(self.0.storage(key).unwrap())
+		}
+
+		fn pairs(&self) -> Result<Vec<(Vec<u8>, Vec<u8>)>, &'static str> {
+			Ok(self.0.pairs().unwrap())
+		}
+
+		fn commit<I>(&mut self, changes: I) -> ::backend::Committed
+			where I: IntoIterator<Item=Update>
+		{
+			self.0.commit(changes)
+		}
+	}
+
+	#[test]
+	fn execute_reports_failure_source() {
+		let backend = BrokenKey(InMemory::default());
+		let mut overlay = OverlayedChanges::default();
+
+		// "get" turns the read failure into an error of its own.
+		match execute(&backend, &mut overlay, &TestExecutor, "get", &CallData(b"broken".to_vec())) {
+			Err(ExecutionError::Backend("unavailable")) => {}
+			other => panic!("expected backend error, got {:?}", other),
+		}
+		match execute(&backend, &mut overlay, &TestExecutor, "fail", &CallData(vec![1])) {
+			Err(ExecutionError::Executor(1)) => {}
+			other => panic!("expected executor error, got {:?}", other),
+		}
+		assert!(overlay.storage(b"value").is_none());
+
+		let config = ExecutionConfig { read_only: true, ..Default::default() };
+		match execute_typed(&backend, &mut overlay, &TestExecutor, "set", &CallData(vec![1]), config, Hooks::default()).0 {
+			Err(ExecutionError::Violation(Violation::ReadOnlyWrite(ref key))) if key == b"value" => {}
+			other => panic!("expected violation, got {:?}", other),
+		}
+
+		overlay.set_backend_stamp(1);
+		match execute(&backend, &mut overlay, &TestExecutor, "set", &CallData(vec![1])) {
+			Err(ExecutionError::BackendMismatch(mismatch)) => assert_eq!(mismatch.found, None),
+			other => panic!("expected backend mismatch, got {:?}", other),
+		}
+	}
+
+	#[test]
+	fn boxed_execute_keeps_error_message() {
+		let backend = BrokenKey(InMemory::default());
+		let mut overlay = OverlayedChanges::default();
+
+		let err = execute_boxed(&backend, &mut overlay, &TestExecutor, "get", &CallData(b"broken".to_vec())).unwrap_err();
+		assert_eq!(err.to_string(), "unavailable");
+		let err = execute_boxed(&backend, &mut overlay, &TestExecutor, "fail", &CallData(vec![1])).unwrap_err();
+		assert_eq!(err.to_string(), "1");
+	}
+
+	#[test]
+	fn random_bytes_follow_state() {
+		let run = |value: u8| {
+			let mut backend = InMemory::default();
+			backend.commit(vec![Update::Storage(b"value".to_vec(), vec![value])]);
+			let mut overlay = OverlayedChanges::default();
+			execute(&backend, &mut overlay, &TestExecutor, "random", &CallData(vec![40])).unwrap()
+		};
+
+		let out = run(1);
+		assert_eq!(out.len(), 80);
+		assert!(out[..40] != out[40..]);
+		assert_eq!(out, run(1));
+		assert!(out != run(2));
+	}
+
+	#[test]
+	fn failing_call_aborts_block() {
+		let backend = InMemory::default();
+		let mut overlay = OverlayedChanges::default();
+
+		let calls = vec![
+			("set".to_owned(), CallData(vec![1])),
+			("fail".to_owned(), CallData(vec![2])),
+			("set_code".to_owned(), CallData(vec![3])),
+		];
+		match execute_block(&backend, &mut overlay, &TestExecutor, &calls) {
+			Err(ExecutionError::Executor(1)) => {}
+			other => panic!("expected the second call to fail, got {:?}", other),
+		}
+		assert_eq!(overlay.storage(b"value").unwrap(), &[1]);
+		assert!(overlay.storage(b"\0code").is_none());
+
+		let calls = vec![
+			("set_code".to_owned(), CallData(vec![3])),
+			("code".to_owned(), CallData(vec![])),
+		];
+		let outs = execute_block(&backend, &mut overlay, &TestExecutor, &calls).unwrap();
+		assert_eq!(outs, vec![vec![], vec![3]]);
+	}
+
+	#[test]
+	fn genesis_hash_is_passed_to_call() {
+		let backend = InMemory::default();
+		let mut overlay = OverlayedChanges::default();
+
+		let out = execute(&backend, &mut overlay, &TestExecutor, "genesis", &CallData(vec![])).unwrap();
+		assert!(out.is_empty());
+
+		let config = ExecutionConfig { genesis_hash: Some([7; 32]), ..Default::default() };
+		let out = execute_with_config(&backend, &mut overlay, &TestExecutor, "genesis", &CallData(vec![]), config).unwrap();
+		assert_eq!(out, vec![7; 32]);
+	}
+
+	#[test]
+	fn timestamp_is_passed_to_call() {
+		let backend = InMemory::default();
+		let mut overlay = OverlayedChanges::default();
+
+		let out = execute(&backend, &mut overlay, &TestExecutor, "timestamp", &CallData(vec![])).unwrap();
+		assert_eq!(out, 0u64.encode());
+
+		let config = ExecutionConfig { timestamp: Some(1_519_000_000), ..Default::default() };
+		let out = execute_with_config(&backend, &mut overlay, &TestExecutor, "timestamp", &CallData(vec![]), config).unwrap();
+		assert_eq!(out, 1_519_000_000u64.encode());
+	}
+
+	#[test]
+	fn read_only_calls_change_nothing() {
+		let mut backend = InMemory::default();
+		backend.commit(vec![Update::Storage(b"value".to_vec(), vec![1])]);
+		let before = backend.pairs().unwrap();
+
+		assert_eq!(execute_read_only(&backend, &TestExecutor, "get", &CallData(b"value".to_vec())).unwrap(), vec![1]);
+		assert!(execute_read_only(&backend, &TestExecutor, "set", &CallData(vec![2])).is_err());
+		assert_eq!(backend.pairs().unwrap(), before);
+
+		let mut overlay = OverlayedChanges::default();
+		let config = ExecutionConfig { read_only: true, ..Default::default() };
+		let mut ext = ::ext::Ext::with_config(&mut overlay, &backend, config);
+		ext.set_storage(b"value".to_vec(), vec![2]);
+		ext.set_child_storage(b"child".to_vec(), b"key".to_vec(), vec![3]);
+		assert_eq!(ext.take_violation(), Some(Violation::ReadOnlyWrite(b"value".to_vec())));
+		assert_eq!(ext.storage(b"value").unwrap(), &[1]);
+		assert_eq!(ext.child_storage(b"child", b"key").unwrap(), &[] as &[u8]);
+		drop(ext);
+		assert!(overlay.storage(b"value").is_none());
+	}
+
+	#[test]
+	fn unauthorized_origin_cannot_take_over_upgrade_key() {
+		let mut backend = InMemory::default();
+		backend.commit(vec![Update::Storage(b"\0upgrade_key".to_vec(), b"alice".to_vec())]);
+		let mut overlay = OverlayedChanges::default();
+
+		let config = ExecutionConfig { origin: Some(b"bob".to_vec()), ..Default::default() };
+		let err = execute_with_config(&backend, &mut overlay, &TestExecutor, "take_upgrade", &CallData(b"bob".to_vec()), config).unwrap_err();
+		assert_eq!(err.to_string(), Violation::UnauthorizedUpgrade.to_string());
+		assert!(overlay.storage(b"\0upgrade_key").is_none());
+		assert!(overlay.storage(b"\0code").is_none());
+
+		let config = ExecutionConfig { origin: Some(b"alice".to_vec()), ..Default::default() };
+		assert!(execute_with_config(&backend, &mut overlay, &TestExecutor, "take_upgrade", &CallData(b"alice".to_vec()), config).is_ok());
+		assert_eq!(overlay.storage(b"\0code").unwrap(), b"taken");
+	}
+
+	#[test]
+	fn only_authorized_origin_can_set_code() {
+		let mut backend = InMemory::default();
+		backend.commit(vec![Update::Storage(b"\0upgrade_key".to_vec(), b"alice".to_vec())]);
+		let mut overlay = OverlayedChanges::default();
+
+		let config = ExecutionConfig { origin: Some(b"bob".to_vec()), ..Default::default() };
+		assert!(execute_with_config(&backend, &mut overlay, &TestExecutor, "set_code", &CallData(b"new".to_vec()), config).is_err());
+		assert!(overlay.storage(b"\0code").is_none());
+
+		let config = ExecutionConfig { origin: Some(b"alice".to_vec()), ..Default::default() };
+		assert!(execute_with_config(&backend, &mut overlay, &TestExecutor, "set_code", &CallData(b"new".to_vec()), config).is_ok());
+		assert_eq!(overlay.storage(b"\0code").unwrap(), b"new");
+	}
+
+	#[test]
+	fn acl_protects_prefixed_keys() {
+		let backend = InMemory::default();
+		let mut overlay = OverlayedChanges::default();
+		let config = |origin: &[u8]| {
+			let mut config = ExecutionConfig { origin: Some(origin.to_vec()), ..Default::default() };
+			config.acl.insert(b"val".to_vec(), vec![b"alice".to_vec()].into_iter().collect());
+			config
+		};
+
+		assert!(execute_with_config(&backend, &mut overlay, &TestExecutor, "set", &CallData(vec![1]), config(b"alice")).is_ok());
+		assert_eq!(overlay.storage(b"value").unwrap(), &[1]);
+
+		assert!(execute_with_config(&backend, &mut overlay, &TestExecutor, "set", &CallData(vec![2]), config(b"bob")).is_err());
+		assert_eq!(overlay.storage(b"value").unwrap(), &[1]);
+
+		assert!(execute_with_config(&backend, &mut overlay, &TestExecutor, "many", &CallData(vec![1]), config(b"bob")).is_ok());
+		assert_eq!(overlay.storage(&[0]).unwrap(), &[1]);
+	}
+
+	#[test]
+	fn writes_past_the_storage_quota_fail_the_call() {
+		let backend = InMemory::default();
+		let mut overlay = OverlayedChanges::default();
+		let config = |quota| ExecutionConfig { storage_quota: Some(quota), ..Default::default() };
+
+		let err = execute_with_config(&backend, &mut overlay, &TestExecutor, "set", &CallData(vec![1, 2]), config(1)).unwrap_err();
+		assert_eq!(err.to_string(), Violation::QuotaExceeded(1).to_string());
+		assert!(overlay.storage(b"value").is_none());
+
+		assert!(execute_with_config(&backend, &mut overlay, &TestExecutor, "set", &CallData(vec![1, 2]), config(2)).is_ok());
+		assert_eq!(overlay.storage(b"value").unwrap(), &[1, 2]);
+	}
+
+	#[test]
+	fn execute_preserving_keeps_diagnostics_on_error() {
+		let backend = InMemory::default();
+		let mut overlay = OverlayedChanges::default();
+
+		let (result, diagnostics) = execute_preserving(&backend, &mut overlay, &TestExecutor, "fail", &CallData(vec![1]));
+		assert!(result.is_err());
+		assert_eq!(diagnostics.reads, vec![b"\0code".to_vec()]);
+		assert_eq!(diagnostics.writes, vec![b"value".to_vec()]);
+		assert!(overlay.storage(b"value").is_none());
+	}
+
+	#[test]
+	fn duplicate_writes_are_warned_about() {
+		let backend = InMemory::default();
+		let mut overlay = OverlayedChanges::default();
+
+		let (result, diagnostics, _) = execute_inner(&backend, &mut overlay, &TestExecutor, "set_twice", &CallData(vec![1]), ExecutionConfig::default(), Hooks::default());
+		assert!(result.is_ok());
+		assert!(diagnostics.duplicate_writes.is_empty());
+
+		let config = ExecutionConfig { warn_on_duplicate_write: true, ..Default::default() };
+		let (result, diagnostics, _) = execute_inner(&backend, &mut overlay, &TestExecutor, "set_twice", &CallData(vec![1]), config, Hooks::default());
+		assert!(result.is_ok());
+		assert_eq!(diagnostics.duplicate_writes, vec![b"value".to_vec()]);
+	}
+
+	#[test]
+	fn touching_too_many_keys_aborts() {
+		let backend = InMemory::default();
+		let mut overlay = OverlayedChanges::default();
+		let config = ExecutionConfig { max_touched_keys: Some(3), ..Default::default() };
+
+		assert!(execute_with_config(&backend, &mut overlay, &TestExecutor, "many", &CallData(vec![2]), config.clone()).is_ok());
+		assert_eq!(overlay.storage(&[1]).unwrap(), &[1]);
+
+		let mut overlay = OverlayedChanges::default();
+		let err = execute_with_config(&backend, &mut overlay, &TestExecutor, "many", &CallData(vec![3]), config).unwrap_err();
+		assert_eq!(err.to_string(), Violation::TooManyKeys(3).to_string());
+		assert!(overlay.storage(&[0]).is_none());
+		assert!(overlay.storage(&[2]).is_none());
+	}
+
+	#[test]
+	fn custom_reserved_prefix_is_used_for_code_and_validators() {
+		let mut backend = InMemory::default();
+		backend.commit(vec![
+			Update::Storage(b"\xffcode".to_vec(), b"the code".to_vec()),
+			Update::Storage(b"\0code".to_vec(), b"other code".to_vec()),
+			Update::Storage(b"\xffvalidator_count".to_vec(), vec![1]),
+			Update::Storage(b"\xffvalidator".to_vec(), b"first".to_vec()),
+		]);
+		let config = ExecutionConfig { reserved_prefix: 0xff, ..Default::default() };
+
+		let mut overlay = OverlayedChanges::default();
+		assert_eq!(
+			execute_with_config(&backend, &mut overlay, &TestExecutor, "code", &CallData(vec![]), config.clone()).unwrap(),
+			b"the code".to_vec()
+		);
+
+		let ext = ::ext::Ext::with_config(&mut overlay, &backend, config);
+		assert_eq!(ext.reserved_key(b"code"), b"\xffcode".to_vec());
+		assert_eq!(ext.validators().unwrap(), vec![&b"first"[..]]);
+	}
+
+	#[test]
+	fn execute_and_persist_flushes_to_db() {
+		let mut db = InMemory::default();
+		let mut overlay = OverlayedChanges::default();
+
+		execute_and_persist(&mut db, &mut overlay, &TestExecutor, "set", &CallData(vec![1])).unwrap();
+		assert!(overlay.storage(b"value").is_none());
+		assert_eq!(db.storage(b"value").unwrap(), &[1]);
+
+		assert!(execute_and_persist(&mut db, &mut overlay, &TestExecutor, "fail", &CallData(vec![2])).is_err());
+		assert_eq!(db.storage(b"value").unwrap(), &[1]);
+
+		let mut reopened = OverlayedChanges::default();
+		let ext = ::ext::Ext::new(&mut reopened, &db);
+		assert_eq!(ext.storage(b"value").unwrap(), &[1]);
+	}
+
+	#[test]
+	fn execute_and_persist_flushes_child_storage() {
+		let mut db = InMemory::default();
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_child_storage(b"alice".to_vec(), b"key".to_vec(), vec![1]);
+		overlay.commit_prospective();
+
+		let expected_root = {
+			let mut preview = overlay.clone();
+			execute(&db, &mut preview, &TestExecutor, "set", &CallData(vec![2])).unwrap();
+			let ext = ::ext::Ext::new(&mut preview, &db);
+			ext.storage_root().unwrap()
+		};
+
+		execute_and_persist(&mut db, &mut overlay, &TestExecutor, "set", &CallData(vec![2])).unwrap();
+		assert!(overlay.drain_committed_children().is_empty());
+		assert_eq!(db.child_storage(b"alice", b"key").unwrap(), &[1]);
+
+		let mut reopened = OverlayedChanges::default();
+		let ext = ::ext::Ext::new(&mut reopened, &db);
+		assert_eq!(ext.child_storage(b"alice", b"key").unwrap(), &[1]);
+		assert_eq!(ext.storage_root().unwrap(), expected_root);
+	}
+
+	#[test]
+	fn execute_with_yield_yields_between_storage_ops() {
+		let backend = InMemory::default();
+
+		let mut plain = OverlayedChanges::default();
+		execute(&backend, &mut plain, &TestExecutor, "many", &CallData(vec![10])).unwrap();
+
+		// one code read, then ten writes and ten reads.
+		let mut yields = 0;
+		let mut overlay = OverlayedChanges::default();
+		execute_with_yield(&backend, &mut overlay, &TestExecutor, "many", &CallData(vec![10]), 3, || yields += 1).unwrap();
+		assert_eq!(yields, 7);
+
+		for i in 0..10 {
+			assert_eq!(overlay.storage(&[i]), plain.storage(&[i]));
+		}
+	}
+
+	#[test]
+	fn runtime_version_is_decoded() {
+		let mut ext = TestExternalities::default();
+
+		assert_eq!(
+			TestExecutor.runtime_version(&mut ext, &[]),
+			Ok(RuntimeVersion { spec_version: 1, impl_version: 7 })
+		);
+	}
+
+	#[test]
+	fn execute_with_events_returns_emitted_events() {
+		let backend = InMemory::default();
+		let mut overlay = OverlayedChanges::default();
+
+		let (result, events) = execute_with_events(&backend, &mut overlay, &TestExecutor, "emit", &CallData(b"first".to_vec()), Default::default());
+		assert!(result.is_ok());
+		assert_eq!(events, vec![
+			Event { topic: b"topic".to_vec(), data: b"first".to_vec() },
+			Event { topic: b"topic".to_vec(), data: b"second".to_vec() },
+		]);
+
+		let (result, events) = execute_with_events(&backend, &mut overlay, &TestExecutor, "emit", &CallData(vec![]), Default::default());
+		assert!(result.is_err());
+		assert!(events.is_empty());
+
+		let config = ExecutionConfig { keep_events_on_error: true, ..Default::default() };
+		let (result, events) = execute_with_events(&backend, &mut overlay, &TestExecutor, "emit", &CallData(vec![]), config);
+		assert!(result.is_err());
+		assert_eq!(events.len(), 2);
+	}
+
+	#[test]
+	fn execute_with_delta_returns_committed_changes() {
+		let backend = InMemory::default();
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(b"earlier".to_vec(), vec![1]);
+		overlay.commit_prospective();
+
+		let (output, delta) = execute_with_delta(&backend, &mut overlay, &TestExecutor, "set_twice", &CallData(vec![2])).unwrap();
+		assert!(output.is_empty());
+		assert_eq!(delta, vec![
+			(b"other".to_vec(), Some(vec![2])),
+			(b"value".to_vec(), Some(vec![2])),
+		]);
+
+		let (_, delta) = execute_with_delta(&backend, &mut overlay, &TestExecutor, "set", &CallData(vec![])).unwrap();
+		assert_eq!(delta, vec![(b"value".to_vec(), None)]);
+
+		assert!(execute_with_delta(&backend, &mut overlay, &TestExecutor, "fail", &CallData(vec![3])).is_err());
+		assert!(overlay.storage(b"value").is_none());
+	}
+
+	#[test]
+	fn code_is_borrowed_from_backend_until_changed() {
+		let mut backend = InMemory::default();
+		backend.commit(vec![Update::Storage(b"\0code".to_vec(), b"the code".to_vec())]);
+		let mut overlay = OverlayedChanges::default();
+
+		match ::ext::Ext::new(&mut overlay, &backend).code_for_call().unwrap() {
+			Cow::Borrowed(code) => assert_eq!(code, b"the code"),
+			Cow::Owned(_) => panic!("unchanged code is borrowed from the backend"),
+		}
+
+		overlay.set_code(&ExecutionConfig::default(), b"new code".to_vec());
+		overlay.commit_prospective();
+		assert_eq!(&*::ext::Ext::new(&mut overlay, &backend).code_for_call().unwrap(), b"new code");
+
+		let out = execute(&backend, &mut overlay, &TestExecutor, "code", &CallData(vec![])).unwrap();
+		assert_eq!(out, b"new code".to_vec());
+	}
+
+	#[test]
+	fn shadow_follows_committed_state() {
+		let backend = InMemory::default();
+		let mut overlay = OverlayedChanges::default();
+		let mut shadow = HashMap::new();
+
+		execute_with_shadow(&backend, &mut overlay, &TestExecutor, "set_twice", &CallData(vec![2]), &mut shadow).unwrap();
+		execute_with_shadow(&backend, &mut overlay, &TestExecutor, "set", &CallData(vec![]), &mut shadow).unwrap();
+		assert!(execute_with_shadow(&backend, &mut overlay, &TestExecutor, "fail", &CallData(vec![3]), &mut shadow).is_err());
+
+		let mut committed = HashMap::new();
+		overlay.for_each_committed(|key, value| if !value.is_empty() {
+			committed.insert(key.to_vec(), value.to_vec());
+		});
+		assert_eq!(shadow, committed);
+		assert_eq!(shadow.len(), 1);
+		assert_eq!(shadow.get(&b"other"[..]), Some(&vec![2]));
+	}
+
+	#[test]
+	fn execution_outcome_round_trips() {
+		let mut backend = InMemory::default();
+		backend.commit(vec![Update::Storage(b"value".to_vec(), vec![1])]);
+		let mut overlay = OverlayedChanges::default();
+
+		let outcome = execute_full(&backend, &mut overlay, &TestExecutor, "emit", &CallData(b"first".to_vec()), Default::default()).unwrap();
+		assert_eq!(outcome.events.len(), 2);
+		assert!(outcome.changes.is_empty());
+
+		let outcome = execute_full(&backend, &mut overlay, &TestExecutor, "set", &CallData(vec![2]), Default::default()).unwrap();
+		assert_eq!(outcome.changes, vec![(b"value".to_vec(), Some(vec![2]))]);
+		assert_eq!(outcome.storage_root, ::backend::storage_root(vec![(b"value".to_vec(), vec![2])]));
+
+		let outcome = ExecutionOutcome {
+			output: b"output".to_vec(),
+			changes: vec![(b"a".to_vec(), Some(vec![1])), (b"b".to_vec(), None)],
+			events: vec![Event { topic: b"topic".to_vec(), data: b"data".to_vec() }],
+			storage_root: [7; 32],
+		};
+		let encoded = outcome.encode();
+		let mut input = &encoded[..];
+		assert_eq!(ExecutionOutcome::decode(&mut input), Some(outcome));
+		assert!(input.is_empty());
+		assert_eq!(ExecutionOutcome::decode(&mut &encoded[..encoded.len() - 1]), None);
+	}
+
+	#[test]
+	fn warmed_keys_are_served_from_overlay() {
+		let mut inner = InMemory::default();
+		inner.commit(vec![Update::Storage(b"\0code".to_vec(), b"the code".to_vec())]);
+		let backend = RecordOrder::new(inner);
+
+		let mut overlay = OverlayedChanges::default();
+		overlay.warm_start(&backend, &[&b"\0code"[..], &b"\0validator_count"[..]]).unwrap();
+		assert_eq!(overlay.generation(), 0);
+
+		let out = execute(&backend, &mut overlay, &TestExecutor, "code", &CallData(vec![])).unwrap();
+		assert_eq!(out, b"the code".to_vec());
+		assert_eq!(backend.into_recording(), vec![b"\0code".to_vec(), b"\0validator_count".to_vec()]);
+	}
+
+	#[test]
+	fn warmed_keys_are_not_changes() {
+		let mut backend = InMemory::default();
+		backend.commit(vec![Update::Storage(b"\0code".to_vec(), b"the code".to_vec())]);
+
+		let mut overlay = OverlayedChanges::default();
+		overlay.warm_start(&backend, &[&b"\0code"[..]]).unwrap();
+		assert_eq!(overlay.storage(b"\0code"), Some(&b"the code"[..]));
+		assert_eq!(overlay.changes_root(), OverlayedChanges::default().changes_root());
+
+		assert!(overlay.drain_committed().is_empty());
+		assert_eq!(overlay.storage(b"\0code"), Some(&b"the code"[..]));
+	}
+
+	#[test]
+	fn replayed_call_reads_in_same_order() {
+		let record = || {
+			let backend = RecordOrder::new(InMemory::default());
+			let mut overlay = OverlayedChanges::default();
+			execute(&backend, &mut overlay, &TestExecutor, "read", &CallData(vec![3, 1, 2])).unwrap();
+			backend.into_recording()
+		};
+
+		let first = record();
+		assert_eq!(first, vec![b"\0code".to_vec(), vec![3], vec![1], vec![2]]);
+		assert_eq!(first, record());
 	}
 }